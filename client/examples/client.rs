@@ -1,33 +1,18 @@
-extern crate pubsub_client;
-extern crate tokio_core;
-extern crate pubsub;
-extern crate futures;
-
-use pubsub::message::{Message, MessageBuilder, MessageType};
 use pubsub_client::PubsubClient;
-use futures::{Stream, Sink, Future};
-use tokio_core::reactor::Core;
+use futures::StreamExt;
 
 
-fn main() {
-    let mut core = Core::new().unwrap();
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
     let remote_addr = "127.0.0.1:9876".parse().unwrap();
 
-    let handle = core.handle();
-    let client = PubsubClient::connect(&remote_addr, &handle)
-        .and_then(|transport| {
-            let mut builder = MessageBuilder::new();
-            builder.message_type(MessageType::Subscribe)
-                .event_name("foobar".to_string());
-            let msg = builder.build().unwrap();
-            transport.send(msg)
-        })
-        .and_then(|transport| {
-            transport.for_each(|msg| {
-                println!("{:?}", msg);
-                Ok(())
-            })
-        });
+    let client = PubsubClient::connect(&remote_addr).await.unwrap();
+    let mut events = client.subscribe("foobar.#");
+    client.publish("foobar.hello", b"Hello from the example".to_vec()).await.unwrap();
+    println!("Published to foobar.hello");
 
-    core.run(client).unwrap();
+    while let Some(event) = events.next().await {
+        let event = event.unwrap();
+        println!("{}: {}", event.topic, String::from_utf8_lossy(&event.payload));
+    }
 }
@@ -0,0 +1,100 @@
+use pubsub::handshake::{Handshake, FEATURE_AUTH};
+use pubsub::message::{Message, MessageHeader, MessageType};
+
+use crate::client::RequestError;
+
+use std::fmt;
+use std::io;
+
+// What a client authenticates with when connecting to a server requiring it
+#[derive(Clone, PartialEq)]
+pub enum Credentials {
+    // The secret shared by the clients of the server
+    Token(String),
+    // A user name and password
+    Password(String, String)
+}
+
+impl Credentials {
+    pub fn token(token: &str) -> Credentials {
+        Credentials::Token(token.to_string())
+    }
+
+    pub fn password(user: &str, password: &str) -> Credentials {
+        Credentials::Password(user.to_string(), password.to_string())
+    }
+
+    // The Authenticate message to send right after the handshake
+    pub fn to_message(&self) -> Message {
+        let (user, secret) = match *self {
+            Credentials::Token(ref token) => ("", token),
+            Credentials::Password(ref user, ref password) => (&user[..], password)
+        };
+        Message {
+            header: MessageHeader {
+                message_type: MessageType::Authenticate,
+                event_name: user.to_string(),
+                retain: false,
+                offset: None
+            },
+            payload: Some(secret.as_bytes().to_vec())
+        }
+    }
+}
+
+// Keeps the secrets out of logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Credentials::Token(_) => write!(f, "Token(..)"),
+            Credentials::Password(ref user, _) => write!(f, "Password({:?}, ..)", user)
+        }
+    }
+}
+
+// Servers that don't know of authentication would disconnect
+// a client sending its credentials
+pub fn check_auth_supported(welcome: &Handshake) -> io::Result<()> {
+    if welcome.features & FEATURE_AUTH == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "server doesn't support authentication"));
+    }
+    Ok(())
+}
+
+// The outcome of authenticating, from the server's reply
+pub fn check_auth_reply(reply: &Message) -> io::Result<()> {
+    match reply.header.message_type {
+        MessageType::Ack => Ok(()),
+        MessageType::Error => {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, RequestError::from_message(reply)))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply to authentication"))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Credentials;
+    use pubsub::message::MessageType;
+
+    #[test]
+    fn test_to_message() {
+        let message = Credentials::password("alice", "secret").to_message();
+        assert_eq!(message.header.message_type, MessageType::Authenticate);
+        assert_eq!(message.header.event_name, "alice");
+        assert_eq!(message.payload, Some(b"secret".to_vec()));
+
+        let message = Credentials::token("t0ken").to_message();
+        assert_eq!(message.header.event_name, "");
+        assert_eq!(message.payload, Some(b"t0ken".to_vec()));
+    }
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        assert_eq!(format!("{:?}", Credentials::token("t0ken")), "Token(..)");
+        assert_eq!(format!("{:?}", Credentials::password("alice", "secret")),
+                   "Password(\"alice\", ..)");
+    }
+}
@@ -0,0 +1,249 @@
+use pubsub::decoder::Decoder;
+use pubsub::handshake::{Handshake, HANDSHAKE_LEN, MAGIC};
+use pubsub::message::{Message, MessageBuilder, MessageType};
+
+use crate::auth::{check_auth_reply, check_auth_supported, Credentials};
+use crate::client::{check_refusal, check_welcome, is_welcome, Event, RequestError, StartOffset};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+// A client for programs that don't use an event loop. Requests block until
+// the server has replied to them. Events received in the meantime are kept
+// until they are asked for with recv or the events iterator.
+pub struct BlockingClient {
+    stream: TcpStream,
+    decoder: Decoder,
+    events: VecDeque<Event>,
+    // Number of the last request sent, which the server uses
+    // as correlation id in its reply
+    request_id: u32
+}
+
+impl BlockingClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<BlockingClient> {
+        BlockingClient::connect_with(addr, None)
+    }
+
+    // Connect to a server requiring authentication. Fails with
+    // PermissionDenied if the credentials are refused.
+    pub fn connect_with_credentials<A: ToSocketAddrs>(addr: A, credentials: &Credentials)
+                                                      -> io::Result<BlockingClient> {
+        BlockingClient::connect_with(addr, Some(credentials))
+    }
+
+    fn connect_with<A: ToSocketAddrs>(addr: A, credentials: Option<&Credentials>)
+                                      -> io::Result<BlockingClient> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(&Handshake::new().into_bytes())?;
+        let mut welcome = [0; HANDSHAKE_LEN];
+        stream.read_exact(&mut welcome[..MAGIC.len()])?;
+        if !is_welcome(&welcome) {
+            let mut decoder = Decoder::new();
+            decoder.feed(&welcome[..MAGIC.len()]);
+            loop {
+                if let Some(error) = check_refusal(&mut decoder) {
+                    return Err(error);
+                }
+                if decoder.read_from(&mut stream)? == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "invalid handshake"));
+                }
+            }
+        }
+        stream.read_exact(&mut welcome[MAGIC.len()..])?;
+        let welcome = check_welcome(&welcome)?;
+
+        let mut client = BlockingClient {
+            stream,
+            decoder: Decoder::new(),
+            events: VecDeque::new(),
+            request_id: 0
+        };
+        if let Some(credentials) = credentials {
+            check_auth_supported(&welcome)?;
+            client.stream.write_all(&credentials.to_message().into_bytes())?;
+            let reply = loop {
+                if let Some(message) = client.read_message(None)? {
+                    break message;
+                }
+            };
+            check_auth_reply(&reply)?;
+        }
+        Ok(client)
+    }
+
+    // Subscribe to a topic, which may contain wildcards
+    pub fn subscribe(&mut self, topic: &str) -> io::Result<()> {
+        self.request(MessageType::Subscribe, topic, None, false)
+    }
+
+    // Subscribe to a topic, without wildcards, on a server logging topics,
+    // replaying its log from the given offset first
+    pub fn subscribe_from(&mut self, topic: &str, start: StartOffset) -> io::Result<()> {
+        self.request_from(MessageType::Subscribe, topic, None, false, Some(start.to_offset()))
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> io::Result<()> {
+        self.request(MessageType::Unsubscribe, topic, None, false)
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        self.request(MessageType::Publish, topic, Some(payload), false)
+    }
+
+    // Publish an event that is also kept by the server, and sent to
+    // those subscribing to the topic later on
+    pub fn publish_retained(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        self.request(MessageType::Publish, topic, Some(payload), true)
+    }
+
+    // Wait for the next event
+    pub fn recv(&mut self) -> io::Result<Event> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            if let Some(message) = self.read_message(None)? {
+                self.on_message(message)?;
+            }
+        }
+    }
+
+    // Wait for the next event for no longer than the timeout.
+    // Returns None if none was received in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Event>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            match self.read_message(Some(deadline))? {
+                Some(message) => self.on_message(message)?,
+                None => return Ok(None)
+            }
+        }
+    }
+
+    // Iterate over the events received, until the connection is closed
+    pub fn events(&mut self) -> Events<'_> {
+        Events {
+            client: self
+        }
+    }
+
+    fn request(&mut self, message_type: MessageType, topic: &str, payload: Option<&[u8]>,
+               retain: bool) -> io::Result<()> {
+        self.request_from(message_type, topic, payload, retain, None)
+    }
+
+    fn request_from(&mut self, message_type: MessageType, topic: &str, payload: Option<&[u8]>,
+                    retain: bool, offset: Option<u64>) -> io::Result<()> {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(message_type)
+            .event_name(topic.to_string())
+            .retain(retain);
+        if let Some(payload) = payload {
+            builder.payload(payload.to_vec());
+        }
+        if let Some(offset) = offset {
+            builder.offset(offset);
+        }
+        let message = builder.build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput,
+                                        format!("invalid request: {:?}", e)))?;
+        self.stream.write_all(&message.into_bytes())?;
+        self.request_id = self.request_id.wrapping_add(1);
+
+        // Events published before the server got to the request
+        // may arrive before the reply
+        loop {
+            let message = match self.read_message(None)? {
+                Some(message) => message,
+                None => continue
+            };
+            match message.header.message_type {
+                MessageType::Ack if message.correlation_id() == Some(self.request_id) => {
+                    return Ok(());
+                },
+                MessageType::Error if message.correlation_id() == Some(self.request_id) => {
+                    return Err(io::Error::other(RequestError::from_message(&message)));
+                },
+                _ => self.on_message(message)?
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: Message) -> io::Result<()> {
+        match message.header.message_type {
+            MessageType::Event => {
+                self.events.push_back(Event::from_message(message));
+                Ok(())
+            },
+            // Not a reply to a request, so the server either revoked a
+            // subscription or is about to close the connection
+            MessageType::Error => {
+                Err(io::Error::other(RequestError::from_message(&message)))
+            },
+            // Replies to requests that are no longer waited for
+            _ => Ok(())
+        }
+    }
+
+    // Read until a whole message has been received, or the deadline
+    // (if any) has passed
+    fn read_message(&mut self, deadline: Option<Instant>) -> io::Result<Option<Message>> {
+        loop {
+            let decoded = self.decoder.decode()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                                            format!("invalid message: {:?}", e)));
+            if let Some(message) = decoded? {
+                return Ok(Some(message));
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    Some(deadline - now)
+                },
+                None => None
+            };
+            self.stream.set_read_timeout(timeout)?;
+
+            match self.decoder.read_from(&mut self.stream) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "connection closed by server"));
+                },
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut => {
+                    return Ok(None);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+// The events received by a blocking client. Ends when the connection
+// is closed by the server.
+pub struct Events<'a> {
+    client: &'a mut BlockingClient
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<io::Result<Event>> {
+        match self.client.recv() {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            result => Some(result)
+        }
+    }
+}
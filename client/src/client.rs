@@ -1,23 +1,375 @@
-use tokio_core::net::TcpStream;
-use tokio_core::reactor::Handle;
-use tokio_io::codec::Framed;
-use tokio_io::{AsyncRead, AsyncWrite};
-use futures::future::Future;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_util::codec::Framed;
+use futures::{SinkExt, Stream, StreamExt};
+use futures::channel::{mpsc, oneshot};
 
-use PubsubCodec;
+use pubsub::decoder::Decoder;
+use pubsub::handshake::{self, Handshake, HandshakeResult, HANDSHAKE_LEN, MAGIC};
+use pubsub::message::{ErrorCode, Message, MessageType, OFFSET_EARLIEST, OFFSET_LATEST};
 
+use crate::PubsubCodec;
+use crate::auth::{check_auth_reply, check_auth_supported};
+use crate::connection::{Command, Connection, Transport};
+use crate::options::ClientOptions;
+use crate::socket::{ServerAddress, Socket};
+
+use std::error;
+use std::fmt;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// An event received on a subscription
+#[derive(PartialEq, Debug, Clone)]
+pub struct Event {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    // Whether this is the retained event of the topic, sent on subscribing
+    pub retained: bool,
+    // The event's offset in the topic's log, if the server logs topics
+    pub offset: Option<u64>
+}
 
-type PubsubFuture = Box<Future<Item=Framed<TcpStream, PubsubCodec>, Error=io::Error>>;
+impl Event {
+    pub fn from_message(message: Message) -> Event {
+        Event {
+            topic: message.header.event_name,
+            payload: message.payload.unwrap_or_default(),
+            retained: message.header.retain,
+            offset: message.header.offset
+        }
+    }
+}
 
-pub struct PubsubClient;
+// Where a subscription starts in the topic's log
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum StartOffset {
+    // The oldest event the server still has
+    Earliest,
+    // The events published from now on
+    Latest,
+    At(u64)
+}
+
+impl StartOffset {
+    // The offset sent in the Subscribe message
+    pub fn to_offset(self) -> u64 {
+        match self {
+            StartOffset::Earliest => OFFSET_EARLIEST,
+            StartOffset::Latest => OFFSET_LATEST,
+            StartOffset::At(offset) => offset
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ConnectionState {
+    Connected,
+    // The connection was lost, and is being reestablished
+    Disconnected,
+    // The client gave up reconnecting, or is no longer used
+    Closed
+}
+
+// A request refused by the server
+#[derive(Debug, Clone)]
+pub struct RequestError {
+    pub topic: String,
+    pub code: Option<ErrorCode>
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "request for {} refused by server: {:?}", self.topic, code),
+            None => write!(f, "request for {} refused by server", self.topic)
+        }
+    }
+}
+
+impl RequestError {
+    // The error carried by an Error message
+    pub fn from_message(message: &Message) -> RequestError {
+        RequestError {
+            topic: message.header.event_name.clone(),
+            code: message.error_code()
+        }
+    }
+}
+
+impl error::Error for RequestError {
+    fn description(&self) -> &str {
+        "request refused by server"
+    }
+}
+
+// Connect, with TLS if the options say so, perform the handshake and
+// authenticate with the credentials, if any. The transport is only handed
+// out once the server has accepted the protocol version and the credentials.
+// Fails with PermissionDenied if the credentials are refused, and with
+// InvalidInput if TLS is asked for over a Unix socket, which doesn't use it.
+pub async fn connect_transport(addr: ServerAddress, options: ClientOptions)
+                               -> io::Result<Transport> {
+    let mut socket = match (addr, options.tls_options()) {
+        (ServerAddress::Tcp(addr), Some(tls)) => {
+            let (connector, server_name) = tls.connector()?;
+            let socket = TcpStream::connect(addr).await?;
+            Socket::Tls(Box::new(connector.connect(server_name, socket).await?))
+        },
+        (ServerAddress::Tcp(addr), None) => Socket::Tcp(TcpStream::connect(addr).await?),
+        (ServerAddress::Unix(_), Some(_)) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "TLS isn't supported over Unix sockets"));
+        },
+        (ServerAddress::Unix(path), None) => Socket::Unix(UnixStream::connect(path).await?)
+    };
+    socket.write_all(&Handshake::new().into_bytes()).await?;
+    let mut welcome = [0; HANDSHAKE_LEN];
+    socket.read_exact(&mut welcome[..MAGIC.len()]).await?;
+    if !is_welcome(&welcome) {
+        return Err(read_refusal(&mut socket, &welcome[..MAGIC.len()]).await);
+    }
+    socket.read_exact(&mut welcome[MAGIC.len()..]).await?;
+    let welcome = check_welcome(&welcome)?;
+    let mut transport = Framed::new(socket, PubsubCodec::new());
+
+    if let Some(credentials) = options.authentication() {
+        check_auth_supported(&welcome)?;
+        transport.send(credentials.to_message()).await?;
+        match transport.next().await {
+            Some(reply) => check_auth_reply(&reply?)?,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "connection closed by server"));
+            }
+        }
+    }
+    Ok(transport)
+}
+
+// A handle to a connection, driven by a task spawned on the tokio runtime.
+// Handles can be cloned to share the connection, which is closed once
+// every handle and subscription has been dropped.
+#[derive(Clone)]
+pub struct PubsubClient {
+    commands: mpsc::UnboundedSender<Command>
+}
 
 impl PubsubClient {
-    pub fn connect(addr: &SocketAddr, handle: &Handle) -> PubsubFuture {
-        Box::new(TcpStream::connect(addr, &handle)
-            .and_then(|socket| {
-                Ok(socket.framed(PubsubCodec))
-            }))
+    // Connect with the default options, reconnecting whenever
+    // the connection is lost
+    pub async fn connect(addr: &SocketAddr) -> io::Result<PubsubClient> {
+        PubsubClient::connect_with(addr, &ClientOptions::default()).await
+    }
+
+    // Connect with the given options. Fails if the first connection attempt
+    // fails, the options only apply once connected. Must be called from
+    // within a tokio runtime, which the connection task is spawned on.
+    pub async fn connect_with(addr: &SocketAddr, options: &ClientOptions)
+                              -> io::Result<PubsubClient> {
+        PubsubClient::connect_to(ServerAddress::Tcp(*addr), options).await
+    }
+
+    // Connect over the Unix socket at the given path, with the default options
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<PubsubClient> {
+        PubsubClient::connect_unix_with(path, &ClientOptions::default()).await
+    }
+
+    // Connect over the Unix socket at the given path, with the given options,
+    // which must not include TLS
+    pub async fn connect_unix_with<P: AsRef<Path>>(path: P, options: &ClientOptions)
+                                                   -> io::Result<PubsubClient> {
+        PubsubClient::connect_to(ServerAddress::Unix(path.as_ref().to_path_buf()), options).await
+    }
+
+    // Connect to a server listening on either kind of address
+    pub async fn connect_to(addr: ServerAddress, options: &ClientOptions)
+                            -> io::Result<PubsubClient> {
+        let transport = connect_transport(addr.clone(), options.clone()).await?;
+        let (commands, receiver) = mpsc::unbounded();
+        let connection = Connection::new(transport, addr, options.clone(), receiver);
+        tokio::spawn(connection);
+        Ok(PubsubClient {
+            commands
+        })
+    }
+
+    // Subscribe to a topic, which may contain wildcards. The subscription
+    // is made again after reconnecting. The stream fails if the server refuses
+    // the subscription or the client gives up reconnecting, and ends when
+    // unsubscribe is called for the same topic.
+    pub fn subscribe(&self, topic: &str) -> Subscription {
+        self.subscribe_to(topic, None)
+    }
+
+    // Subscribe to a topic, without wildcards, on a server logging topics,
+    // replaying its log from the given offset before the events published
+    // from then on. After reconnecting, the subscription is made again from
+    // the offset following the last event received, so none are missed
+    // (or from the given offset, if none has been received yet).
+    // The stream fails if the server can't replay the log from the offset.
+    pub fn subscribe_from(&self, topic: &str, start: StartOffset) -> Subscription {
+        self.subscribe_to(topic, Some(start))
+    }
+
+    fn subscribe_to(&self, topic: &str, start: Option<StartOffset>) -> Subscription {
+        let (sender, events) = mpsc::unbounded();
+        let command = Command::Subscribe(topic.to_string(), start, sender);
+        if let Err(e) = self.commands.unbounded_send(command) {
+            // The stream gets the error instead of hanging
+            if let Command::Subscribe(_, _, sender) = e.into_inner() {
+                let _ = sender.unbounded_send(Err(connection_closed()));
+            }
+        }
+        Subscription {
+            events,
+            commands: self.commands.clone()
+        }
+    }
+
+    // Unsubscribe from a topic, ending every stream subscribed to it.
+    // Resolves once the server has acknowledged it.
+    pub fn unsubscribe(&self, topic: &str) -> impl Future<Output=io::Result<()>> {
+        let (reply, result) = oneshot::channel();
+        self.send(Command::Unsubscribe(topic.to_string(), reply), result)
+    }
+
+    // Publish an event. Resolves once the server has acknowledged it.
+    // While disconnected, the event is buffered until reconnected. It fails
+    // if the connection is lost after it has been sent, as it's then unknown
+    // whether the server got it.
+    pub fn publish(&self, topic: &str, payload: Vec<u8>) -> impl Future<Output=io::Result<()>> {
+        let (reply, result) = oneshot::channel();
+        self.send(Command::Publish(topic.to_string(), payload, false, reply), result)
+    }
+
+    // Publish an event that is also kept by the server, and sent to
+    // those subscribing to the topic later on
+    pub fn publish_retained(&self, topic: &str, payload: Vec<u8>)
+                            -> impl Future<Output=io::Result<()>> {
+        let (reply, result) = oneshot::channel();
+        self.send(Command::Publish(topic.to_string(), payload, true, reply), result)
+    }
+
+    // The current state of the connection, followed by every change to it.
+    // Ends once the connection is closed.
+    pub fn state_changes(&self) -> StateChanges {
+        let (sender, states) = mpsc::unbounded();
+        if let Err(e) = self.commands.unbounded_send(Command::Watch(sender)) {
+            if let Command::Watch(sender) = e.into_inner() {
+                let _ = sender.unbounded_send(ConnectionState::Closed);
+            }
+        }
+        StateChanges {
+            states
+        }
+    }
+
+    // The request is sent right away, rather than when the returned future
+    // is first polled, so that requests are made in the order of the calls
+    fn send(&self, command: Command, result: oneshot::Receiver<io::Result<()>>)
+            -> impl Future<Output=io::Result<()>> {
+        // If the connection task is gone, the reply is dropped with the command
+        let _ = self.commands.unbounded_send(command);
+        async move {
+            match result.await {
+                Ok(result) => result,
+                // The connection task went away without replying
+                Err(_) => Err(connection_closed())
+            }
+        }
+    }
+}
+
+// The events of a subscription
+pub struct Subscription {
+    events: mpsc::UnboundedReceiver<io::Result<Event>>,
+    // To let the connection know when the subscription is dropped
+    commands: mpsc::UnboundedSender<Command>
+}
+
+// A subscription that fails yields the error, and then ends
+impl Stream for Subscription {
+    type Item = io::Result<Event>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Event>>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.events.close();
+        let _ = self.commands.unbounded_send(Command::Release);
+    }
+}
+
+// Changes to the state of the connection
+pub struct StateChanges {
+    states: mpsc::UnboundedReceiver<ConnectionState>
+}
+
+impl Stream for StateChanges {
+    type Item = ConnectionState;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ConnectionState>> {
+        self.states.poll_next_unpin(cx)
+    }
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "connection closed")
+}
+
+// The server may refuse the connection with an Error message in place of
+// the Welcome. Only the Welcome starts with the magic bytes, so the first
+// MAGIC.len() bytes received tell which one is coming.
+pub fn is_welcome(start: &[u8]) -> bool {
+    start.starts_with(MAGIC)
+}
+
+pub fn check_welcome(data: &[u8]) -> io::Result<Handshake> {
+    match handshake::parse(data) {
+        HandshakeResult::Completed(ref welcome, _) if welcome.is_supported() => Ok(*welcome),
+        HandshakeResult::Completed(welcome, _) => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               format!("unsupported protocol version {}", welcome.version)))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid handshake"))
+    }
+}
+
+// The error to fail connecting with, once the refusal received so far has
+// been fed to the decoder, or None if more of it is needed
+pub fn check_refusal(decoder: &mut Decoder) -> Option<io::Error> {
+    match decoder.decode() {
+        Ok(Some(ref message)) if message.header.message_type == MessageType::Error => {
+            Some(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                format!("connection refused by server: {:?}",
+                                        message.error_code())))
+        },
+        Ok(None) => None,
+        _ => Some(io::Error::new(io::ErrorKind::InvalidData, "invalid handshake"))
+    }
+}
+
+// Read the rest of a refusal, starting with the bytes already received
+async fn read_refusal(socket: &mut Socket, start: &[u8]) -> io::Error {
+    let mut decoder = Decoder::new();
+    decoder.feed(start);
+    let mut buf = [0; 256];
+    loop {
+        if let Some(error) = check_refusal(&mut decoder) {
+            return error;
+        }
+        match socket.read(&mut buf).await {
+            Ok(0) => return io::Error::new(io::ErrorKind::UnexpectedEof, "invalid handshake"),
+            Ok(len) => decoder.feed(&buf[..len]),
+            Err(e) => return e
+        }
     }
 }
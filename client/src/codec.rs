@@ -1,64 +1,86 @@
-use tokio_io::codec::{Encoder, Decoder};
-use bytes::BytesMut;
-use pubsub::message::{Message, MessageHeader, MessageBuilder};
-use pubsub::parser::{parse, ParseResult};
+use tokio_util::codec::{Encoder, Decoder};
+use bytes::{Buf, BytesMut};
+use pubsub::decoder;
+use pubsub::message::Message;
 
 use std::io;
 
-// Previous idiotic design decisions make this contrieved
-// and stupid part necessary
-fn build_message(header: MessageHeader, payload: Option<Vec<u8>>) -> io::Result<Message> {
-    let mut builder = MessageBuilder::new();
-    builder.message_type(header.message_type)
-        .event_name(header.event_name);
+#[derive(Default)]
+pub struct PubsubCodec {
+    decoder: decoder::Decoder
+}
 
-    if let Some(payload) = payload {
-        builder.payload(payload);
+impl PubsubCodec {
+    pub fn new() -> PubsubCodec {
+        PubsubCodec::default()
     }
-    let message = try!(builder.build()
-                       .or(Err(io::Error::new(io::ErrorKind::Other,
-                                              "invalid message"))));
-    Ok(message)
 }
 
-pub struct PubsubCodec;
-
 impl Decoder for PubsubCodec {
     type Item = Message;
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
-        match parse(&buf) {
-            ParseResult::Completed(header, consumed, payload_len) => {
-                if header.message_type.expects_payload() {
-                    if buf.len() >= consumed + payload_len {
-                        buf.split_to(consumed);
-                        let payload = buf.split_to(payload_len).to_vec();
-                        Ok(Some(try!(build_message(header, Some(payload)))))
-                    }
-                    else {
-                        Ok(None)
-                    }
-                }
-                else {
-                    buf.split_to(consumed);
-                    Ok(Some(try!(build_message(header, None))))
-                }
-            },
+        // The decoder keeps track of partially received messages itself,
+        // and tells how much of the buffer it has used up
+        let (message, consumed) = self.decoder.decode_from(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("invalid message: {:?}", e)))?;
+        buf.advance(consumed);
+        Ok(message)
+    }
 
-            ParseResult::Incomplete => Ok(None),
-            ParseResult::Error => Err(io::Error::new(io::ErrorKind::Other,
-                                                     "invalid message"))
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        match self.decode(buf)? {
+            Some(message) => Ok(Some(message)),
+            None if buf.is_empty() && !self.decoder.has_partial_message() => Ok(None),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                       "connection closed in the middle of a message"))
         }
     }
 }
 
-impl Encoder for PubsubCodec {
-    type Item = Message;
+impl Encoder<Message> for PubsubCodec {
     type Error = io::Error;
 
-    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-        buf.extend(msg.into_bytes());
+    fn encode(&mut self, msg: Message, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(&msg.into_bytes());
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::PubsubCodec;
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+    use pubsub::message::Message;
+
+    #[test]
+    fn test_decode_in_place() {
+        let mut codec = PubsubCodec::new();
+        let mut bytes = Message::ack("event".to_string(), 1).into_bytes();
+        bytes.extend(Message::ack("other".to_string(), 2).into_bytes());
+        let mut buf = BytesMut::from(&bytes[..bytes.len() - 1]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::ack("event".to_string(), 1)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&bytes[bytes.len() - 1..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::ack("other".to_string(), 2)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_eof() {
+        let bytes = Message::ack("event".to_string(), 1).into_bytes();
+        let mut codec = PubsubCodec::new();
+        let mut buf = BytesMut::from(&bytes[..]);
+        assert!(codec.decode_eof(&mut buf).unwrap().is_some());
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+
+        // Closed in the middle of the payload
+        let mut buf = BytesMut::from(&bytes[..bytes.len() - 1]);
+        assert!(codec.decode_eof(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,537 @@
+use futures::{SinkExt, StreamExt};
+use futures::channel::{mpsc, oneshot};
+use tokio::time::{self, Sleep};
+use tokio_util::codec::Framed;
+
+use pubsub::message::{ErrorCode, Message, MessageBuilder, MessageType};
+use pubsub::topic;
+
+use crate::PubsubCodec;
+use crate::client::{connect_transport, ConnectionState, Event, RequestError, StartOffset};
+use crate::options::ClientOptions;
+use crate::socket::{ServerAddress, Socket};
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub type Transport = Framed<Socket, PubsubCodec>;
+pub type Reply = oneshot::Sender<io::Result<()>>;
+pub type EventSender = mpsc::UnboundedSender<io::Result<Event>>;
+pub type StateSender = mpsc::UnboundedSender<ConnectionState>;
+
+// Requests from the client handles to the connection task
+pub enum Command {
+    // Pattern, and where to start in the topic's log, if anywhere
+    Subscribe(String, Option<StartOffset>, EventSender),
+    Unsubscribe(String, Reply),
+    // Topic, payload and whether the event should be retained
+    Publish(String, Vec<u8>, bool, Reply),
+    // A subscription stream was dropped
+    Release,
+    // Send connection state changes to the given sender
+    Watch(StateSender)
+}
+
+// What to do with the server's reply to a request
+enum PendingRequest {
+    // Nothing on success, but the subscriptions the request was made for,
+    // by id, are failed if the server refuses it
+    Subscribe(Vec<u64>),
+    Reply(Reply)
+}
+
+struct Subscription {
+    // Tells apart the subscriptions to the same pattern
+    id: u64,
+    pattern: String,
+    // Where to start in the topic's log when subscribing again, moved on
+    // past each event received. None for plain subscriptions.
+    start: Option<StartOffset>,
+    events: EventSender
+}
+
+impl Subscription {
+    // Whether the event is new to the subscription. Replaying a log for
+    // another subscription to the topic may send events it already got.
+    fn receive(&mut self, event: &Event) -> bool {
+        match (self.start, event.offset) {
+            (Some(StartOffset::At(next)), Some(offset)) if offset < next => false,
+            (Some(_), Some(offset)) => {
+                self.start = Some(StartOffset::At(offset + 1));
+                true
+            },
+            _ => true
+        }
+    }
+}
+
+enum State {
+    Connected(Transport),
+    // Waiting for the backoff delay to pass before reconnecting
+    Waiting(Pin<Box<Sleep>>),
+    Connecting(Pin<Box<dyn Future<Output=io::Result<Transport>> + Send>>),
+    Closed
+}
+
+// Drives a connection: writes the requests made through the client handles,
+// and routes the events received to the subscriptions they match.
+// When the connection is lost, it reconnects and subscribes again to
+// everything that was subscribed to. Runs until every client handle and
+// subscription has been dropped, or it gives up reconnecting.
+pub struct Connection {
+    address: ServerAddress,
+    options: ClientOptions,
+    state: State,
+    // The last state sent to the watchers
+    reported_state: ConnectionState,
+    // Number of failed reconnection attempts in a row
+    attempts: u32,
+    commands: mpsc::UnboundedReceiver<Command>,
+    // Set once every sender of commands has been dropped
+    commands_done: bool,
+    // Requests yet to be written. While disconnected, only publishes are kept.
+    outgoing: VecDeque<(Message, PendingRequest)>,
+    // The server numbers requests in the order they are received,
+    // starting at 1 on each connection, and uses the number as
+    // correlation id in replies
+    last_request_id: u32,
+    pending: HashMap<u32, PendingRequest>,
+    subscriptions: Vec<Subscription>,
+    next_subscription_id: u64,
+    watchers: Vec<StateSender>
+}
+
+impl Connection {
+    pub fn new(transport: Transport, address: ServerAddress, options: ClientOptions,
+               commands: mpsc::UnboundedReceiver<Command>) -> Connection {
+        Connection {
+            address,
+            options,
+            state: State::Connected(transport),
+            reported_state: ConnectionState::Connected,
+            attempts: 0,
+            commands,
+            commands_done: false,
+            outgoing: VecDeque::new(),
+            last_request_id: 0,
+            pending: HashMap::new(),
+            subscriptions: Vec::new(),
+            next_subscription_id: 0,
+            watchers: Vec::new()
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected(_))
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        match self.state {
+            State::Connected(_) => ConnectionState::Connected,
+            State::Waiting(_) | State::Connecting(_) => ConnectionState::Disconnected,
+            State::Closed => ConnectionState::Closed
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        let connection_state = self.connection_state();
+        if connection_state != self.reported_state {
+            self.reported_state = connection_state;
+            self.watchers.retain(|w| w.unbounded_send(connection_state).is_ok());
+        }
+    }
+
+    fn request(&mut self, message_type: MessageType, topic: String, payload: Option<Vec<u8>>,
+               retain: bool, pending: PendingRequest) {
+        self.request_from(message_type, topic, payload, retain, None, pending);
+    }
+
+    fn request_from(&mut self, message_type: MessageType, topic: String,
+                    payload: Option<Vec<u8>>, retain: bool, offset: Option<u64>,
+                    pending: PendingRequest) {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(message_type)
+            .event_name(topic)
+            .retain(retain);
+        if let Some(payload) = payload {
+            builder.payload(payload);
+        }
+        if let Some(offset) = offset {
+            builder.offset(offset);
+        }
+        match builder.build() {
+            Ok(message) => self.outgoing.push_back((message, pending)),
+            Err(e) => {
+                self.fail_request(pending, || {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid request: {:?}", e))
+                });
+            }
+        }
+    }
+
+    fn on_command(&mut self, command: Command) {
+        match command {
+            Command::Subscribe(pattern, start, events) => {
+                let id = self.next_subscription_id;
+                self.next_subscription_id += 1;
+                self.subscriptions.push(Subscription {
+                    id,
+                    pattern: pattern.clone(),
+                    start,
+                    events
+                });
+                // Otherwise it's sent when reconnected
+                if self.is_connected() {
+                    self.request_from(MessageType::Subscribe, pattern, None, false,
+                                      start.map(StartOffset::to_offset),
+                                      PendingRequest::Subscribe(vec![id]));
+                }
+            },
+            Command::Unsubscribe(pattern, reply) => {
+                // Dropping the senders ends the streams
+                self.subscriptions.retain(|s| s.pattern != pattern);
+                if self.is_connected() {
+                    self.request(MessageType::Unsubscribe, pattern, None, false,
+                                 PendingRequest::Reply(reply));
+                }
+                else {
+                    let _ = reply.send(Ok(()));
+                }
+            },
+            Command::Publish(topic, payload, retain, reply) => {
+                if !self.is_connected() && self.outgoing.len() >= self.options.buffered_publishes() {
+                    let _ = reply.send(Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                                          "disconnected and publish buffer full")));
+                    return;
+                }
+                self.request(MessageType::Publish, topic, Some(payload), retain,
+                             PendingRequest::Reply(reply));
+            },
+            Command::Release => self.release_subscriptions(),
+            Command::Watch(watcher) => {
+                if watcher.unbounded_send(self.reported_state).is_ok() {
+                    self.watchers.push(watcher);
+                }
+            }
+        }
+    }
+
+    // Forget the subscriptions whose streams have been dropped, and
+    // unsubscribe from the patterns no stream is interested in any more
+    fn release_subscriptions(&mut self) {
+        let (released, active): (Vec<_>, Vec<_>) = self.subscriptions.drain(..)
+            .partition(|s| s.events.is_closed());
+        self.subscriptions = active;
+        if !self.is_connected() {
+            return;
+        }
+        for subscription in released {
+            let pattern = subscription.pattern;
+            if self.subscriptions.iter().any(|s| s.pattern == pattern) {
+                continue;
+            }
+            // Nobody is waiting for the outcome
+            let (reply, _) = oneshot::channel();
+            self.request(MessageType::Unsubscribe, pattern, None, false,
+                         PendingRequest::Reply(reply));
+        }
+    }
+
+    fn on_message(&mut self, message: Message) {
+        match message.header.message_type {
+            MessageType::Event => {
+                let event = Event::from_message(message);
+                let mut released = false;
+                for subscription in &mut self.subscriptions {
+                    if topic::matches(&subscription.pattern, &event.topic) && subscription.receive(&event) {
+                        released |= subscription.events.unbounded_send(Ok(event.clone())).is_err();
+                    }
+                }
+                if released {
+                    self.release_subscriptions();
+                }
+            },
+            MessageType::Ack => {
+                let pending = message.correlation_id()
+                    .and_then(|id| self.pending.remove(&id));
+                if let Some(PendingRequest::Reply(reply)) = pending {
+                    let _ = reply.send(Ok(()));
+                }
+            },
+            MessageType::Error => {
+                let error = RequestError::from_message(&message);
+                let pending = message.correlation_id()
+                    .and_then(|id| self.pending.remove(&id));
+                match pending {
+                    Some(pending) => {
+                        self.fail_request(pending, || io::Error::other(error.clone()));
+                    },
+                    // The server revoked the subscriptions to the pattern
+                    None if error.code == Some(ErrorCode::AccessDenied) => {
+                        let ids = self.subscriptions.iter()
+                            .filter(|s| s.pattern == error.topic)
+                            .map(|s| s.id)
+                            .collect();
+                        self.fail_request(PendingRequest::Subscribe(ids),
+                                          || io::Error::other(error.clone()));
+                    },
+                    // Otherwise the server is about to close the connection
+                    None => {}
+                }
+            },
+            // Only sent by clients
+            MessageType::Subscribe | MessageType::Unsubscribe | MessageType::Publish
+                | MessageType::Authenticate => {}
+        }
+    }
+
+    // Fail a request with the error made by the given function,
+    // which is called for each party waiting for the outcome
+    fn fail_request<F>(&mut self, pending: PendingRequest, error: F)
+        where F: Fn() -> io::Error {
+        match pending {
+            PendingRequest::Subscribe(ids) => {
+                // The streams end after the error, as their senders are dropped.
+                // Other subscriptions to the pattern are left alone.
+                let (failed, active): (Vec<_>, Vec<_>) = self.subscriptions.drain(..)
+                    .partition(|s| ids.contains(&s.id));
+                self.subscriptions = active;
+                for subscription in failed {
+                    let _ = subscription.events.unbounded_send(Err(error()));
+                }
+            },
+            PendingRequest::Reply(reply) => { let _ = reply.send(Err(error())); }
+        }
+    }
+
+    // Fail every request and subscription, as the connection is gone for good
+    fn close(&mut self, error: io::Error) {
+        for (_, pending) in self.pending.drain() {
+            if let PendingRequest::Reply(reply) = pending {
+                let _ = reply.send(Err(copy_error(&error)));
+            }
+        }
+        for (_, pending) in self.outgoing.drain(..) {
+            if let PendingRequest::Reply(reply) = pending {
+                let _ = reply.send(Err(copy_error(&error)));
+            }
+        }
+        for subscription in self.subscriptions.drain(..) {
+            let _ = subscription.events.unbounded_send(Err(copy_error(&error)));
+        }
+        self.set_state(State::Closed);
+    }
+
+    fn on_disconnect(&mut self, error: io::Error) {
+        if !self.options.reconnects() {
+            self.close(error);
+            return;
+        }
+
+        // Requests that have been written may or may not have been carried out
+        let lost = io::Error::new(io::ErrorKind::ConnectionAborted,
+                                  format!("connection lost: {}", error));
+        for (_, pending) in self.pending.drain() {
+            if let PendingRequest::Reply(reply) = pending {
+                let _ = reply.send(Err(copy_error(&lost)));
+            }
+        }
+        self.last_request_id = 0;
+
+        // Subscriptions are sent again once reconnected, and the server
+        // won't remember the ones that were unsubscribed from
+        let outgoing = mem::take(&mut self.outgoing);
+        for (message, pending) in outgoing {
+            match message.header.message_type {
+                MessageType::Publish => self.outgoing.push_back((message, pending)),
+                _ => {
+                    if let PendingRequest::Reply(reply) = pending {
+                        let _ = reply.send(Ok(()));
+                    }
+                }
+            }
+        }
+
+        self.attempts = 0;
+        self.reconnect_later(error);
+    }
+
+    fn reconnect_later(&mut self, error: io::Error) {
+        if !self.options.may_retry(self.attempts) {
+            let error = io::Error::new(error.kind(), format!("couldn't reconnect: {}", error));
+            self.close(error);
+            return;
+        }
+        let delay = self.options.backoff(self.attempts, rand::random());
+        self.attempts += 1;
+        self.set_state(State::Waiting(Box::pin(time::sleep(delay))));
+    }
+
+    fn on_reconnect(&mut self, transport: Transport) {
+        self.attempts = 0;
+        // Subscribe again before sending the publishes made while disconnected.
+        // A topic subscribed to from offsets is replayed from the earliest
+        // one any of its subscriptions is at.
+        let mut patterns: Vec<(String, Option<u64>, Vec<u64>)> = Vec::new();
+        for subscription in &self.subscriptions {
+            let offset = subscription.start.map(StartOffset::to_offset);
+            match patterns.iter_mut().find(|&&mut (ref pattern, _, _)| *pattern == subscription.pattern) {
+                Some(&mut (_, ref mut earliest, ref mut ids)) => {
+                    *earliest = match (*earliest, offset) {
+                        (Some(earliest), Some(offset)) => Some(earliest.min(offset)),
+                        (earliest, offset) => earliest.or(offset)
+                    };
+                    ids.push(subscription.id);
+                },
+                None => patterns.push((subscription.pattern.clone(), offset, vec![subscription.id]))
+            }
+        }
+        let publishes = mem::take(&mut self.outgoing);
+        for (pattern, offset, ids) in patterns {
+            self.request_from(MessageType::Subscribe, pattern, None, false, offset,
+                              PendingRequest::Subscribe(ids));
+        }
+        self.outgoing.extend(publishes);
+        self.set_state(State::Connected(transport));
+    }
+
+    fn poll_commands(&mut self, cx: &mut Context<'_>) {
+        while !self.commands_done {
+            match self.commands.poll_next_unpin(cx) {
+                Poll::Ready(Some(command)) => self.on_command(command),
+                Poll::Ready(None) => self.commands_done = true,
+                Poll::Pending => break
+            }
+        }
+    }
+
+    fn poll_messages(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        loop {
+            let message = match self.state {
+                State::Connected(ref mut transport) => match transport.poll_next_unpin(cx) {
+                    Poll::Ready(Some(message)) => message?,
+                    Poll::Ready(None) => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "connection closed by server"));
+                    },
+                    Poll::Pending => return Ok(())
+                },
+                _ => return Ok(())
+            };
+            self.on_message(message);
+        }
+    }
+
+    // Returns whether everything written so far has been flushed
+    fn write_messages(&mut self, cx: &mut Context<'_>) -> io::Result<bool> {
+        let transport = match self.state {
+            State::Connected(ref mut transport) => transport,
+            _ => return Ok(false)
+        };
+        while !self.outgoing.is_empty() {
+            if transport.poll_ready_unpin(cx)?.is_pending() {
+                break;
+            }
+            let (message, pending) = self.outgoing.pop_front().unwrap();
+            transport.start_send_unpin(message)?;
+            self.last_request_id = self.last_request_id.wrapping_add(1);
+            self.pending.insert(self.last_request_id, pending);
+        }
+        Ok(transport.poll_flush_unpin(cx)?.is_ready())
+    }
+
+    // Read and write as much as possible. Returns whether everything
+    // has been written.
+    fn poll_transport(&mut self, cx: &mut Context<'_>) -> io::Result<bool> {
+        self.poll_messages(cx)?;
+        let flushed = self.write_messages(cx)? && self.outgoing.is_empty();
+        Ok(flushed)
+    }
+
+    fn poll_connection(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            self.poll_commands(cx);
+            let state = mem::replace(&mut self.state, State::Closed);
+            match state {
+                State::Connected(transport) => {
+                    self.state = State::Connected(transport);
+                    match self.poll_transport(cx) {
+                        // Done once nobody can make requests or receive events
+                        // any more, and every request has been answered
+                        Ok(flushed) => {
+                            if self.commands_done && self.subscriptions.is_empty()
+                                && flushed && self.pending.is_empty() {
+                                self.set_state(State::Closed);
+                                return Poll::Ready(());
+                            }
+                            return Poll::Pending;
+                        },
+                        Err(e) => {
+                            self.on_disconnect(e);
+                            continue;
+                        }
+                    }
+                },
+                State::Waiting(mut sleep) => {
+                    match sleep.as_mut().poll(cx) {
+                        Poll::Pending => {
+                            self.state = State::Waiting(sleep);
+                        },
+                        Poll::Ready(()) => {
+                            let connect = connect_transport(self.address.clone(), self.options.clone());
+                            self.state = State::Connecting(Box::pin(connect));
+                            continue;
+                        }
+                    }
+                },
+                State::Connecting(mut connect) => {
+                    match connect.as_mut().poll(cx) {
+                        Poll::Ready(Ok(transport)) => {
+                            self.on_reconnect(transport);
+                            continue;
+                        },
+                        Poll::Pending => {
+                            self.state = State::Connecting(connect);
+                        },
+                        // Retrying with credentials that were refused is pointless
+                        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::PermissionDenied => {
+                            self.close(copy_error(e));
+                            continue;
+                        },
+                        Poll::Ready(Err(e)) => {
+                            self.reconnect_later(e);
+                            continue;
+                        }
+                    }
+                },
+                State::Closed => return Poll::Ready(())
+            }
+
+            // Waiting to reconnect. Nobody will know if it ever happens
+            // once every handle has been dropped.
+            if self.commands_done && self.subscriptions.is_empty() {
+                self.close(io::Error::new(io::ErrorKind::NotConnected, "connection closed"));
+                return Poll::Ready(());
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+impl Future for Connection {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.get_mut().poll_connection(cx)
+    }
+}
+
+// io::Error isn't Clone
+fn copy_error(error: &io::Error) -> io::Error {
+    io::Error::new(error.kind(), error.to_string())
+}
@@ -1,14 +1,20 @@
-extern crate pubsub;
-extern crate tokio_core;
-extern crate tokio_io;
-extern crate futures;
-extern crate bytes;
-
+mod auth;
 mod codec;
 mod client;
+mod connection;
+mod options;
+mod socket;
+mod tls;
+mod blocking;
 
-use codec::PubsubCodec;
-pub use client::PubsubClient;
+use crate::codec::PubsubCodec;
+pub use crate::auth::Credentials;
+pub use crate::client::{connect_transport, ConnectionState, Event, PubsubClient, RequestError,
+                 StartOffset, StateChanges, Subscription};
+pub use crate::options::ClientOptions;
+pub use crate::socket::ServerAddress;
+pub use crate::tls::TlsOptions;
+pub use crate::blocking::{BlockingClient, Events};
 
 #[cfg(test)]
 mod tests {
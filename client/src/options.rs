@@ -0,0 +1,164 @@
+use crate::auth::Credentials;
+use crate::tls::TlsOptions;
+
+use std::cmp;
+use std::time::Duration;
+
+// How a client connects and authenticates, and behaves when its
+// connection is lost
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    credentials: Option<Credentials>,
+    tls: Option<TlsOptions>,
+    reconnect: bool,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    max_reconnect_attempts: Option<u32>,
+    publish_buffer: usize
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            credentials: None,
+            tls: None,
+            reconnect: true,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.5,
+            max_reconnect_attempts: None,
+            publish_buffer: 1024
+        }
+    }
+}
+
+impl ClientOptions {
+    pub fn new() -> ClientOptions {
+        ClientOptions::default()
+    }
+
+    // Authenticate with these credentials on connecting and reconnecting.
+    // Connecting fails if the server doesn't accept them.
+    pub fn credentials(&mut self, credentials: Credentials) -> &mut ClientOptions {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    // Connect over TLS, verifying the server as given
+    pub fn tls(&mut self, tls: TlsOptions) -> &mut ClientOptions {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Whether to reconnect at all. Without reconnecting, subscriptions
+    // and pending requests fail once the connection is lost.
+    pub fn reconnect(&mut self, reconnect: bool) -> &mut ClientOptions {
+        self.reconnect = reconnect;
+        self
+    }
+
+    // The delay before the first reconnection attempt, which is doubled
+    // for every following attempt up to the maximum
+    pub fn initial_backoff(&mut self, backoff: Duration) -> &mut ClientOptions {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(&mut self, backoff: Duration) -> &mut ClientOptions {
+        self.max_backoff = backoff;
+        self
+    }
+
+    // The fraction of each delay that is randomized, from 0 to 1, to keep
+    // clients from reconnecting all at once when a server comes back
+    pub fn jitter(&mut self, jitter: f64) -> &mut ClientOptions {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    // Give up after this many failed attempts in a row. Retries forever by default.
+    pub fn max_reconnect_attempts(&mut self, attempts: u32) -> &mut ClientOptions {
+        self.max_reconnect_attempts = Some(attempts);
+        self
+    }
+
+    // The number of publishes kept while disconnected, to be sent once
+    // reconnected. Publishing fails right away when the buffer is full.
+    pub fn publish_buffer(&mut self, publishes: usize) -> &mut ClientOptions {
+        self.publish_buffer = publishes;
+        self
+    }
+
+    pub fn authentication(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn tls_options(&self) -> Option<&TlsOptions> {
+        self.tls.as_ref()
+    }
+
+    pub fn reconnects(&self) -> bool {
+        self.reconnect
+    }
+
+    pub fn buffered_publishes(&self) -> usize {
+        self.publish_buffer
+    }
+
+    pub fn may_retry(&self, attempt: u32) -> bool {
+        self.max_reconnect_attempts.is_none_or(|max| attempt < max)
+    }
+
+    // The delay before the given reconnection attempt (counting from 0),
+    // for a random number between 0 and 1
+    pub fn backoff(&self, attempt: u32, random: f64) -> Duration {
+        let initial = duration_ms(self.initial_backoff) as f64;
+        let max = duration_ms(self.max_backoff) as f64;
+        let delay = (initial * 2f64.powi(cmp::min(attempt, 32) as i32)).min(max);
+        Duration::from_millis((delay * (1.0 - self.jitter * random)) as u64)
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::ClientOptions;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let mut options = ClientOptions::new();
+        options.initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_secs(1))
+            .jitter(0.0);
+        assert_eq!(options.backoff(0, 0.5), Duration::from_millis(100));
+        assert_eq!(options.backoff(1, 0.5), Duration::from_millis(200));
+        assert_eq!(options.backoff(3, 0.5), Duration::from_millis(800));
+        assert_eq!(options.backoff(4, 0.5), Duration::from_secs(1));
+        assert_eq!(options.backoff(1000, 0.5), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut options = ClientOptions::new();
+        options.initial_backoff(Duration::from_millis(100))
+            .jitter(0.5);
+        assert_eq!(options.backoff(0, 0.0), Duration::from_millis(100));
+        assert_eq!(options.backoff(0, 0.5), Duration::from_millis(75));
+        assert_eq!(options.backoff(0, 1.0), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let mut options = ClientOptions::new();
+        assert!(options.may_retry(1000));
+        options.max_reconnect_attempts(3);
+        assert!(options.may_retry(2));
+        assert!(!options.may_retry(3));
+    }
+}
@@ -0,0 +1,99 @@
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+use std::convert::TryFrom;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// How to verify a server serving clients over TLS, and which certificate
+// to present to it, if any
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsOptions {
+    server_name: String,
+    ca_file: PathBuf,
+    // Certificate chain and private key
+    client_certificate: Option<(PathBuf, PathBuf)>
+}
+
+impl TlsOptions {
+    // The server's certificate must be valid for server_name, and signed
+    // by one of the CA certificates in the given PEM file
+    pub fn new<P: Into<PathBuf>>(server_name: &str, ca_file: P) -> TlsOptions {
+        TlsOptions {
+            server_name: server_name.to_string(),
+            ca_file: ca_file.into(),
+            client_certificate: None
+        }
+    }
+
+    // Present the certificate chain and private key from the given PEM
+    // files, for servers that only accept clients with a certificate
+    pub fn client_certificate<P, Q>(&mut self, certificate: P, key: Q) -> &mut TlsOptions
+        where P: Into<PathBuf>, Q: Into<PathBuf> {
+        self.client_certificate = Some((certificate.into(), key.into()));
+        self
+    }
+
+    // Load the files. This is done for every connection attempt, so that
+    // certificates that have been replaced are picked up when reconnecting.
+    pub fn connector(&self) -> io::Result<(TlsConnector, ServerName<'static>)> {
+        let server_name = ServerName::try_from(self.server_name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut roots = RootCertStore::empty();
+        for certificate in load_certificates(&self.ca_file)? {
+            roots.add(certificate).map_err(|e| tls_error(&self.ca_file, e))?;
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots);
+        let config = match self.client_certificate {
+            Some((ref certificate, ref key)) => {
+                let chain = load_certificates(certificate)?;
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| tls_error(key, e))?;
+                builder.with_client_auth_cert(chain, key).map_err(|e| tls_error(certificate, e))?
+            },
+            None => builder.with_no_client_auth()
+        };
+        Ok((TlsConnector::from(Arc::new(config)), server_name))
+    }
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(path, e))?;
+    if certificates.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certificates)
+}
+
+fn tls_error<E: ToString>(path: &Path, error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   format!("couldn't load {}: {}", path.display(), error.to_string()))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::TlsOptions;
+    use std::io;
+
+    #[test]
+    fn test_missing_files() {
+        let error = TlsOptions::new("localhost", "/nonexistent/ca.pem").connector().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().starts_with("couldn't load /nonexistent/ca.pem"));
+    }
+
+    #[test]
+    fn test_invalid_server_name() {
+        let error = TlsOptions::new("not a name", "/nonexistent/ca.pem").connector().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
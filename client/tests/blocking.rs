@@ -0,0 +1,156 @@
+
+use pubsub::message::ErrorCode;
+use pubsub_client::{BlockingClient, Credentials, Event, RequestError, StartOffset};
+use pubsub_server::{ServerBuilder, ServerHandle};
+
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use std::time::{Duration, Instant};
+
+fn start_server() -> ServerHandle {
+    ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap()
+}
+
+fn event(topic: &str, payload: &[u8]) -> Event {
+    Event {
+        topic: topic.to_string(),
+        payload: payload.to_vec(),
+        retained: false,
+        offset: None
+    }
+}
+
+#[test]
+fn test_publish_and_subscribe() {
+    let server = start_server();
+    let mut subscriber = BlockingClient::connect(server.local_addr()).unwrap();
+    let mut publisher = BlockingClient::connect(server.local_addr()).unwrap();
+
+    subscriber.subscribe("sensors.*").unwrap();
+    publisher.publish("sensors.temp", b"21").unwrap();
+    publisher.publish("sensors.humidity", b"40").unwrap();
+
+    assert_eq!(subscriber.recv().unwrap(), event("sensors.temp", b"21"));
+    assert_eq!(subscriber.recv_timeout(Duration::from_secs(10)).unwrap(),
+               Some(event("sensors.humidity", b"40")));
+}
+
+#[test]
+fn test_connection_refused() {
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .max_connections(1)
+        .start()
+        .unwrap();
+    let _client = BlockingClient::connect(server.local_addr()).unwrap();
+    let error = BlockingClient::connect(server.local_addr()).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(error.to_string().contains("ServerFull"));
+}
+
+#[test]
+fn test_recv_timeout() {
+    let server = start_server();
+    let mut client = BlockingClient::connect(server.local_addr()).unwrap();
+    client.subscribe("event").unwrap();
+
+    let start = Instant::now();
+    assert_eq!(client.recv_timeout(Duration::from_millis(100)).unwrap(), None);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // Still usable afterwards
+    client.publish("event", b"payload").unwrap();
+    assert_eq!(client.recv_timeout(Duration::from_secs(10)).unwrap(),
+               Some(event("event", b"payload")));
+}
+
+#[test]
+fn test_events_received_while_waiting_for_reply() {
+    let server = start_server();
+    let mut client = BlockingClient::connect(server.local_addr()).unwrap();
+    client.subscribe("#").unwrap();
+
+    // The events of these publishes arrive before their acks
+    client.publish("first", b"1").unwrap();
+    client.publish("second", b"2").unwrap();
+    let events: Vec<Event> = client.events().take(2).map(|e| e.unwrap()).collect();
+    assert_eq!(events, vec![event("first", b"1"), event("second", b"2")]);
+}
+
+#[test]
+fn test_refused_request() {
+    let server = start_server();
+    let mut client = BlockingClient::connect(server.local_addr()).unwrap();
+
+    let error = client.subscribe("sensors.#.temp").unwrap_err();
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::InvalidTopic));
+
+    client.subscribe("sensors.#").unwrap();
+}
+
+#[test]
+fn test_events_end_when_closed() {
+    let server = start_server();
+    let mut client = BlockingClient::connect(server.local_addr()).unwrap();
+    client.subscribe("event").unwrap();
+    client.publish("event", b"payload").unwrap();
+
+    server.shutdown().unwrap();
+    let events: Vec<Event> = client.events().map(|e| e.unwrap()).collect();
+    assert_eq!(events, vec![event("event", b"payload")]);
+}
+
+#[test]
+fn test_authentication() {
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .auth_token("t0ken")
+        .start()
+        .unwrap();
+
+    let mut client = BlockingClient::connect_with_credentials(server.local_addr(),
+                                                              &Credentials::token("t0ken")).unwrap();
+    client.publish("event", b"payload").unwrap();
+
+    let error = BlockingClient::connect_with_credentials(server.local_addr(),
+                                                         &Credentials::token("guess")).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::AuthenticationFailed));
+
+    // Requests without authenticating are refused, and the connection closed
+    let mut client = BlockingClient::connect(server.local_addr()).unwrap();
+    let error = client.publish("event", b"payload").unwrap_err();
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::NotAuthenticated));
+}
+
+#[test]
+fn test_subscribe_from_offset() {
+    let dir = env::temp_dir().join(format!("pubsub-blocking-log-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .topic_log_dir(dir.clone())
+        .start()
+        .unwrap();
+    let mut client = BlockingClient::connect(server.local_addr()).unwrap();
+    client.publish("event", b"logged").unwrap();
+
+    client.subscribe_from("event", StartOffset::Earliest).unwrap();
+    let received = client.recv().unwrap();
+    assert_eq!((received.offset, received.payload), (Some(0), b"logged".to_vec()));
+
+    let error = client.subscribe_from("event", StartOffset::At(2)).unwrap_err();
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::InvalidOffset));
+
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
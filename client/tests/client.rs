@@ -0,0 +1,386 @@
+use pubsub::message::ErrorCode;
+use pubsub_client::{ClientOptions, ConnectionState, Credentials, Event, PubsubClient,
+                     RequestError, ServerAddress, StartOffset, TlsOptions};
+use pubsub_server::{ServerBuilder, ServerHandle};
+
+use futures::{Stream, StreamExt, TryStreamExt};
+use tokio::time::timeout;
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
+
+fn start_server() -> ServerHandle {
+    ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap()
+}
+
+fn event(topic: &str, payload: &[u8]) -> Event {
+    Event {
+        topic: topic.to_string(),
+        payload: payload.to_vec(),
+        retained: false,
+        offset: None
+    }
+}
+
+async fn connect(server: &ServerHandle) -> PubsubClient {
+    run(PubsubClient::connect(&server.local_addr())).await.unwrap()
+}
+
+async fn connect_with(address: &SocketAddr, options: &ClientOptions) -> PubsubClient {
+    run(PubsubClient::connect_with(address, options)).await.unwrap()
+}
+
+fn reconnect_options() -> ClientOptions {
+    let mut options = ClientOptions::new();
+    options.initial_backoff(Duration::from_millis(20))
+        .max_backoff(Duration::from_millis(100));
+    options
+}
+
+// The next item of a stream, which must not end
+async fn next<S: Stream + Unpin>(stream: &mut S) -> S::Item {
+    run(stream.next()).await.expect("stream ended")
+}
+
+// Wait for a future, failing the test if it takes too long
+async fn run<F: Future>(future: F) -> F::Output {
+    timeout(Duration::from_secs(10), future).await.expect("test timed out")
+}
+
+#[tokio::test]
+async fn test_publish_and_subscribe() {
+    let server = start_server();
+    let client = connect(&server).await;
+
+    let mut temperatures = client.subscribe("sensors.*.temp");
+    let everything = client.subscribe("#");
+    // The publish is acked after the subscriptions, as requests are handled in order
+    run(client.publish("sensors.kitchen.temp", b"21".to_vec())).await.unwrap();
+    run(client.publish("lights.kitchen", b"on".to_vec())).await.unwrap();
+
+    let received = next(&mut temperatures).await.unwrap();
+    assert_eq!(received, event("sensors.kitchen.temp", b"21"));
+    let received: Vec<Event> = run(everything.take(2).try_collect()).await.unwrap();
+    assert_eq!(received, vec![event("sensors.kitchen.temp", b"21"),
+                              event("lights.kitchen", b"on")]);
+}
+
+#[tokio::test]
+async fn test_multiple_clients() {
+    let server = start_server();
+    let subscriber = connect(&server).await;
+    let publisher = connect(&server).await;
+
+    let events = subscriber.subscribe("event");
+    // Make sure the subscription is in place before publishing
+    run(subscriber.publish("other", vec![])).await.unwrap();
+    run(publisher.publish_retained("event", b"1".to_vec())).await.unwrap();
+    run(publisher.publish("event", b"2".to_vec())).await.unwrap();
+
+    let received: Vec<Event> = run(events.take(2).try_collect()).await.unwrap();
+    assert_eq!(received, vec![event("event", b"1"), event("event", b"2")]);
+
+    // Late subscribers get the retained event
+    let mut late = subscriber.subscribe("event");
+    let received = next(&mut late).await.unwrap();
+    assert_eq!(received, Event {
+        topic: "event".to_string(),
+        payload: b"1".to_vec(),
+        retained: true,
+        offset: None
+    });
+}
+
+#[tokio::test]
+async fn test_unsubscribe_ends_streams() {
+    let server = start_server();
+    let client = connect(&server).await;
+
+    let events = client.subscribe("event");
+    run(client.unsubscribe("event")).await.unwrap();
+    run(client.publish("event", b"payload".to_vec())).await.unwrap();
+    let received: Vec<Event> = run(events.try_collect()).await.unwrap();
+    assert_eq!(received, vec![]);
+}
+
+#[tokio::test]
+async fn test_refused_requests() {
+    let server = start_server();
+    let client = connect(&server).await;
+
+    let error = run(client.publish("sensors.*", vec![])).await.unwrap_err();
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::InvalidTopic));
+
+    let error = run(client.subscribe("sensors.#.temp").try_collect::<Vec<_>>()).await.unwrap_err();
+    assert!(error.get_ref().unwrap().is::<RequestError>());
+
+    // The connection is still usable
+    run(client.publish("sensors", vec![])).await.unwrap();
+
+    // A refused subscription leaves the others to the same topic alone.
+    // This server doesn't log topics, so can't replay them.
+    let mut events = client.subscribe("sensors");
+    let error = run(client.subscribe_from("sensors", StartOffset::Earliest)
+                    .try_collect::<Vec<_>>()).await.unwrap_err();
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::InvalidOffset));
+    run(client.publish("sensors", b"still subscribed".to_vec())).await.unwrap();
+    assert_eq!(next(&mut events).await.unwrap(), event("sensors", b"still subscribed"));
+}
+
+#[tokio::test]
+async fn test_connection_refused() {
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .max_connections(1)
+        .start()
+        .unwrap();
+    let _client = connect(&server).await;
+    let error = run(PubsubClient::connect(&server.local_addr())).await.err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(error.to_string().contains("ServerFull"));
+}
+
+#[tokio::test]
+async fn test_connection_lost() {
+    let server = start_server();
+    let mut options = ClientOptions::new();
+    options.reconnect(false);
+    let client = connect_with(&server.local_addr(), &options).await;
+    let events = client.subscribe("event");
+    run(client.publish("event", vec![])).await.unwrap();
+
+    server.shutdown().unwrap();
+    assert!(run(events.try_collect::<Vec<_>>()).await.is_err());
+    assert!(run(client.publish("event", vec![])).await.is_err());
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    let server = start_server();
+    let address = server.local_addr();
+    let client = connect_with(&address, &reconnect_options()).await;
+    let mut states = client.state_changes();
+    let mut events = client.subscribe("event");
+    run(client.publish("other", vec![])).await.unwrap();
+
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    server.shutdown().unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Disconnected);
+
+    // Publishes are buffered until reconnected, and the subscription is
+    // made again before they are sent
+    let publish = client.publish("event", b"buffered".to_vec());
+    let _server = ServerBuilder::new().bind(address).start().unwrap();
+    run(publish).await.unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    let event_received = next(&mut events).await.unwrap();
+    assert_eq!(event_received, event("event", b"buffered"));
+}
+
+#[tokio::test]
+async fn test_publish_buffer_full() {
+    let server = start_server();
+    let address = server.local_addr();
+    let mut options = reconnect_options();
+    options.publish_buffer(1);
+    let client = connect_with(&address, &options).await;
+    let mut states = client.state_changes();
+
+    server.shutdown().unwrap();
+    next(&mut states).await;
+    assert_eq!(next(&mut states).await, ConnectionState::Disconnected);
+
+    let _buffered = client.publish("event", vec![]);
+    let error = run(client.publish("event", vec![])).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+}
+
+#[tokio::test]
+async fn test_give_up_reconnecting() {
+    let server = start_server();
+    let address = server.local_addr();
+    let mut options = reconnect_options();
+    options.max_reconnect_attempts(2);
+    let client = connect_with(&address, &options).await;
+    let states = client.state_changes();
+    let events = client.subscribe("event");
+    run(client.publish("other", vec![])).await.unwrap();
+
+    server.shutdown().unwrap();
+    let states: Vec<ConnectionState> = run(states.collect()).await;
+    assert_eq!(states, vec![ConnectionState::Connected, ConnectionState::Disconnected,
+                            ConnectionState::Closed]);
+    assert!(run(events.try_collect::<Vec<_>>()).await.is_err());
+    assert!(run(client.publish("event", vec![])).await.is_err());
+}
+
+#[tokio::test]
+async fn test_authentication() {
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .auth_token("t0ken")
+        .start()
+        .unwrap();
+    let address = server.local_addr();
+
+    let mut options = reconnect_options();
+    options.credentials(Credentials::token("t0ken"));
+    let client = connect_with(&address, &options).await;
+    let mut states = client.state_changes();
+    run(client.publish("event", vec![])).await.unwrap();
+
+    // The credentials are sent again when reconnecting
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    server.shutdown().unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Disconnected);
+    let _server = ServerBuilder::new().bind(address).auth_token("t0ken").start().unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    run(client.publish("event", vec![])).await.unwrap();
+
+    options.credentials(Credentials::token("guess"));
+    let error = run(PubsubClient::connect_with(&address, &options)).await.err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::AuthenticationFailed));
+}
+
+#[tokio::test]
+async fn test_credentials_refused_on_reconnect() {
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .auth_token("t0ken")
+        .start()
+        .unwrap();
+    let address = server.local_addr();
+    let mut options = reconnect_options();
+    options.credentials(Credentials::token("t0ken"));
+    let client = connect_with(&address, &options).await;
+    let states = client.state_changes();
+    run(client.publish("event", vec![])).await.unwrap();
+
+    // Reconnecting gives up for good once the credentials are refused
+    server.shutdown().unwrap();
+    let _server = ServerBuilder::new().bind(address).auth_token("changed").start().unwrap();
+    let states: Vec<ConnectionState> = run(states.collect()).await;
+    assert_eq!(states, vec![ConnectionState::Connected, ConnectionState::Disconnected,
+                            ConnectionState::Closed]);
+    let error = run(client.publish("event", vec![])).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+}
+
+#[tokio::test]
+async fn test_subscription_revoked() {
+    let acl = env::temp_dir().join(format!("pubsub-client-acl-{}", process::id()));
+    fs::write(&acl, "default = \"allow\"").unwrap();
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .acl_file(acl.clone())
+        .start()
+        .unwrap();
+    let client = connect(&server).await;
+    let mut revoked = client.subscribe("sensors.*");
+    let mut events = client.subscribe("lights");
+    run(client.publish("lights", vec![])).await.unwrap();
+    next(&mut events).await.unwrap();
+
+    // The stream fails, then ends
+    fs::write(&acl, "[[rule]]\nuser = \"*\"\ntopic = \"lights\"\naction = \"allow\"\n").unwrap();
+    server.acl_handle().reload().unwrap();
+    let error = next(&mut revoked).await.unwrap_err();
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::AccessDenied));
+    assert!(run(revoked.next()).await.is_none());
+
+    // The other subscription is still there
+    run(client.publish("lights", b"on".to_vec())).await.unwrap();
+    assert_eq!(next(&mut events).await.unwrap(), event("lights", b"on"));
+    fs::remove_file(acl).unwrap();
+}
+
+#[tokio::test]
+async fn test_unix_socket() {
+    let path = env::temp_dir().join(format!("pubsub-client-{}.sock", process::id()));
+    let server = ServerBuilder::new().unix_socket(path.clone()).start().unwrap();
+    let client = run(PubsubClient::connect_unix_with(&path, &reconnect_options())).await.unwrap();
+    let mut states = client.state_changes();
+    let mut events = client.subscribe("event");
+    run(client.publish("event", b"local".to_vec())).await.unwrap();
+    assert_eq!(next(&mut events).await.unwrap(), event("event", b"local"));
+
+    // Reconnecting uses the socket too
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    server.shutdown().unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Disconnected);
+    let _server = ServerBuilder::new().unix_socket(path.clone()).start().unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    run(client.publish("event", b"again".to_vec())).await.unwrap();
+    assert_eq!(next(&mut events).await.unwrap(), event("event", b"again"));
+
+    // TLS is only used over TCP
+    let mut options = ClientOptions::new();
+    options.tls(TlsOptions::new("localhost", "/nonexistent/ca.pem"));
+    let error = run(PubsubClient::connect_to(ServerAddress::Unix(path), &options)).await
+        .err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_subscribe_from_offset() {
+    let dir = env::temp_dir().join(format!("pubsub-client-log-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .topic_log_dir(dir.clone())
+        .start()
+        .unwrap();
+    let address = server.local_addr();
+    let publisher = connect(&server).await;
+    run(publisher.publish("event", b"0".to_vec())).await.unwrap();
+    run(publisher.publish("event", b"1".to_vec())).await.unwrap();
+
+    let client = connect_with(&address, &reconnect_options()).await;
+    let mut states = client.state_changes();
+    let mut events = client.subscribe_from("event", StartOffset::Earliest);
+    for i in 0..2u8 {
+        let received = next(&mut events).await.unwrap();
+        assert_eq!((received.offset, received.payload), (Some(i as u64), vec![b'0' + i]));
+    }
+
+    // Events published while disconnected are caught up with, once
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    server.shutdown().unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Disconnected);
+    let server = ServerBuilder::new().bind(address).topic_log_dir(dir.clone()).start().unwrap();
+    let publisher = connect(&server).await;
+    run(publisher.publish("event", b"2".to_vec())).await.unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    run(publisher.publish("event", b"3".to_vec())).await.unwrap();
+    for i in 2..4u8 {
+        let received = next(&mut events).await.unwrap();
+        assert_eq!((received.offset, received.payload), (Some(i as u64), vec![b'0' + i]));
+    }
+
+    // Only what is published from then on
+    let mut latest = client.subscribe_from("event", StartOffset::Latest);
+    run(publisher.publish("event", b"4".to_vec())).await.unwrap();
+    assert_eq!(next(&mut latest).await.unwrap().offset, Some(4));
+    assert_eq!(next(&mut events).await.unwrap().offset, Some(4));
+
+    // Past the end of the log
+    let error = next(&mut client.subscribe_from("event", StartOffset::At(10))).await.unwrap_err();
+    let error = error.get_ref().unwrap().downcast_ref::<RequestError>().unwrap();
+    assert_eq!(error.code, Some(ErrorCode::InvalidOffset));
+
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,167 @@
+use pubsub_client::{ClientOptions, ConnectionState, Event, PubsubClient, TlsOptions};
+use pubsub_server::{ServerBuilder, ServerHandle};
+
+use futures::{Stream, StreamExt};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use tokio::time::timeout;
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+// A certificate authority made up for a test, with its certificate
+// and the ones it issues written to PEM files
+struct Authority {
+    certificate: rcgen::Certificate,
+    key: KeyPair,
+    test: &'static str,
+    files: Vec<PathBuf>
+}
+
+impl Authority {
+    fn new(test: &'static str) -> Authority {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        Authority {
+            certificate: params.self_signed(&key).unwrap(),
+            key,
+            test,
+            files: Vec::new()
+        }
+    }
+
+    fn write_file(&mut self, name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pubsub-client-tls-{}-{}-{}",
+                                                process::id(), self.test, name));
+        fs::write(&path, contents).unwrap();
+        self.files.push(path.clone());
+        path
+    }
+
+    fn ca_file(&mut self, name: &str) -> PathBuf {
+        let pem = self.certificate.pem();
+        self.write_file(name, &pem)
+    }
+
+    // The certificate and key files of a new certificate for the given name
+    fn issue(&mut self, name: &str) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec![name.to_string()]).unwrap()
+            .signed_by(&key, &self.certificate, &self.key)
+            .unwrap();
+        (self.write_file(&format!("{}-cert.pem", name), &certificate.pem()),
+         self.write_file(&format!("{}-key.pem", name), &key.serialize_pem()))
+    }
+}
+
+impl Drop for Authority {
+    fn drop(&mut self) {
+        for path in &self.files {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn start_server(address: SocketAddr, certificate: &(PathBuf, PathBuf),
+                client_ca: Option<&PathBuf>) -> ServerHandle {
+    let mut builder = ServerBuilder::new();
+    builder.bind(address)
+        .tls(certificate.0.clone(), certificate.1.clone());
+    if let Some(client_ca) = client_ca {
+        builder.tls_client_ca(client_ca.clone());
+    }
+    builder.start().unwrap()
+}
+
+fn tls_options(ca_file: &Path) -> ClientOptions {
+    let mut options = ClientOptions::new();
+    options.tls(TlsOptions::new("localhost", ca_file))
+        .initial_backoff(Duration::from_millis(20))
+        .max_backoff(Duration::from_millis(100));
+    options
+}
+
+// The next item of a stream, which must not end
+async fn next<S: Stream + Unpin>(stream: &mut S) -> S::Item {
+    run(stream.next()).await.expect("stream ended")
+}
+
+// Wait for a future, failing the test if it takes too long
+async fn run<F: Future>(future: F) -> F::Output {
+    timeout(Duration::from_secs(10), future).await.expect("test timed out")
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let mut authority = Authority::new("tls");
+    let certificate = authority.issue("localhost");
+    let ca_file = authority.ca_file("ca.pem");
+    let server = start_server("127.0.0.1:0".parse().unwrap(), &certificate, None);
+    let address = server.local_addr();
+
+    let options = tls_options(&ca_file);
+    let client = run(PubsubClient::connect_with(&address, &options)).await.unwrap();
+    let mut states = client.state_changes();
+    let mut events = client.subscribe("event");
+    run(client.publish("event", b"secret".to_vec())).await.unwrap();
+    let event = next(&mut events).await.unwrap();
+    assert_eq!(event, Event {
+        topic: "event".to_string(),
+        payload: b"secret".to_vec(),
+        retained: false,
+        offset: None
+    });
+
+    // Reconnecting uses TLS too
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    server.shutdown().unwrap();
+    assert_eq!(next(&mut states).await, ConnectionState::Disconnected);
+    let _server = start_server(address, &certificate, None);
+    assert_eq!(next(&mut states).await, ConnectionState::Connected);
+    run(client.publish("event", b"again".to_vec())).await.unwrap();
+    assert_eq!(next(&mut events).await.unwrap().payload, b"again".to_vec());
+
+    // Connecting without TLS, or not trusting the server's certificate, fails
+    assert!(run(PubsubClient::connect(&address)).await.is_err());
+    let mut other_authority = Authority::new("tls-other");
+    let other_ca_file = other_authority.ca_file("ca.pem");
+    assert!(run(PubsubClient::connect_with(&address, &tls_options(&other_ca_file))).await.is_err());
+    // The certificate is only valid for localhost
+    let mut options = tls_options(&ca_file);
+    options.tls(TlsOptions::new("example.com", ca_file.clone()));
+    assert!(run(PubsubClient::connect_with(&address, &options)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_client_certificate() {
+    let mut authority = Authority::new("client-certificate");
+    let certificate = authority.issue("localhost");
+    let ca_file = authority.ca_file("ca.pem");
+    let mut client_authority = Authority::new("client-certificate-clients");
+    let client_ca_file = client_authority.ca_file("ca.pem");
+    let client_certificate = client_authority.issue("client");
+    let server = start_server("127.0.0.1:0".parse().unwrap(), &certificate,
+                              Some(&client_ca_file));
+    let address = server.local_addr();
+
+    let mut options = tls_options(&ca_file);
+    let mut tls = TlsOptions::new("localhost", ca_file.clone());
+    tls.client_certificate(client_certificate.0.clone(), client_certificate.1.clone());
+    options.tls(tls);
+    let client = run(PubsubClient::connect_with(&address, &options)).await.unwrap();
+    run(client.publish("event", vec![])).await.unwrap();
+
+    // The server refuses clients without a certificate, or with one
+    // it doesn't trust
+    assert!(run(PubsubClient::connect_with(&address, &tls_options(&ca_file))).await.is_err());
+    let untrusted = authority.issue("client");
+    let mut tls = TlsOptions::new("localhost", ca_file.clone());
+    tls.client_certificate(untrusted.0, untrusted.1);
+    options.tls(tls);
+    assert!(run(PubsubClient::connect_with(&address, &options)).await.is_err());
+}
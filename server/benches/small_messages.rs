@@ -0,0 +1,99 @@
+// Throughput of many small events to a single subscriber.
+// Run with: cargo bench --bench small_messages [-- EVENTS]
+extern crate pubsub;
+extern crate pubsub_server;
+
+use pubsub::decoder::Decoder;
+use pubsub::handshake::{Handshake, HANDSHAKE_LEN};
+use pubsub::message::{MessageBuilder, MessageType};
+use pubsub_server::{ServerBuilder, ServerHandle};
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Instant;
+
+const DEFAULT_EVENTS: usize = 200000;
+const PAYLOAD_LEN: usize = 16;
+
+fn connect(server: &ServerHandle) -> TcpStream {
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_nodelay(true).unwrap();
+    stream.write_all(&Handshake::new().into_bytes()).unwrap();
+    let mut welcome = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut welcome).unwrap();
+    stream
+}
+
+fn encode(message_type: MessageType, event_name: &str, payload: Option<&[u8]>) -> Vec<u8> {
+    let mut builder = MessageBuilder::new();
+    builder.message_type(message_type)
+        .event_name(event_name.to_string());
+    if let Some(payload) = payload {
+        builder.payload(payload.to_vec());
+    }
+    builder.build().unwrap().into_bytes()
+}
+
+// Read messages until the given number of the given type have been received
+fn receive(stream: &mut TcpStream, message_type: MessageType, count: usize) {
+    let mut decoder = Decoder::new();
+    let mut received = 0;
+    while received < count {
+        match decoder.decode().unwrap() {
+            Some(message) => {
+                if message.header.message_type == message_type {
+                    received += 1;
+                }
+            },
+            None => {
+                if decoder.read_from(stream).unwrap() == 0 {
+                    panic!("connection closed after {} messages", received);
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let events = env::args().skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .map_or(DEFAULT_EVENTS, |arg| arg.parse().expect("invalid number of events"));
+
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap();
+
+    let mut subscriber = connect(&server);
+    subscriber.write_all(&encode(MessageType::Subscribe, "bench", None)).unwrap();
+    receive(&mut subscriber, MessageType::Ack, 1);
+
+    let mut publisher = connect(&server);
+    let publish = encode(MessageType::Publish, "bench", Some(&[0x2a; PAYLOAD_LEN]));
+    let mut acks = publisher.try_clone().unwrap();
+    let acks = thread::spawn(move || receive(&mut acks, MessageType::Ack, events));
+
+    let start = Instant::now();
+    let sender = thread::spawn(move || -> io::Result<()> {
+        // Send the publishes in chunks rather than one write each,
+        // so that the publisher isn't what's being measured
+        let chunk: Vec<u8> = publish.iter().cloned().cycle().take(publish.len() * 1000).collect();
+        let mut remaining = events;
+        while remaining > 0 {
+            let count = if remaining < 1000 { remaining } else { 1000 };
+            try!(publisher.write_all(&chunk[..publish.len() * count]));
+            remaining -= count;
+        }
+        Ok(())
+    });
+    receive(&mut subscriber, MessageType::Event, events);
+    let elapsed = start.elapsed();
+    sender.join().unwrap().unwrap();
+    acks.join().unwrap();
+
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{} events of {} bytes in {:.3} s: {:.0} events/s",
+             events, PAYLOAD_LEN, seconds, events as f64 / seconds);
+}
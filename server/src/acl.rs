@@ -0,0 +1,238 @@
+use pubsub::topic;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use toml;
+
+use config::ConfigError;
+
+// What a client asks to do with a topic
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Access {
+    Subscribe,
+    Publish
+}
+
+struct Rule {
+    // None for rules applying to every client
+    user: Option<String>,
+    pattern: String,
+    // None for rules applying to both subscribing and publishing
+    access: Option<Access>,
+    allow: bool
+}
+
+impl Rule {
+    fn applies(&self, user: Option<&str>, access: Access, topic: &str) -> bool {
+        if let Some(ref rule_user) = self.user {
+            if user != Some(&rule_user[..]) {
+                return false;
+            }
+        }
+        if self.access.is_some_and(|a| a != access) {
+            return false;
+        }
+        match access {
+            Access::Publish => topic::matches(&self.pattern, topic),
+            // A subscription is allowed when the rule covers everything it
+            // could receive, and denied when any of that is denied
+            Access::Subscribe if self.allow => topic::covers(&self.pattern, topic),
+            Access::Subscribe => topic::overlaps(&self.pattern, topic)
+        }
+    }
+}
+
+// Which clients may subscribe and publish to which topics. The rules are
+// checked in order, and the first one applying to a request decides.
+// Requests no rule applies to get the default.
+pub struct Acl {
+    rules: Vec<Rule>,
+    default_allow: bool
+}
+
+// The ACL file format, e.g.
+//   default = "deny"
+//
+//   [[rule]]
+//   user = "alice"
+//   topic = "sensors.#"
+//   access = "publish"
+//   action = "allow"
+//
+// user is a user name from the credentials file, or "*" for every client,
+// including those authenticated with the shared token. access is one of
+// subscribe, publish and all (the default). action is allow or deny, as is
+// default, which is deny if not given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    default: Option<String>,
+    rule: Option<Vec<RuleSection>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSection {
+    user: String,
+    topic: String,
+    access: Option<String>,
+    action: String
+}
+
+impl Acl {
+    // Lets every client do anything, for servers without an ACL file
+    pub fn allow_all() -> Acl {
+        Acl {
+            rules: Vec::new(),
+            default_allow: true
+        }
+    }
+
+    pub fn load(path: Option<&Path>) -> Result<Acl, ConfigError> {
+        let path = match path {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => return Ok(Acl::allow_all())
+        };
+        let mut contents = String::new();
+        try!(File::open(&path)
+             .and_then(|mut f| f.read_to_string(&mut contents))
+             .map_err(|e| ConfigError::AclFile(path.clone(), e.to_string())));
+        Acl::parse(&contents)
+            .map_err(|reason| ConfigError::AclFile(path, reason))
+    }
+
+    fn parse(contents: &str) -> Result<Acl, String> {
+        let file: AclFile = try!(toml::from_str(contents).map_err(|e| e.to_string()));
+        let default_allow = match file.default {
+            Some(action) => try!(parse_action(&action)),
+            None => false
+        };
+        let mut rules = Vec::new();
+        for (number, rule) in file.rule.unwrap_or_default().into_iter().enumerate() {
+            let invalid = |what: &str, value: &str| {
+                format!("rule {}: invalid {}: {}", number + 1, what, value)
+            };
+            if rule.user.is_empty() {
+                return Err(invalid("user", "none given"));
+            }
+            if !topic::is_valid_pattern(&rule.topic) {
+                return Err(invalid("topic", &rule.topic));
+            }
+            let access = match rule.access.as_ref().map(|a| &a[..]) {
+                Some("subscribe") => Some(Access::Subscribe),
+                Some("publish") => Some(Access::Publish),
+                Some("all") | None => None,
+                Some(access) => return Err(invalid("access", access))
+            };
+            let allow = try!(parse_action(&rule.action)
+                             .map_err(|_| invalid("action", &rule.action)));
+            rules.push(Rule {
+                user: if rule.user == "*" { None } else { Some(rule.user) },
+                pattern: rule.topic,
+                access,
+                allow
+            });
+        }
+        Ok(Acl {
+            rules,
+            default_allow
+        })
+    }
+
+    // Whether a client, authenticated as the given user (None for the shared
+    // token, or when not authenticated), may subscribe or publish to a topic
+    pub fn allows(&self, user: Option<&str>, access: Access, topic: &str) -> bool {
+        self.rules.iter()
+            .find(|rule| rule.applies(user, access, topic))
+            .map_or(self.default_allow, |rule| rule.allow)
+    }
+}
+
+fn parse_action(action: &str) -> Result<bool, String> {
+    match action {
+        "allow" => Ok(true),
+        "deny" => Ok(false),
+        _ => Err(format!("invalid action: {}", action))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Acl, Access};
+
+    const RULES: &str = r#"
+        [[rule]]
+        user = "alice"
+        topic = "sensors.#"
+        access = "publish"
+        action = "allow"
+
+        [[rule]]
+        user = "*"
+        topic = "sensors.secret.#"
+        action = "deny"
+
+        [[rule]]
+        user = "*"
+        topic = "sensors.#"
+        access = "subscribe"
+        action = "allow"
+    "#;
+
+    #[test]
+    fn test_allow_all() {
+        let acl = Acl::allow_all();
+        assert!(acl.allows(None, Access::Publish, "anything"));
+        assert!(acl.allows(Some("alice"), Access::Subscribe, "#"));
+    }
+
+    #[test]
+    fn test_first_rule_decides() {
+        let acl = Acl::parse(RULES).unwrap();
+        assert!(acl.allows(Some("alice"), Access::Publish, "sensors.kitchen.temp"));
+        assert!(acl.allows(Some("alice"), Access::Publish, "sensors.secret.key"));
+        assert!(!acl.allows(Some("bob"), Access::Publish, "sensors.kitchen.temp"));
+        assert!(acl.allows(Some("bob"), Access::Subscribe, "sensors.kitchen.temp"));
+        assert!(acl.allows(None, Access::Subscribe, "sensors.hall.temp"));
+        assert!(!acl.allows(Some("bob"), Access::Subscribe, "sensors.secret.key"));
+        // Denied by default
+        assert!(!acl.allows(Some("alice"), Access::Subscribe, "lights.kitchen"));
+        assert!(!acl.allows(Some("alice"), Access::Publish, "lights.kitchen"));
+    }
+
+    #[test]
+    fn test_subscriptions_must_be_covered() {
+        let acl = Acl::parse(RULES).unwrap();
+        // Could receive events from sensors.secret
+        assert!(!acl.allows(Some("bob"), Access::Subscribe, "sensors.#"));
+        assert!(!acl.allows(Some("bob"), Access::Subscribe, "sensors.*.key"));
+        // Could receive events outside of sensors
+        assert!(!acl.allows(Some("bob"), Access::Subscribe, "#"));
+        assert!(!acl.allows(Some("bob"), Access::Subscribe, "*.kitchen.temp"));
+    }
+
+    #[test]
+    fn test_default() {
+        let acl = Acl::parse("default = \"allow\"").unwrap();
+        assert!(acl.allows(Some("alice"), Access::Publish, "anything"));
+        let acl = Acl::parse("").unwrap();
+        assert!(!acl.allows(Some("alice"), Access::Publish, "anything"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(Acl::parse("default = \"maybe\"").err(),
+                   Some("invalid action: maybe".to_string()));
+        let rule = "[[rule]]\nuser = \"*\"\ntopic = \"sensors.#.temp\"\naction = \"allow\"";
+        assert_eq!(Acl::parse(rule).err(),
+                   Some("rule 1: invalid topic: sensors.#.temp".to_string()));
+        let rule = "[[rule]]\nuser = \"*\"\ntopic = \"#\"\naccess = \"read\"\naction = \"allow\"";
+        assert_eq!(Acl::parse(rule).err(),
+                   Some("rule 1: invalid access: read".to_string()));
+        assert!(Acl::parse("[[rule]]\nuser = \"*\"\ntopic = \"#\"").is_err());
+        assert!(Acl::parse("[[rule]]\nuser = \"*\"\npattern = \"#\"\naction = \"allow\"").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use config::{Config, ConfigError};
+
+// A password as stored in the credentials file. Only a salted hash of it
+// is kept, so that the file doesn't give the passwords away.
+struct PasswordHash {
+    salt: String,
+    hash: [u8; 32]
+}
+
+impl PasswordHash {
+    fn matches(&self, password: &[u8]) -> bool {
+        constant_time_eq(&salted_hash(&self.salt, password), &self.hash)
+    }
+}
+
+// Checks the credentials clients send when connecting. With neither
+// a token nor a credentials file configured, clients don't need to
+// authenticate at all.
+pub struct Authenticator {
+    required: bool,
+    token: Option<String>,
+    // Password hashes by user name
+    users: HashMap<String, PasswordHash>
+}
+
+impl Authenticator {
+    pub fn load(config: &Config) -> Result<Authenticator, ConfigError> {
+        let users = match config.credentials_file {
+            Some(ref path) => {
+                let path = path.to_string_lossy().into_owned();
+                let mut contents = String::new();
+                try!(File::open(&path)
+                     .and_then(|mut f| f.read_to_string(&mut contents))
+                     .map_err(|e| ConfigError::CredentialsFile(path.clone(), e.to_string())));
+                try!(parse_credentials(&contents)
+                     .map_err(|reason| ConfigError::CredentialsFile(path, reason)))
+            },
+            None => HashMap::new()
+        };
+        Ok(Authenticator {
+            required: config.requires_authentication(),
+            token: config.auth_token.clone(),
+            users
+        })
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+
+    // Whether the credentials of an Authenticate message are valid.
+    // An empty user name goes with the shared token, any other
+    // with the password of that user.
+    pub fn authenticate(&self, user: &str, secret: &[u8]) -> bool {
+        if user.is_empty() {
+            return match self.token {
+                // Comparing hashes keeps the comparison time independent
+                // of the token's length
+                Some(ref token) => {
+                    constant_time_eq(&salted_hash("", secret), &salted_hash("", token.as_bytes()))
+                },
+                None => false
+            };
+        }
+        self.users.get(user).is_some_and(|hash| hash.matches(secret))
+    }
+}
+
+// The credentials file has a line per user, of the form
+//   user:salt:hash
+// where hash is the hex encoded SHA-256 of the salt followed by the
+// password, e.g. as printed by: printf '%s' "$salt$password" | sha256sum
+// Empty lines and lines starting with # are ignored.
+fn parse_credentials(contents: &str) -> Result<HashMap<String, PasswordHash>, String> {
+    let mut users = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(':').collect();
+        let hash = if fields.len() == 3 { parse_hash(fields[2]) } else { None };
+        let hash = match hash {
+            Some(hash) if !fields[0].is_empty() => hash,
+            _ => return Err(format!("line {}: expected user:salt:hash", number + 1))
+        };
+        if users.contains_key(fields[0]) {
+            return Err(format!("line {}: user {} given twice", number + 1, fields[0]));
+        }
+        users.insert(fields[0].to_string(), PasswordHash {
+            salt: fields[1].to_string(),
+            hash
+        });
+    }
+    Ok(users)
+}
+
+fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(byte) => byte,
+            Err(_) => return None
+        };
+    }
+    Some(hash)
+}
+
+fn salted_hash(salt: &str, secret: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(secret);
+    hasher.finalize().into()
+}
+
+// Compare without returning early, so that the time taken doesn't tell
+// how much of a guess was right
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{parse_credentials, Authenticator};
+    use config::Config;
+
+    // printf '%s' 's4ltsecret' | sha256sum
+    const ALICE: &str =
+        "alice:s4lt:56fb59a0918a8e898cc46532428936acd1717e608c197eef668b7c6ef2f3c655";
+
+    fn authenticator(token: Option<&str>, credentials: &str) -> Authenticator {
+        Authenticator {
+            required: true,
+            token: token.map(|t| t.to_string()),
+            users: parse_credentials(credentials).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_not_required() {
+        let auth = Authenticator::load(&Config::default()).unwrap();
+        assert!(!auth.is_required());
+        assert!(!auth.authenticate("", b""));
+    }
+
+    #[test]
+    fn test_token() {
+        let auth = authenticator(Some("t0ken"), "");
+        assert!(auth.is_required());
+        assert!(auth.authenticate("", b"t0ken"));
+        assert!(!auth.authenticate("", b"t0ke"));
+        assert!(!auth.authenticate("", b"t0ken "));
+        // The token doesn't go with user names
+        assert!(!auth.authenticate("alice", b"t0ken"));
+    }
+
+    #[test]
+    fn test_passwords() {
+        let auth = authenticator(None, &format!("# Users\n\n{}\n", ALICE));
+        assert!(auth.is_required());
+        assert!(auth.authenticate("alice", b"secret"));
+        assert!(!auth.authenticate("alice", b"Secret"));
+        assert!(!auth.authenticate("bob", b"secret"));
+        assert!(!auth.authenticate("", b"secret"));
+    }
+
+    #[test]
+    fn test_invalid_credentials() {
+        let invalid_hash = "alice:s4lt:56fb59a0918a8e898cc46532428936ac";
+        assert_eq!(parse_credentials(invalid_hash).err(),
+                   Some("line 1: expected user:salt:hash".to_string()));
+        assert!(parse_credentials("alice:secret").is_err());
+        assert!(parse_credentials(&ALICE.replace("56", "zz")).is_err());
+        assert_eq!(parse_credentials(&format!("{}\n{}", ALICE, ALICE)).err(),
+                   Some("line 2: user alice given twice".to_string()));
+        assert!(parse_credentials("\n# Nobody\n").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,388 @@
+use mio::net::TcpListener;
+use mio::Poll;
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use server::{AclHandle, PubsubServer, Shared, ShutdownHandle, Stats, Worker};
+use config::{Config, SlowConsumerPolicy, TopicLogSync};
+use hooks::{Hooks, NoHooks};
+use auth::Authenticator;
+use acl::Acl;
+use listener::Listener;
+use tls;
+use topic_log::TopicLogs;
+
+
+// Configures and starts a server running on its own thread
+pub struct ServerBuilder {
+    config: Config,
+    // Addresses given with bind(), replacing the ones in the config.
+    // Set, if only to none, once bind(), unix_socket() or websocket()
+    // has been called.
+    addresses: Option<Vec<SocketAddr>>,
+    hooks: Box<dyn Hooks>
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::from_config(Config::default())
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn from_config(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            addresses: None,
+            hooks: Box::new(NoHooks)
+        }
+    }
+
+    // Listen on the given address. May be called several times to listen
+    // on more than one address. Use port 0 to have one picked by the OS.
+    pub fn bind(&mut self, address: SocketAddr) -> &mut ServerBuilder {
+        self.addresses.get_or_insert_with(Vec::new).push(address);
+        self
+    }
+
+    // Listen on a Unix socket at the given path, as well as on the addresses
+    // given with bind(), if any
+    pub fn unix_socket<P: Into<PathBuf>>(&mut self, path: P) -> &mut ServerBuilder {
+        self.addresses.get_or_insert_with(Vec::new);
+        self.config.unix_socket = Some(path.into());
+        self
+    }
+
+    // The permissions of the Unix socket file, e.g. 0o660
+    pub fn unix_socket_mode(&mut self, mode: u32) -> &mut ServerBuilder {
+        self.config.unix_socket_mode = Some(mode);
+        self
+    }
+
+    // Accept WebSocket connections on the given address, as well as
+    // connections on the addresses given with bind(), if any. May be called
+    // several times, and with port 0 like bind().
+    pub fn websocket(&mut self, address: SocketAddr) -> &mut ServerBuilder {
+        self.addresses.get_or_insert_with(Vec::new);
+        self.config.websocket_addresses.push(address);
+        self
+    }
+
+    pub fn max_connections(&mut self, max_connections: usize) -> &mut ServerBuilder {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    pub fn max_frame_size(&mut self, max_frame_size: usize) -> &mut ServerBuilder {
+        self.config.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn max_queued_events(&mut self, max_queued_events: usize) -> &mut ServerBuilder {
+        self.config.max_queued_events = Some(max_queued_events);
+        self
+    }
+
+    pub fn max_queued_bytes(&mut self, max_queued_bytes: usize) -> &mut ServerBuilder {
+        self.config.max_queued_bytes = Some(max_queued_bytes);
+        self
+    }
+
+    pub fn slow_consumer_policy(&mut self, policy: SlowConsumerPolicy) -> &mut ServerBuilder {
+        self.config.slow_consumer_policy = policy;
+        self
+    }
+
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut ServerBuilder {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    // Require clients to authenticate with the given shared secret
+    pub fn auth_token(&mut self, token: &str) -> &mut ServerBuilder {
+        self.config.auth_token = Some(token.to_string());
+        self
+    }
+
+    // Require clients to authenticate with a user name and password
+    // from the given file, which has a line of user:salt:hash per user,
+    // hash being the hex encoded SHA-256 of the salt followed by the password
+    pub fn credentials_file<P: Into<PathBuf>>(&mut self, path: P) -> &mut ServerBuilder {
+        self.config.credentials_file = Some(path.into());
+        self
+    }
+
+    // Only let clients subscribe and publish as allowed by the rules in the
+    // given file. It can be read again while running through an AclHandle.
+    pub fn acl_file<P: Into<PathBuf>>(&mut self, path: P) -> &mut ServerBuilder {
+        self.config.acl_file = Some(path.into());
+        self
+    }
+
+    // Serve clients over TLS, with the certificate chain and private key
+    // from the given PEM files
+    pub fn tls<P: Into<PathBuf>, Q: Into<PathBuf>>(&mut self, certificate: P, key: Q)
+                                                   -> &mut ServerBuilder {
+        self.config.tls_certificate = Some(certificate.into());
+        self.config.tls_key = Some(key.into());
+        self
+    }
+
+    // Only let clients connect with a certificate signed by one of the
+    // CA certificates in the given PEM file. Requires tls to be set.
+    pub fn tls_client_ca<P: Into<PathBuf>>(&mut self, path: P) -> &mut ServerBuilder {
+        self.config.tls_client_ca = Some(path.into());
+        self
+    }
+
+    // Append published events to a log per topic in the given directory,
+    // so that subscribers can replay them from an offset
+    pub fn topic_log_dir<P: Into<PathBuf>>(&mut self, path: P) -> &mut ServerBuilder {
+        self.config.topic_log_dir = Some(path.into());
+        self
+    }
+
+    // The size a topic log file grows to before a new one is started
+    pub fn topic_log_segment_size(&mut self, bytes: u64) -> &mut ServerBuilder {
+        self.config.topic_log_segment_size = bytes;
+        self
+    }
+
+    // Keep no more than this many log files per topic, deleting the oldest
+    pub fn topic_log_max_segments(&mut self, segments: usize) -> &mut ServerBuilder {
+        self.config.topic_log_max_segments = Some(segments);
+        self
+    }
+
+    // Keep no more than this many topic logs open at once, closing
+    // the ones used least recently
+    pub fn topic_log_max_open(&mut self, logs: usize) -> &mut ServerBuilder {
+        self.config.topic_log_max_open = logs;
+        self
+    }
+
+    // When logged events are synced to disk, Always by default
+    pub fn topic_log_sync(&mut self, sync: TopicLogSync) -> &mut ServerBuilder {
+        self.config.topic_log_sync = sync;
+        self
+    }
+
+    // The time between syncs when the topic logs are synced periodically
+    pub fn topic_log_sync_interval(&mut self, interval: Duration) -> &mut ServerBuilder {
+        self.config.topic_log_sync_interval = interval;
+        self
+    }
+
+    // Serve clients on this many threads, each running its own event loop
+    pub fn workers(&mut self, workers: usize) -> &mut ServerBuilder {
+        self.config.workers = workers;
+        self
+    }
+
+    // With more than one worker, the hooks are called from all of their threads
+    pub fn hooks<H: Hooks + 'static>(&mut self, hooks: H) -> &mut ServerBuilder {
+        self.hooks = Box::new(hooks);
+        self
+    }
+
+    // Bind the listeners and start serving clients on new threads.
+    // The builder is left with default hooks.
+    pub fn start(&mut self) -> io::Result<ServerHandle> {
+        let mut config = self.config.clone();
+        if let Some(ref addresses) = self.addresses {
+            config.bind_addresses = addresses.clone();
+        }
+        try!(config.validate()
+             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+        let auth = try!(Authenticator::load(&config)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+        let acl = try!(Acl::load(config.acl_file.as_deref())
+                       .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+        let tls = try!(tls::load_config(&config)
+                       .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+        let topic_logs = try!(TopicLogs::open(&config)
+                              .map_err(|e| io::Error::new(e.kind(),
+                                                          format!("couldn't open topic log \
+                                                                   directory: {}", e))));
+        if let Some(ref topic_logs) = topic_logs {
+            info!("Logging topics to {}", topic_logs.dir().display());
+        }
+
+        let mut listeners = Vec::new();
+        let mut local_addresses = Vec::new();
+        for address in &config.bind_addresses {
+            let listener = try!(TcpListener::bind(*address)
+                                .map_err(|e| io::Error::new(e.kind(),
+                                                            format!("couldn't bind to {}: {}",
+                                                                    address, e))));
+            let local_address = try!(listener.local_addr());
+            if tls.is_some() {
+                info!("Listening on {} with TLS", local_address);
+            }
+            else {
+                info!("Listening on {}", local_address);
+            }
+            local_addresses.push(local_address);
+            listeners.push(Listener::Tcp(listener));
+        }
+        let mut websocket_addresses = Vec::new();
+        for address in &config.websocket_addresses {
+            let listener = try!(TcpListener::bind(*address)
+                                .map_err(|e| io::Error::new(e.kind(),
+                                                            format!("couldn't bind to {}: {}",
+                                                                    address, e))));
+            let local_address = try!(listener.local_addr());
+            if tls.is_some() {
+                info!("Listening on {} for WebSocket connections with TLS", local_address);
+            }
+            else {
+                info!("Listening on {} for WebSocket connections", local_address);
+            }
+            websocket_addresses.push(local_address);
+            listeners.push(Listener::WebSocket(listener));
+        }
+        if let Some(ref path) = config.unix_socket {
+            let listener = try!(Listener::bind_unix(path, config.unix_socket_mode)
+                                .map_err(|e| io::Error::new(e.kind(),
+                                                            format!("couldn't bind to {}: {}",
+                                                                    path.display(), e))));
+            info!("Listening on {}", path.display());
+            listeners.push(listener);
+        }
+
+        let mut polls = Vec::new();
+        let mut workers = Vec::new();
+        let mut inboxes = Vec::new();
+        for _ in 0..config.workers {
+            let poll = try!(Poll::new());
+            let (sender, inbox) = mpsc::channel();
+            workers.push(try!(Worker::new(&poll, sender)));
+            polls.push(poll);
+            inboxes.push(inbox);
+        }
+
+        let hooks = ::std::mem::replace(&mut self.hooks, Box::new(NoHooks));
+        let shared = Arc::new(Shared::new(hooks, auth, acl, tls, topic_logs));
+        let mut handle = ServerHandle {
+            local_addresses,
+            websocket_addresses,
+            unix_socket: config.unix_socket.clone(),
+            shutdown: ShutdownHandle::new(&workers),
+            acl: AclHandle::new(config.acl_file.clone(), shared.clone(), &workers),
+            shared: shared.clone(),
+            threads: Vec::new()
+        };
+        // Only the first worker accepts connections
+        let mut listeners = Some(listeners);
+        for (index, (poll, inbox)) in polls.into_iter().zip(inboxes).enumerate() {
+            let mut server = PubsubServer::new(poll, listeners.take().unwrap_or_default(),
+                                               config.clone(), shared.clone(), workers.clone(),
+                                               index, inbox);
+            try!(server.register());
+            let name = if config.workers == 1 {
+                "pubsub-server".to_string()
+            }
+            else {
+                format!("pubsub-worker-{}", index)
+            };
+            // Dropping the handle on failure stops the workers already started
+            let thread = try!(thread::Builder::new()
+                              .name(name)
+                              .spawn(move || server.run()));
+            handle.threads.push(thread);
+        }
+        Ok(handle)
+    }
+}
+
+// A running server. Dropping it shuts the server down and waits for it to stop.
+pub struct ServerHandle {
+    local_addresses: Vec<SocketAddr>,
+    websocket_addresses: Vec<SocketAddr>,
+    unix_socket: Option<PathBuf>,
+    shutdown: ShutdownHandle,
+    acl: AclHandle,
+    shared: Arc<Shared>,
+    // One per worker
+    threads: Vec<JoinHandle<io::Result<()>>>
+}
+
+impl ServerHandle {
+    // The address of the first listener. Panics if the server only
+    // listens on a Unix socket.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addresses[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addresses
+    }
+
+    // The addresses WebSocket connections are accepted on
+    pub fn websocket_addrs(&self) -> &[SocketAddr] {
+        &self.websocket_addresses
+    }
+
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    // A handle that can be used to shut the server down from other threads
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // A handle that can be used to reload the ACL file from other threads
+    pub fn acl_handle(&self) -> AclHandle {
+        self.acl.clone()
+    }
+
+    // How slow consumers have been dealt with so far, by all workers
+    pub fn stats(&self) -> Stats {
+        self.shared.stats()
+    }
+
+    // Shut the server down gracefully, and wait for it to stop
+    pub fn shutdown(mut self) -> io::Result<()> {
+        try!(self.shutdown.shutdown());
+        self.join()
+    }
+
+    // Wait for the server to stop, e.g. after shutting it down
+    // through a shutdown handle
+    pub fn wait(mut self) -> io::Result<()> {
+        self.join()
+    }
+
+    // Wait for all workers, returning the first error of any of them
+    fn join(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for thread in self.threads.drain(..) {
+            let thread_result = thread.join()
+                .unwrap_or_else(|_| Err(io::Error::other("server thread panicked")));
+            if result.is_ok() {
+                result = thread_result;
+            }
+        }
+        result
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if !self.threads.is_empty() {
+            let _ = self.shutdown.shutdown();
+            let _ = self.join();
+        }
+    }
+}
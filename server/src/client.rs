@@ -1,103 +1,114 @@
+use bytes::Bytes;
 use mio;
-use mio::tcp::{TcpStream};
-use mio::{EventSet, PollOpt, TryRead, TryWrite};
+use mio::event::Source;
+use mio::{Interest, Registry};
 
-use pubsub::message::MessageHeader;
-use pubsub::parser::{parse, ParseResult};
-
-use server::{EventLoop};
+use pubsub::decoder::{DecodeError, Decoder};
+use pubsub::message::{ErrorCode, Message, MessageType, SharedMessage, RETAIN_FLAG, EXTENDED_FLAG};
+use pubsub::handshake::{self, Handshake, HandshakeResult};
+use pubsub::topic;
 
 use pending_event::{EventId, PendingEvents};
+use config::{Config, SlowConsumerPolicy};
+use stream::{PeerAddress, Stream};
+use websocket::WebSocketStream;
 
-use std::{u8, u16};
-
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, IoSlice, Write};
 
+// Most events, and bytes of them, written with a single system call.
+// The former stays well below the usual IOV_MAX of 1024.
+const MAX_BATCH_EVENTS: usize = 256;
+const MAX_BATCH_BYTES: usize = 256 * 1024;
 
-const MAX_PACKET_SIZE: usize =
-    1 // Type
-    + 1 // Name length
-    + u8::MAX as usize // Name
-    + 2 // Payload length
-    + u16::MAX as usize; // Payload
 
 pub enum ClientAction {
-    Subscribe(String),
-    Publish(String, Vec<u8>),
+    // The handshake received from the client, to be answered
+    Hello(Handshake),
+    // User name (empty for the shared token) and password or token
+    Authenticate(String, Bytes),
+    // Pattern, and the offset to replay the topic's log from, if any
+    Subscribe(String, Option<u64>),
+    // Event name, payload and whether it should be retained
+    Publish(String, Bytes, bool),
     Unsubscribe(String),
-    Error,
+    // A well-formed request that can't be carried out.
+    // The client is told why, but stays connected.
+    Invalid(String, ErrorCode),
+    // A protocol violation. The client is told why and is then disconnected
+    Error(ErrorCode),
+    // A client being turned away is ready to be told why
+    Refused(ErrorCode),
+    // The socket was closed or failed
+    Disconnected,
     Nothing
 }
 
-enum ReadState {
-    Header(usize),
-    Payload(MessageHeader, usize, usize)
+// Outcome of queueing an event for a client
+pub enum QueueResult {
+    Queued,
+    // The queue limits were exceeded, and the given number of events
+    // (either older ones or the new one) were dropped
+    Dropped(usize),
+    // The queue limits were exceeded, and the client should be disconnected.
+    // The new event was not queued.
+    Full
 }
 
-struct Buffer {
-    buf: [u8; MAX_PACKET_SIZE],
-    read_index: usize,
-    write_index: usize
+// Where a client subscribed to a topic from an offset is in its log
+struct Replay {
+    // The offset of the next event to read from the log, or once caught up,
+    // of the first event sent as published rather than from the log
+    next_offset: u64,
+    caught_up: bool
 }
 
-impl Buffer {
-    fn new() -> Buffer {
-        Buffer {
-            buf: [0; MAX_PACKET_SIZE],
-            read_index: 0,
-            write_index: 0
-        }
-    }
-
-    fn reset(&mut self) {
-        self.read_index = 0;
-        self.write_index = 0;
-    }
-
-    fn reshuffle(&mut self, remaining_bytes: usize) {
-        let mut i = 0;
-        let start = self.write_index - remaining_bytes;
-        for j in start..self.write_index {
-            self.buf[i] = self.buf[j];
-            i += 1;
-        }
-        self.read_index = 0;
-        self.write_index = remaining_bytes;
-    }
-
-    fn writable(&mut self) -> &mut [u8] {
-        &mut self.buf[self.write_index..]
-    }
-
-    fn read_bytes(&self, bytes: usize) -> &[u8] {
-        &self.buf[self.read_index..self.read_index + bytes]
-    }
-
-    fn unused_bytes(&self) -> usize {
-        assert!(self.write_index >= self.read_index);
-        self.write_index - self.read_index
-    }
+struct QueuedEvent {
+    event_id: EventId,
+    len: usize,
+    // Replies to the client's own requests must not be dropped
+    droppable: bool
 }
 
 struct WriteQueue {
-    queue: VecDeque<EventId>,
-    write_index: usize
+    queue: VecDeque<QueuedEvent>,
+    write_index: usize,
+    queued_bytes: usize
 }
 
 impl WriteQueue {
     fn new() -> WriteQueue {
         WriteQueue {
             queue: VecDeque::new(),
-            write_index: 0
+            write_index: 0,
+            queued_bytes: 0
         }
     }
 
-    fn add_event(&mut self, event_id: EventId) {
-        self.queue.push_back(event_id);
+    fn add_event(&mut self, event_id: EventId, event_len: usize, droppable: bool) {
+        self.queue.push_back(QueuedEvent {
+            event_id,
+            len: event_len,
+            droppable
+        });
+        self.queued_bytes += event_len;
     }
 
-    fn current_event_id(&self) -> EventId {
-        self.queue[0]
+    // Remove the oldest droppable event that hasn't started being written
+    fn drop_oldest_event(&mut self) -> Option<EventId> {
+        let first = if self.write_index > 0 { 1 } else { 0 };
+        let index = match self.queue.iter().skip(first).position(|e| e.droppable) {
+            Some(position) => first + position,
+            None => return None
+        };
+        let event = self.queue.remove(index).unwrap();
+        self.queued_bytes -= event.len;
+        Some(event.event_id)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
     }
 
     fn has_events_pending(&self) -> bool {
@@ -105,188 +116,564 @@ impl WriteQueue {
     }
 
     fn finish_current_event(&mut self) -> EventId {
-        let event_id = self.queue.pop_front()
+        let event = self.queue.pop_front()
             .expect("Called finish_current_event with no events left");
         self.write_index = 0;
-        event_id
+        self.queued_bytes -= event.len;
+        event.event_id
+    }
+}
+
+// How frames are carried to and from a client
+enum Transport {
+    // Straight over the socket
+    Stream(Stream),
+    // One per binary WebSocket message
+    WebSocket(Box<WebSocketStream>)
+}
+
+impl Transport {
+    fn wants_write(&self) -> bool {
+        match *self {
+            Transport::Stream(ref stream) => stream.wants_write(),
+            Transport::WebSocket(ref websocket) => websocket.wants_write()
+        }
+    }
+
+    // Whether the TLS or WebSocket handshake has yet to complete
+    fn is_handshaking(&self) -> bool {
+        match *self {
+            Transport::Stream(ref stream) => stream.is_handshaking(),
+            Transport::WebSocket(ref websocket) => websocket.is_handshaking()
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match *self {
+            Transport::Stream(ref mut stream) => stream,
+            Transport::WebSocket(ref mut websocket) => &mut **websocket
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match *self {
+            Transport::Stream(ref mut stream) => stream,
+            Transport::WebSocket(ref mut websocket) => &mut **websocket
+        }
     }
 }
 
 pub struct PubsubClient {
-    socket: TcpStream,
+    socket: Transport,
     token: mio::Token,
-    read_state: ReadState,
+    peer_address: PeerAddress,
+    // Set once the client's handshake has been received
+    handshake_received: bool,
+    // Set once the client has authenticated, or right away if the server
+    // doesn't require it. Until then, any request gets the client disconnected.
+    authenticated: bool,
+    // Set once an Authenticate message has been received, as only one may be sent
+    auth_received: bool,
+    // The user the client authenticated as, None for the shared token
+    // or when not authenticated
+    user: Option<String>,
+    // The patterns subscribed to, to be checked again when the ACL is reloaded
+    subscriptions: HashSet<String>,
+    // The topics subscribed to from an offset
+    replays: HashMap<String, Replay>,
     write_queue: WriteQueue,
-    buffer: Buffer
+    decoder: Decoder,
+    // Number of the last request received, used as correlation id in replies
+    request_id: u32,
+    // Set when the client should be disconnected once its write queue is empty
+    closing: bool,
+    // What the socket is registered for. Writability is only waited for
+    // while there are events queued.
+    interest: Interest,
+    max_queued_events: Option<usize>,
+    max_queued_bytes: Option<usize>,
+    slow_consumer_policy: SlowConsumerPolicy,
+    // Number of events dropped because of the queue limits
+    dropped_events: usize,
+    // Features negotiated in the handshake
+    features: u32,
+    // Why the client is being turned away, if it is. It is told once its
+    // TLS or WebSocket handshake is done, and nothing it sends is acted on.
+    refusal: Option<ErrorCode>
 }
 
 impl PubsubClient {
-    pub fn new(socket: TcpStream, token: mio::Token) -> PubsubClient {
+    // Clients connecting to a WebSocket listener have to upgrade the
+    // connection before sending their handshake
+    pub fn new(socket: Stream, peer_address: PeerAddress, token: mio::Token, config: &Config)
+               -> PubsubClient {
+        let socket = match peer_address {
+            PeerAddress::WebSocket(_) => {
+                Transport::WebSocket(Box::new(WebSocketStream::new(socket, config.max_frame_size)))
+            },
+            _ => Transport::Stream(socket)
+        };
         PubsubClient {
             socket: socket,
             token: token,
-            read_state: ReadState::Header(0),
+            peer_address,
+            handshake_received: false,
+            authenticated: !config.requires_authentication(),
+            auth_received: false,
+            user: None,
+            subscriptions: HashSet::new(),
+            replays: HashMap::new(),
             write_queue: WriteQueue::new(),
-            buffer: Buffer::new()
+            decoder: Decoder::with_max_frame_size(config.max_frame_size),
+            request_id: 0,
+            closing: false,
+            interest: Interest::READABLE,
+            max_queued_events: config.max_queued_events,
+            max_queued_bytes: config.max_queued_bytes,
+            slow_consumer_policy: config.slow_consumer_policy,
+            dropped_events: 0,
+            // Until the handshake is done, only so that a failed one is reported
+            features: handshake::FEATURE_ACKS,
+            refusal: None
         }
     }
 
-    pub fn socket(&self) -> &TcpStream {
-        &self.socket
+    pub fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.register(self.socket.source(), self.token, self.interest)
     }
 
-    fn reregister(&self, event_loop: &mut EventLoop) {
-        let mut event_set = EventSet::readable();
-        if self.write_queue.has_events_pending() {
-            event_set = event_set | EventSet::writable();
+    // Wait for the socket to become writable if there is something to write,
+    // and stop reading from a closing client. The socket is edge triggered,
+    // but reregistering reports it right away if it is already writable.
+    pub fn update_interest(&mut self, registry: &Registry) -> io::Result<()> {
+        let interest = match (self.closing, self.has_data_pending()) {
+            (false, false) => Interest::READABLE,
+            (false, true) => Interest::READABLE | Interest::WRITABLE,
+            // A closing client without events left is disconnected
+            (true, _) => Interest::WRITABLE
+        };
+        if interest != self.interest {
+            self.interest = interest;
+            try!(registry.reregister(self.socket.source(), self.token, interest));
         }
-        event_loop.reregister(&self.socket, self.token, event_set,
-                              PollOpt::edge() | PollOpt::oneshot()).unwrap();
+        Ok(())
     }
 
-    pub fn read(&mut self, event_loop: &mut EventLoop) -> ClientAction {
-        let action = match self.socket.try_read(self.buffer.writable()) {
-            Ok(Some(0)) => { return ClientAction::Error },
-            Ok(Some(len)) => {
-                println!("Read {} bytes", len);
-                self.buffer.write_index += len;
-                self.handle_read(len)
-            },
-            Ok(None) => {
-                // Would Block. Do nothing and simply try again later
-                ClientAction::Nothing
-            },
-            Err(_) => { return ClientAction::Error; }
+    pub fn peer_address(&self) -> &PeerAddress {
+        &self.peer_address
+    }
+
+    // Whether there are events, or TLS or WebSocket data, left to write
+    pub fn has_data_pending(&self) -> bool {
+        self.write_queue.has_events_pending() || self.socket.wants_write()
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    // The credentials sent by the client have been accepted
+    pub fn authenticate(&mut self, user: Option<String>) {
+        self.authenticated = true;
+        self.user = user;
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(|user| &user[..])
+    }
+
+    pub fn subscriptions(&self) -> &HashSet<String> {
+        &self.subscriptions
+    }
+
+    pub fn subscribed(&mut self, pattern: &str) {
+        self.subscriptions.insert(pattern.to_string());
+    }
+
+    pub fn unsubscribed(&mut self, pattern: &str) {
+        self.subscriptions.remove(pattern);
+        self.replays.remove(pattern);
+    }
+
+    // Whether events are sent with their offsets
+    pub fn wants_offsets(&self) -> bool {
+        self.has_feature(handshake::FEATURE_OFFSETS)
+    }
+
+    // Start catching up on the topic's log from the given offset
+    pub fn replay_from(&mut self, topic: &str, offset: u64) {
+        self.replays.insert(topic.to_string(), Replay {
+            next_offset: offset,
+            caught_up: false
+        });
+    }
+
+    // The topics being caught up on, and the offsets to read them from
+    pub fn replaying(&self) -> Vec<(String, u64)> {
+        self.replays.iter()
+            .filter(|&(_, replay)| !replay.caught_up)
+            .map(|(topic, replay)| (topic.clone(), replay.next_offset))
+            .collect()
+    }
+
+    // The topic's log has been read up to the given offset
+    pub fn replayed(&mut self, topic: &str, next_offset: u64, caught_up: bool) {
+        if let Some(replay) = self.replays.get_mut(topic) {
+            replay.next_offset = next_offset;
+            replay.caught_up = caught_up;
+        }
+    }
+
+    // Whether an event published to the topic is to be queued. Those of a
+    // topic being caught up on come from its log instead, as do those
+    // that had already been logged when it was caught up with.
+    pub fn accepts_event(&self, topic: &str, offset: Option<u64>) -> bool {
+        match (self.replays.get(topic), offset) {
+            (None, _) => true,
+            (Some(replay), _) if !replay.caught_up => false,
+            (Some(replay), Some(offset)) => offset >= replay.next_offset,
+            (Some(_), None) => true
+        }
+    }
+
+    // Read once from the socket. Returns the action for the first complete
+    // handshake or message received, or None if there was nothing to read.
+    // The socket is edge triggered, so reading has to go on until then.
+    pub fn read(&mut self) -> Option<ClientAction> {
+        let result = match self.socket {
+            Transport::Stream(ref mut stream) => self.decoder.read_from(stream),
+            Transport::WebSocket(ref mut websocket) => match websocket.read_message() {
+                Ok(Some(message)) => return Some(self.on_websocket_message(&message)),
+                // Control messages are dealt with by the WebSocket
+                Ok(None) => return Some(ClientAction::Nothing),
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    return Some(ClientAction::Error(ErrorCode::MalformedMessage));
+                },
+                Err(e) => Err(e)
+            }
         };
-        self.reregister(event_loop);
-        action
-    }
-
-    pub fn write(&mut self, event_loop: &mut EventLoop, pending_events: &mut PendingEvents) -> Result<(), ()> {
-        // Ugly way of limiting the lifetime of "data"
-        // in order to be able to mutably borrow "pending_events" further down
-        let (write_res, data_len) = {
-            let data = match pending_events.get_event_data(self.write_queue.current_event_id()) {
-                Some(d) => &d[self.write_queue.write_index..],
-                None => {
-                    println!("Tried to get data for non existing event in write! Should not happen");
-                    return Err(());
+        match result {
+            Ok(0) => Some(ClientAction::Disconnected),
+            Ok(len) => {
+                trace!("{}: read {} bytes", self, len);
+                Some(self.next_action())
+            },
+            // Completing the TLS handshake doesn't make anything readable
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.take_refusal(),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Some(ClientAction::Nothing),
+            Err(_) => Some(ClientAction::Disconnected)
+        }
+    }
+
+    // Write as many of the queued events as possible at once,
+    // so that lots of small events don't take a system call each.
+    // Goes on until the queue is empty or the socket is full.
+    pub fn write(&mut self, pending_events: &mut PendingEvents) -> Result<(), ()> {
+        // Encrypted data or messages left over from earlier writes go first
+        match self.socket.writer().flush() {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(_) => return Err(())
+        }
+        while self.write_queue.has_events_pending() {
+            let write_res = {
+                let mut batch = Vec::new();
+                let mut batch_len = 0;
+                for event in self.write_queue.queue.iter() {
+                    let data = match pending_events.get_event_data(event.event_id) {
+                        Some(d) => d,
+                        None => {
+                            error!("{}: tried to get data for non existing event in write", self);
+                            return Err(());
+                        }
+                    };
+                    // The first event may have been partly written already
+                    let data = if batch.is_empty() { &data[self.write_queue.write_index..] } else { data };
+                    batch.push(IoSlice::new(data));
+                    batch_len += data.len();
+                    if batch.len() >= MAX_BATCH_EVENTS || batch_len >= MAX_BATCH_BYTES {
+                        break;
+                    }
                 }
+                // Over WebSocket, each event is a message of its own
+                self.socket.writer().write_vectored(&batch)
             };
-            (self.socket.try_write(data), data.len())
-        };
 
-        match write_res {
-            // Is this OK or not, i.e. should the client be disconnected?
-            Ok(Some(0)) => { return Err(()) },
-            Ok(Some(len)) => {
-                self.write_queue.write_index += len;
-                if self.write_queue.write_index >= data_len {
-                    self.finish_event(pending_events);
-                }
-            },
-            // Would block. Try again later
-            Ok(None) => {}
-            Err(_) => { return Err(()) }
+            match write_res {
+                // Is this OK or not, i.e. should the client be disconnected?
+                Ok(0) => { return Err(()) },
+                Ok(len) => {
+                    trace!("{}: wrote {} bytes", self, len);
+                    self.finish_written(len, pending_events);
+                },
+                // Try again once the socket is writable
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => { return Err(()) }
+            }
         }
-        self.reregister(event_loop);
         Ok(())
     }
 
-    pub fn handle_read(&mut self, read_len: usize) -> ClientAction {
-        let mut packet_complete = false;
+    // The action for the next complete handshake or message received, if any
+    pub fn next_action(&mut self) -> ClientAction {
+        if self.refusal.is_some() {
+            return self.take_refusal().unwrap_or(ClientAction::Nothing);
+        }
+        if !self.handshake_received {
+            if self.decoder.buffered().is_empty() {
+                return ClientAction::Nothing;
+            }
+            return match handshake::parse(self.decoder.buffered()) {
+                HandshakeResult::Completed(hello, consumed) => {
+                    self.decoder.consume(consumed);
+                    self.handshake_received = true;
+                    ClientAction::Hello(hello)
+                },
+                HandshakeResult::Incomplete => ClientAction::Nothing,
+                HandshakeResult::Error => ClientAction::Error(ErrorCode::HandshakeFailed)
+            };
+        }
 
-        let action = match self.read_state {
-            ReadState::Header(in_buffer) => {
-                let readable_bytes = in_buffer + read_len;
-                if readable_bytes == 0 {
-                    return ClientAction::Nothing;
-                }
-                let parse_result = parse(self.buffer.read_bytes(readable_bytes));
-                match parse_result {
-                    ParseResult::Completed(msg_header, header_len, payload_len) => {
-                        self.buffer.read_index = header_len;
-
-                        assert!(in_buffer + read_len >= header_len);
-                        let remaining_in_buffer = in_buffer + read_len - header_len;
-
-                        match self.on_header(msg_header, remaining_in_buffer, payload_len) {
-                            Some(action) => {
-                                packet_complete = true;
-                                action
-                            },
-                            None => ClientAction::Nothing
-                        }
-                    },
-                    ParseResult::Incomplete => {
-                        self.read_state = ReadState::Header(in_buffer + read_len);
-                        ClientAction::Nothing
-                    }
-                    ParseResult::Error => {
-                        packet_complete = true;
-                        ClientAction::Error
-                    }
+        match self.decoder.decode_shared() {
+            Ok(Some(message)) => {
+                // Authenticate isn't counted as a request
+                if message.header.message_type != MessageType::Authenticate {
+                    self.request_id = self.request_id.wrapping_add(1);
                 }
+                self.on_message(message)
             },
-            ReadState::Payload(ref header, ref mut in_buffer, payload_len) => {
-                if *in_buffer + read_len >= payload_len {
-                    let payload = Vec::from(self.buffer.read_bytes(payload_len));
-                    self.buffer.read_index += payload_len;
-                    packet_complete = true;
-                    ClientAction::Publish(header.event_name.clone(), payload)
-                }
-                else {
-                    *in_buffer += read_len;
-                    ClientAction::Nothing
-                }
+            Ok(None) => ClientAction::Nothing,
+            Err(DecodeError::FrameTooLarge) => {
+                self.request_id = self.request_id.wrapping_add(1);
+                ClientAction::Error(ErrorCode::FrameTooLarge)
+            },
+            Err(DecodeError::Malformed) => {
+                self.request_id = self.request_id.wrapping_add(1);
+                ClientAction::Error(ErrorCode::MalformedMessage)
             }
+        }
+    }
+
+    // Each WebSocket message carries exactly one handshake or message
+    fn on_websocket_message(&mut self, message: &[u8]) -> ClientAction {
+        trace!("{}: received a WebSocket message of {} bytes", self, message.len());
+        if let Some(action) = self.take_refusal() {
+            return action;
+        }
+        let handshake = !self.handshake_received;
+        self.decoder.feed(message);
+        let action = self.next_action();
+        let left = self.decoder.buffered().len();
+        self.decoder.consume(left);
+        let parsed = match action {
+            ClientAction::Nothing => false,
+            ClientAction::Error(_) => return action,
+            _ if left == 0 => return action,
+            _ => true
         };
+        // Part of a handshake or message, more than one, or nothing at all
+        if handshake {
+            return ClientAction::Error(ErrorCode::HandshakeFailed);
+        }
+        if !parsed {
+            self.request_id = self.request_id.wrapping_add(1);
+        }
+        ClientAction::Error(ErrorCode::MalformedMessage)
+    }
+
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events
+    }
+
+    fn exceeds_queue_limits(&self, event_len: usize) -> bool {
+        let too_many = self.max_queued_events
+            .is_some_and(|max| self.write_queue.len() >= max);
+        let too_large = self.max_queued_bytes
+            .is_some_and(|max| self.write_queue.queued_bytes + event_len > max);
+        too_many || too_large
+    }
+
+    // Queue an event for writing to the client, applying the slow consumer
+    // policy if that would exceed the client's queue limits.
+    // Events that are dropped are finished in pending_events, except for
+    // the new event when the result is Full.
+    pub fn publish(&mut self, event_id: EventId, event_len: usize,
+                   pending_events: &mut PendingEvents) -> QueueResult {
+        if !self.exceeds_queue_limits(event_len) {
+            self.queue_event(event_id, event_len, true);
+            return QueueResult::Queued;
+        }
+
+        let mut dropped = 0;
+        match self.slow_consumer_policy {
+            SlowConsumerPolicy::Disconnect => return QueueResult::Full,
+            SlowConsumerPolicy::DropOldest => {
+                while self.exceeds_queue_limits(event_len) {
+                    match self.write_queue.drop_oldest_event() {
+                        Some(dropped_id) => {
+                            pending_events.finish_event(dropped_id);
+                            dropped += 1;
+                        },
+                        None => break
+                    }
+                }
+            },
+            SlowConsumerPolicy::DropNewest => {}
+        }
+
+        // Nothing left to drop to make room for the new event
+        // (or the policy is to drop the new one)
+        if self.exceeds_queue_limits(event_len) {
+            pending_events.finish_event(event_id);
+            dropped += 1;
+        }
+        else {
+            self.queue_event(event_id, event_len, true);
+        }
+        self.dropped_events += dropped;
+        QueueResult::Dropped(dropped)
+    }
+
+    fn queue_event(&mut self, event_id: EventId, event_len: usize, droppable: bool) {
+        self.write_queue.add_event(event_id, event_len, droppable);
+    }
+
+    // Replies are always queued, regardless of the queue limits
+    fn reply(&mut self, data: Bytes, pending_events: &mut PendingEvents) {
+        let event_len = data.len();
+        let event_id = pending_events.add_event(data, 1);
+        self.queue_event(event_id, event_len, false);
+    }
+
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature != 0
+    }
+
+    // Whether the message only uses features negotiated with the client
+    pub fn accepts(&self, message_data: &[u8]) -> bool {
+        uses_only(message_data, self.features)
+    }
+
+    // Answer the client's handshake
+    pub fn welcome(&mut self, handshake: Handshake, pending_events: &mut PendingEvents) {
+        self.features = handshake.features;
+        self.reply(Bytes::from(handshake.into_bytes()), pending_events);
+    }
+
+    // Acknowledge the last request received
+    pub fn ack(&mut self, event_name: String, pending_events: &mut PendingEvents) {
+        if !self.has_feature(handshake::FEATURE_ACKS) {
+            return;
+        }
+        let message = Message::ack(event_name, self.request_id);
+        self.reply(Bytes::from(message.into_bytes()), pending_events);
+    }
 
-        if packet_complete {
-            self.on_packet_complete();
+    // Reject the last request received
+    pub fn reject(&mut self, event_name: String, code: ErrorCode,
+                  pending_events: &mut PendingEvents) {
+        if !self.has_feature(handshake::FEATURE_ACKS) {
+            return;
         }
+        let message = Message::error(event_name, self.request_id, code);
+        self.reply(Bytes::from(message.into_bytes()), pending_events);
+    }
 
-        action
+    // Tell the client it lost a subscription, with an error that isn't
+    // a reply to any request
+    pub fn revoked(&mut self, pattern: String, pending_events: &mut PendingEvents) {
+        if !self.has_feature(handshake::FEATURE_ACKS) {
+            return;
+        }
+        let message = Message::error(pattern, 0, ErrorCode::AccessDenied);
+        self.reply(Bytes::from(message.into_bytes()), pending_events);
     }
 
-    pub fn publish(&mut self, event_id: EventId, event_loop: &mut EventLoop) {
-        self.write_queue.add_event(event_id);
-        self.reregister(event_loop);
+    // Stop reading from the client, and disconnect it once the write queue has been flushed
+    pub fn close(&mut self) {
+        self.closing = true;
     }
 
-    fn on_header(&mut self, header: MessageHeader, remaining_in_buffer: usize, payload_len: usize)
-                 -> Option<ClientAction> {
+    // Turn the client away without serving it, telling it why once
+    // that can be done
+    pub fn refuse(&mut self, code: ErrorCode) {
+        self.refusal = Some(code);
+    }
+
+    pub fn is_refused(&self) -> bool {
+        self.refusal.is_some()
+    }
+
+    fn take_refusal(&mut self) -> Option<ClientAction> {
+        if self.closing || self.socket.is_handshaking() {
+            return None;
+        }
+        self.refusal.map(ClientAction::Refused)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closing && !self.has_data_pending()
+    }
+
+    fn on_message(&mut self, message: SharedMessage) -> ClientAction {
         use pubsub::message::MessageType::*;
 
+        // Retained, extended or offset messages from a client that didn't negotiate them
+        if (message.header.retain && !self.has_feature(handshake::FEATURE_RETAIN))
+            || (message.is_extended() && !self.has_feature(handshake::FEATURE_EXTENDED_FRAMES))
+            || (message.header.offset.is_some() && !self.has_feature(handshake::FEATURE_OFFSETS)) {
+            return ClientAction::Error(ErrorCode::MalformedMessage);
+        }
+
+        let header = message.header;
         match header.message_type {
-            Subscribe => Some(ClientAction::Subscribe(header.event_name)),
-            Unsubscribe => Some(ClientAction::Unsubscribe(header.event_name)),
+            // Authenticate has to come before any request, from a client
+            // that negotiated it
+            Authenticate if self.auth_received || self.request_id > 0
+                || !self.has_feature(handshake::FEATURE_AUTH) => {
+                ClientAction::Error(ErrorCode::UnexpectedMessage)
+            },
+            Authenticate => {
+                self.auth_received = true;
+                ClientAction::Authenticate(header.event_name, message.payload.unwrap_or_default())
+            },
+            Subscribe | Unsubscribe | Publish if !self.authenticated => {
+                ClientAction::Error(ErrorCode::NotAuthenticated)
+            },
+            Subscribe | Unsubscribe if !topic::is_valid_pattern(&header.event_name) => {
+                ClientAction::Invalid(header.event_name, ErrorCode::InvalidTopic)
+            },
+            // Only the log of a single topic can be replayed
+            Subscribe if header.offset.is_some() && !topic::is_valid_topic(&header.event_name) => {
+                ClientAction::Invalid(header.event_name, ErrorCode::InvalidTopic)
+            },
+            Subscribe => ClientAction::Subscribe(header.event_name, header.offset),
+            Unsubscribe => ClientAction::Unsubscribe(header.event_name),
+            // Publishing has to be done to a concrete event name
+            Publish if !topic::is_valid_topic(&header.event_name) => {
+                ClientAction::Invalid(header.event_name, ErrorCode::InvalidTopic)
+            },
             Publish => {
-                if remaining_in_buffer >= payload_len {
-                    // Got the entire payload as well in the same read
-                    let payload = Vec::from(self.buffer.read_bytes(payload_len));
-                    self.buffer.read_index += payload_len;
-                    Some(ClientAction::Publish(header.event_name, payload))
-                }
-                else {
-                    self.read_state = ReadState::Payload(header, remaining_in_buffer, payload_len);
-                    None
-                }
+                ClientAction::Publish(header.event_name, message.payload.unwrap_or_default(),
+                                      header.retain)
             },
-            // Event should only be sent server -> client
-            Event => Some(ClientAction::Error)
+            // These should only be sent server -> client
+            Event | Ack | Error => ClientAction::Error(ErrorCode::UnexpectedMessage)
         }
     }
 
-    fn on_packet_complete(&mut self) {
-        let unused_bytes = self.buffer.unused_bytes();
-        if unused_bytes > 0 {
-            self.buffer.reshuffle(unused_bytes);
-        }
-        else {
-            self.buffer.reset();
+    // Finish the events that have been written completely
+    fn finish_written(&mut self, mut written: usize, pending_events: &mut PendingEvents) {
+        while written > 0 {
+            let remaining = self.write_queue.queue[0].len - self.write_queue.write_index;
+            if written < remaining {
+                self.write_queue.write_index += written;
+                return;
+            }
+            written -= remaining;
+            self.finish_event(pending_events);
         }
-        self.read_state = ReadState::Header(unused_bytes);
     }
 
     fn finish_event(&mut self, pending_events: &mut PendingEvents) {
@@ -300,3 +687,47 @@ impl PubsubClient {
         }
     }
 }
+
+// Identifies the client in log messages
+impl fmt::Display for PubsubClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.user {
+            Some(ref user) => write!(f, "client {} ({}, {})", self.token.0, self.peer_address, user),
+            None => write!(f, "client {} ({})", self.token.0, self.peer_address)
+        }
+    }
+}
+
+// Whether the flags of an encoded message only need the given features
+fn uses_only(message_data: &[u8], features: u32) -> bool {
+    let flags = message_data[0];
+    (flags & RETAIN_FLAG == 0 || features & handshake::FEATURE_RETAIN != 0)
+        && (flags & EXTENDED_FLAG == 0 || features & handshake::FEATURE_EXTENDED_FRAMES != 0)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::uses_only;
+    use pubsub::handshake::{FEATURE_ACKS, FEATURE_RETAIN, FEATURE_EXTENDED_FRAMES};
+    use pubsub::message::{MessageBuilder, MessageType};
+
+    fn event(payload_len: usize, retain: bool) -> Vec<u8> {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Event)
+            .event_name("event".to_string())
+            .payload(vec![0; payload_len])
+            .retain(retain);
+        builder.build().unwrap().into_bytes()
+    }
+
+    #[test]
+    fn test_uses_only() {
+        assert!(uses_only(&event(10, false), 0));
+        assert!(!uses_only(&event(10, true), FEATURE_ACKS));
+        assert!(uses_only(&event(10, true), FEATURE_RETAIN));
+        assert!(!uses_only(&event(70000, false), FEATURE_ACKS | FEATURE_RETAIN));
+        assert!(uses_only(&event(70000, false), FEATURE_EXTENDED_FRAMES));
+        assert!(!uses_only(&event(70000, true), FEATURE_EXTENDED_FRAMES));
+    }
+}
@@ -0,0 +1,884 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{self, App, Arg, ArgMatches};
+use log::LevelFilter;
+use toml;
+
+use pubsub::decoder::DEFAULT_MAX_FRAME_SIZE;
+
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:9876";
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+// Default time given to flush queued events to clients when shutting down, in seconds
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 5;
+pub const DEFAULT_WORKERS: usize = 1;
+// Default size a topic log segment grows to before a new one is started
+pub const DEFAULT_TOPIC_LOG_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+// Default number of topic logs kept open at once
+pub const DEFAULT_TOPIC_LOG_MAX_OPEN: usize = 256;
+// Default time between syncs of the topic logs to disk when they are synced
+// periodically, in milliseconds
+pub const DEFAULT_TOPIC_LOG_SYNC_INTERVAL: u64 = 1000;
+
+// What to do when an event would make a client's write queue exceed its limits
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SlowConsumerPolicy {
+    // Drop queued events, oldest first, to make room for the new one
+    DropOldest,
+    // Drop the new event
+    DropNewest,
+    // Disconnect the client
+    Disconnect
+}
+
+impl SlowConsumerPolicy {
+    fn parse(policy: &str) -> Result<SlowConsumerPolicy, ConfigError> {
+        match policy {
+            "drop-oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "drop-newest" => Ok(SlowConsumerPolicy::DropNewest),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(ConfigError::InvalidValue("slow consumer policy", policy.to_string()))
+        }
+    }
+}
+
+// When events appended to the topic logs are synced to disk, which decides
+// what the ack to a publish guarantees
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TopicLogSync {
+    // Before the publisher is acked, so that an acked event survives a crash
+    // of the machine, at the cost of a disk flush per publish
+    Always,
+    // Every sync interval, in the background. Events acked since the last
+    // sync are lost if the machine crashes, but not if only the server does.
+    Periodic,
+    // Whenever the operating system gets to it
+    Never
+}
+
+impl TopicLogSync {
+    fn parse(sync: &str) -> Result<TopicLogSync, ConfigError> {
+        match sync {
+            "always" => Ok(TopicLogSync::Always),
+            "periodic" => Ok(TopicLogSync::Periodic),
+            "never" => Ok(TopicLogSync::Never),
+            _ => Err(ConfigError::InvalidValue("topic log sync", sync.to_string()))
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Config {
+    pub bind_addresses: Vec<SocketAddr>,
+    // A Unix socket to listen on as well as, or instead of, the addresses,
+    // and the permissions of the socket file (e.g. 0o660)
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_mode: Option<u32>,
+    // Addresses to accept WebSocket connections on, e.g. from browsers
+    pub websocket_addresses: Vec<SocketAddr>,
+    pub max_connections: usize,
+    pub max_frame_size: usize,
+    // Limits of the events queued for writing to a single client,
+    // and what to do with clients exceeding them
+    pub max_queued_events: Option<usize>,
+    pub max_queued_bytes: Option<usize>,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    // How long to keep writing to clients after being asked to shut down
+    pub shutdown_timeout: Duration,
+    // Number of threads serving clients, each running its own event loop
+    pub workers: usize,
+    // Clients have to authenticate with either the token, or a user name and
+    // password from the credentials file, when one of them is given
+    pub auth_token: Option<String>,
+    pub credentials_file: Option<PathBuf>,
+    // Rules of who may subscribe and publish to what. Everyone may do
+    // anything without it.
+    pub acl_file: Option<PathBuf>,
+    // Clients connect with TLS when a certificate and key are given,
+    // and have to present a certificate signed by the client CA if one is
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    // Published events are appended to a log per topic in this directory
+    // when given, so that subscribers can replay them from an offset.
+    // The oldest segments of a topic are deleted once it has more than
+    // the maximum, if there is one. The logs used least recently are
+    // closed once more than the maximum are open.
+    pub topic_log_dir: Option<PathBuf>,
+    pub topic_log_segment_size: u64,
+    pub topic_log_max_segments: Option<usize>,
+    pub topic_log_max_open: usize,
+    pub topic_log_sync: TopicLogSync,
+    pub topic_log_sync_interval: Duration,
+    pub log_level: LevelFilter
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind_addresses: vec![DEFAULT_BIND_ADDRESS.parse().unwrap()],
+            unix_socket: None,
+            unix_socket_mode: None,
+            websocket_addresses: Vec::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_queued_events: None,
+            max_queued_bytes: None,
+            slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT),
+            workers: DEFAULT_WORKERS,
+            auth_token: None,
+            credentials_file: None,
+            acl_file: None,
+            tls_certificate: None,
+            tls_key: None,
+            tls_client_ca: None,
+            topic_log_dir: None,
+            topic_log_segment_size: DEFAULT_TOPIC_LOG_SEGMENT_SIZE,
+            topic_log_max_segments: None,
+            topic_log_max_open: DEFAULT_TOPIC_LOG_MAX_OPEN,
+            topic_log_sync: TopicLogSync::Always,
+            topic_log_sync_interval: Duration::from_millis(DEFAULT_TOPIC_LOG_SYNC_INTERVAL),
+            log_level: LevelFilter::Info
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ConfigError {
+    // Help or version text asked for on the command line
+    Help(String),
+    // Unknown or malformed command line arguments, with usage
+    Arguments(String),
+    // Path and reason
+    File(String, String),
+    // Path and reason
+    CredentialsFile(String, String),
+    // Path and reason
+    AclFile(String, String),
+    // Path and reason
+    Tls(String, String),
+    // Setting and value
+    InvalidValue(&'static str, String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Help(ref text) | ConfigError::Arguments(ref text) => {
+                write!(f, "{}", text)
+            },
+            ConfigError::File(ref path, ref reason) => {
+                write!(f, "couldn't read config file {}: {}", path, reason)
+            },
+            ConfigError::CredentialsFile(ref path, ref reason) => {
+                write!(f, "couldn't read credentials file {}: {}", path, reason)
+            },
+            ConfigError::AclFile(ref path, ref reason) => {
+                write!(f, "couldn't read ACL file {}: {}", path, reason)
+            },
+            ConfigError::Tls(ref path, ref reason) => {
+                write!(f, "couldn't load TLS file {}: {}", path, reason)
+            },
+            ConfigError::InvalidValue(setting, ref value) => {
+                write!(f, "invalid value for {}: {}", setting, value)
+            }
+        }
+    }
+}
+
+// The config file format. Every setting is optional
+// and overrides the default when present.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    bind: Option<Vec<String>>,
+    unix_socket: Option<String>,
+    // In octal, e.g. "660"
+    unix_socket_mode: Option<String>,
+    websocket: Option<Vec<String>>,
+    max_connections: Option<usize>,
+    max_frame_size: Option<usize>,
+    // In seconds
+    shutdown_timeout: Option<u64>,
+    workers: Option<usize>,
+    log_level: Option<String>,
+    client_queue: Option<QueueSection>,
+    auth: Option<AuthSection>,
+    tls: Option<TlsSection>,
+    topic_log: Option<TopicLogSection>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct QueueSection {
+    max_events: Option<usize>,
+    max_bytes: Option<usize>,
+    policy: Option<String>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AuthSection {
+    token: Option<String>,
+    credentials_file: Option<String>,
+    acl_file: Option<String>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+    certificate: Option<String>,
+    key: Option<String>,
+    client_ca: Option<String>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TopicLogSection {
+    dir: Option<String>,
+    segment_size: Option<u64>,
+    max_segments: Option<usize>,
+    max_open: Option<usize>,
+    sync: Option<String>,
+    // In milliseconds
+    sync_interval: Option<u64>
+}
+
+impl Config {
+    // Build the config from the command line, using the config file
+    // given with --config (if any) for settings not given as flags
+    pub fn from_args<I, T>(args: I) -> Result<Config, ConfigError>
+        where I: IntoIterator<Item=T>, T: Into<::std::ffi::OsString> + Clone {
+        let matches = try!(app().get_matches_from_safe(args).map_err(|e| match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => {
+                ConfigError::Help(e.message)
+            },
+            _ => ConfigError::Arguments(e.message)
+        }));
+        let mut config = Config::default();
+        // The default address is only listened on when given no other
+        // address or socket
+        config.bind_addresses.clear();
+        if let Some(path) = matches.value_of("config") {
+            try!(config.merge_file(path));
+        }
+        try!(config.merge_args(&matches));
+        if !config.has_listeners() {
+            config.bind_addresses = Config::default().bind_addresses;
+        }
+        try!(config.validate());
+        Ok(config)
+    }
+
+    fn merge_file(&mut self, path: &str) -> Result<(), ConfigError> {
+        let mut contents = String::new();
+        try!(File::open(path)
+             .and_then(|mut f| f.read_to_string(&mut contents))
+             .map_err(|e| ConfigError::File(path.to_string(), e.to_string())));
+        self.merge_toml(&contents)
+            .map_err(|e| match e {
+                ConfigError::File(_, reason) => ConfigError::File(path.to_string(), reason),
+                e => e
+            })
+    }
+
+    fn merge_toml(&mut self, contents: &str) -> Result<(), ConfigError> {
+        let file: ConfigFile = try!(toml::from_str(contents)
+                                    .map_err(|e| ConfigError::File(String::new(), e.to_string())));
+        if let Some(bind) = file.bind {
+            self.bind_addresses = try!(parse_addresses("bind address",
+                                                       bind.iter().map(|a| &a[..])));
+        }
+        if let Some(unix_socket) = file.unix_socket {
+            self.unix_socket = Some(PathBuf::from(unix_socket));
+        }
+        if let Some(mode) = file.unix_socket_mode {
+            self.unix_socket_mode = Some(try!(parse_mode(&mode)));
+        }
+        if let Some(websocket) = file.websocket {
+            self.websocket_addresses = try!(parse_addresses("WebSocket address",
+                                                            websocket.iter().map(|a| &a[..])));
+        }
+        if let Some(max_connections) = file.max_connections {
+            self.max_connections = max_connections;
+        }
+        if let Some(max_frame_size) = file.max_frame_size {
+            self.max_frame_size = max_frame_size;
+        }
+        if let Some(shutdown_timeout) = file.shutdown_timeout {
+            self.shutdown_timeout = Duration::from_secs(shutdown_timeout);
+        }
+        if let Some(workers) = file.workers {
+            self.workers = workers;
+        }
+        if let Some(log_level) = file.log_level {
+            self.log_level = try!(parse_log_level(&log_level));
+        }
+        let queue = file.client_queue.unwrap_or_default();
+        if queue.max_events.is_some() {
+            self.max_queued_events = queue.max_events;
+        }
+        if queue.max_bytes.is_some() {
+            self.max_queued_bytes = queue.max_bytes;
+        }
+        if let Some(policy) = queue.policy {
+            self.slow_consumer_policy = try!(SlowConsumerPolicy::parse(&policy));
+        }
+        let auth = file.auth.unwrap_or_default();
+        if auth.token.is_some() {
+            self.auth_token = auth.token;
+        }
+        if let Some(credentials_file) = auth.credentials_file {
+            self.credentials_file = Some(PathBuf::from(credentials_file));
+        }
+        if let Some(acl_file) = auth.acl_file {
+            self.acl_file = Some(PathBuf::from(acl_file));
+        }
+        let tls = file.tls.unwrap_or_default();
+        if let Some(certificate) = tls.certificate {
+            self.tls_certificate = Some(PathBuf::from(certificate));
+        }
+        if let Some(key) = tls.key {
+            self.tls_key = Some(PathBuf::from(key));
+        }
+        if let Some(client_ca) = tls.client_ca {
+            self.tls_client_ca = Some(PathBuf::from(client_ca));
+        }
+        let topic_log = file.topic_log.unwrap_or_default();
+        if let Some(dir) = topic_log.dir {
+            self.topic_log_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(segment_size) = topic_log.segment_size {
+            self.topic_log_segment_size = segment_size;
+        }
+        if topic_log.max_segments.is_some() {
+            self.topic_log_max_segments = topic_log.max_segments;
+        }
+        if let Some(max_open) = topic_log.max_open {
+            self.topic_log_max_open = max_open;
+        }
+        if let Some(sync) = topic_log.sync {
+            self.topic_log_sync = try!(TopicLogSync::parse(&sync));
+        }
+        if let Some(sync_interval) = topic_log.sync_interval {
+            self.topic_log_sync_interval = Duration::from_millis(sync_interval);
+        }
+        Ok(())
+    }
+
+    fn merge_args(&mut self, matches: &ArgMatches) -> Result<(), ConfigError> {
+        if let Some(bind) = matches.values_of("bind") {
+            self.bind_addresses = try!(parse_addresses("bind address", bind));
+        }
+        if let Some(unix_socket) = matches.value_of("unix-socket") {
+            self.unix_socket = Some(PathBuf::from(unix_socket));
+        }
+        if let Some(mode) = matches.value_of("unix-socket-mode") {
+            self.unix_socket_mode = Some(try!(parse_mode(mode)));
+        }
+        if let Some(websocket) = matches.values_of("websocket") {
+            self.websocket_addresses = try!(parse_addresses("WebSocket address", websocket));
+        }
+        if let Some(max_connections) = try!(parse_arg(matches, "max-connections", "max connections")) {
+            self.max_connections = max_connections;
+        }
+        if let Some(max_frame_size) = try!(parse_arg(matches, "max-frame-size", "max frame size")) {
+            self.max_frame_size = max_frame_size;
+        }
+        if let Some(max_queued_events) = try!(parse_arg(matches, "max-queued-events", "max queued events")) {
+            self.max_queued_events = Some(max_queued_events);
+        }
+        if let Some(max_queued_bytes) = try!(parse_arg(matches, "max-queued-bytes", "max queued bytes")) {
+            self.max_queued_bytes = Some(max_queued_bytes);
+        }
+        if let Some(policy) = matches.value_of("slow-consumer-policy") {
+            self.slow_consumer_policy = try!(SlowConsumerPolicy::parse(policy));
+        }
+        if let Some(shutdown_timeout) = try!(parse_arg(matches, "shutdown-timeout", "shutdown timeout")) {
+            self.shutdown_timeout = Duration::from_secs(shutdown_timeout);
+        }
+        if let Some(workers) = try!(parse_arg(matches, "workers", "workers")) {
+            self.workers = workers;
+        }
+        if let Some(token) = matches.value_of("auth-token") {
+            self.auth_token = Some(token.to_string());
+        }
+        if let Some(credentials_file) = matches.value_of("credentials-file") {
+            self.credentials_file = Some(PathBuf::from(credentials_file));
+        }
+        if let Some(acl_file) = matches.value_of("acl-file") {
+            self.acl_file = Some(PathBuf::from(acl_file));
+        }
+        if let Some(certificate) = matches.value_of("tls-cert") {
+            self.tls_certificate = Some(PathBuf::from(certificate));
+        }
+        if let Some(key) = matches.value_of("tls-key") {
+            self.tls_key = Some(PathBuf::from(key));
+        }
+        if let Some(client_ca) = matches.value_of("tls-client-ca") {
+            self.tls_client_ca = Some(PathBuf::from(client_ca));
+        }
+        if let Some(dir) = matches.value_of("topic-log-dir") {
+            self.topic_log_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(segment_size) = try!(parse_arg(matches, "topic-log-segment-size", "topic log segment size")) {
+            self.topic_log_segment_size = segment_size;
+        }
+        if let Some(max_segments) = try!(parse_arg(matches, "topic-log-max-segments", "topic log max segments")) {
+            self.topic_log_max_segments = Some(max_segments);
+        }
+        if let Some(max_open) = try!(parse_arg(matches, "topic-log-max-open", "topic log max open")) {
+            self.topic_log_max_open = max_open;
+        }
+        if let Some(sync) = matches.value_of("topic-log-sync") {
+            self.topic_log_sync = try!(TopicLogSync::parse(sync));
+        }
+        if let Some(sync_interval) = try!(parse_arg(matches, "topic-log-sync-interval", "topic log sync interval")) {
+            self.topic_log_sync_interval = Duration::from_millis(sync_interval);
+        }
+        if let Some(log_level) = matches.value_of("log-level") {
+            self.log_level = try!(parse_log_level(log_level));
+        }
+        Ok(())
+    }
+
+    pub fn requires_authentication(&self) -> bool {
+        self.auth_token.is_some() || self.credentials_file.is_some()
+    }
+
+    pub fn uses_tls(&self) -> bool {
+        self.tls_certificate.is_some()
+    }
+
+    pub fn logs_topics(&self) -> bool {
+        self.topic_log_dir.is_some()
+    }
+
+    fn has_listeners(&self) -> bool {
+        !self.bind_addresses.is_empty() || self.unix_socket.is_some() ||
+            !self.websocket_addresses.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.has_listeners() {
+            return Err(ConfigError::InvalidValue("bind address", "none given".to_string()));
+        }
+        if self.unix_socket_mode.is_some() && self.unix_socket.is_none() {
+            return Err(ConfigError::InvalidValue("Unix socket mode", "given without a socket"
+                                                 .to_string()));
+        }
+        // Port 0 has a port picked for each listener
+        let addresses: Vec<_> = self.bind_addresses.iter()
+            .chain(&self.websocket_addresses)
+            .filter(|address| address.port() != 0)
+            .collect();
+        for (i, address) in addresses.iter().enumerate() {
+            if addresses[..i].contains(address) {
+                return Err(ConfigError::InvalidValue("bind address",
+                                                     format!("{} given twice", address)));
+            }
+        }
+        if self.max_connections == 0 {
+            return Err(ConfigError::InvalidValue("max connections", "0".to_string()));
+        }
+        if self.max_frame_size == 0 {
+            return Err(ConfigError::InvalidValue("max frame size", "0".to_string()));
+        }
+        if self.max_queued_events == Some(0) {
+            return Err(ConfigError::InvalidValue("max queued events", "0".to_string()));
+        }
+        if self.max_queued_bytes == Some(0) {
+            return Err(ConfigError::InvalidValue("max queued bytes", "0".to_string()));
+        }
+        if self.workers == 0 {
+            return Err(ConfigError::InvalidValue("workers", "0".to_string()));
+        }
+        if self.auth_token.as_ref().is_some_and(|token| token.is_empty()) {
+            return Err(ConfigError::InvalidValue("auth token", "none given".to_string()));
+        }
+        if self.tls_certificate.is_some() != self.tls_key.is_some() {
+            return Err(ConfigError::InvalidValue("TLS", "certificate and key go together"
+                                                 .to_string()));
+        }
+        if self.tls_client_ca.is_some() && !self.uses_tls() {
+            return Err(ConfigError::InvalidValue("TLS client CA", "given without a certificate"
+                                                 .to_string()));
+        }
+        if self.topic_log_segment_size == 0 {
+            return Err(ConfigError::InvalidValue("topic log segment size", "0".to_string()));
+        }
+        if self.topic_log_max_segments == Some(0) {
+            return Err(ConfigError::InvalidValue("topic log max segments", "0".to_string()));
+        }
+        if self.topic_log_max_segments.is_some() && !self.logs_topics() {
+            return Err(ConfigError::InvalidValue("topic log max segments",
+                                                 "given without a directory".to_string()));
+        }
+        if self.topic_log_max_open == 0 {
+            return Err(ConfigError::InvalidValue("topic log max open", "0".to_string()));
+        }
+        if self.topic_log_sync_interval == Duration::from_secs(0) {
+            return Err(ConfigError::InvalidValue("topic log sync interval", "0".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("pubsub-server")
+        .about("Publish/subscribe message broker")
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("TOML config file. Command line flags take precedence over it"))
+        .arg(Arg::with_name("bind")
+             .short("b")
+             .long("bind")
+             .value_name("ADDRESS")
+             .multiple(true)
+             .number_of_values(1)
+             .help("Address to listen on. May be given several times"))
+        .arg(Arg::with_name("unix-socket")
+             .long("unix-socket")
+             .value_name("PATH")
+             .help("Unix socket to listen on. Without --bind, only this socket is listened on"))
+        .arg(Arg::with_name("unix-socket-mode")
+             .long("unix-socket-mode")
+             .value_name("MODE")
+             .help("Permissions of the Unix socket file, in octal, e.g. 660"))
+        .arg(Arg::with_name("websocket")
+             .long("websocket")
+             .value_name("ADDRESS")
+             .multiple(true)
+             .number_of_values(1)
+             .help("Address to accept WebSocket connections on, each binary message \
+                    carrying one protocol message. May be given several times"))
+        .arg(Arg::with_name("max-connections")
+             .long("max-connections")
+             .value_name("N")
+             .help("Maximum number of connected clients"))
+        .arg(Arg::with_name("max-frame-size")
+             .long("max-frame-size")
+             .value_name("BYTES")
+             .help("Maximum size of a single message"))
+        .arg(Arg::with_name("max-queued-events")
+             .long("max-queued-events")
+             .value_name("N")
+             .help("Maximum number of events queued for a single client"))
+        .arg(Arg::with_name("max-queued-bytes")
+             .long("max-queued-bytes")
+             .value_name("BYTES")
+             .help("Maximum number of bytes queued for a single client"))
+        .arg(Arg::with_name("slow-consumer-policy")
+             .long("slow-consumer-policy")
+             .value_name("POLICY")
+             .possible_values(&["drop-oldest", "drop-newest", "disconnect"])
+             .help("What to do when a client's queue limits are exceeded"))
+        .arg(Arg::with_name("shutdown-timeout")
+             .long("shutdown-timeout")
+             .value_name("SECONDS")
+             .help("How long to keep flushing queued events to clients when shutting down"))
+        .arg(Arg::with_name("workers")
+             .short("w")
+             .long("workers")
+             .value_name("THREADS")
+             .help("Number of threads serving clients"))
+        .arg(Arg::with_name("auth-token")
+             .long("auth-token")
+             .value_name("TOKEN")
+             .help("Shared secret clients may authenticate with"))
+        .arg(Arg::with_name("credentials-file")
+             .long("credentials-file")
+             .value_name("FILE")
+             .help("File of user names and password hashes clients may authenticate with"))
+        .arg(Arg::with_name("acl-file")
+             .long("acl-file")
+             .value_name("FILE")
+             .help("TOML file of rules on who may subscribe and publish to what. \
+                    Reloaded on SIGHUP"))
+        .arg(Arg::with_name("tls-cert")
+             .long("tls-cert")
+             .value_name("FILE")
+             .help("PEM file of the certificate chain to serve clients with over TLS"))
+        .arg(Arg::with_name("tls-key")
+             .long("tls-key")
+             .value_name("FILE")
+             .help("PEM file of the private key of the TLS certificate"))
+        .arg(Arg::with_name("tls-client-ca")
+             .long("tls-client-ca")
+             .value_name("FILE")
+             .help("PEM file of the CA certificates that client certificates must be \
+                    signed by. Clients need no certificate without it"))
+        .arg(Arg::with_name("topic-log-dir")
+             .long("topic-log-dir")
+             .value_name("DIR")
+             .help("Directory to log published events to, per topic, so that subscribers \
+                    can replay them from an offset"))
+        .arg(Arg::with_name("topic-log-segment-size")
+             .long("topic-log-segment-size")
+             .value_name("BYTES")
+             .help("Size a topic log file grows to before a new one is started"))
+        .arg(Arg::with_name("topic-log-max-segments")
+             .long("topic-log-max-segments")
+             .value_name("N")
+             .help("Number of log files kept per topic, the oldest being deleted"))
+        .arg(Arg::with_name("topic-log-max-open")
+             .long("topic-log-max-open")
+             .value_name("N")
+             .help("Number of topic logs kept open at once, the ones used least recently \
+                    being closed"))
+        .arg(Arg::with_name("topic-log-sync")
+             .long("topic-log-sync")
+             .value_name("WHEN")
+             .possible_values(&["always", "periodic", "never"])
+             .help("When logged events are synced to disk. With always (the default), \
+                    publishes are only acked once the event is on disk"))
+        .arg(Arg::with_name("topic-log-sync-interval")
+             .long("topic-log-sync-interval")
+             .value_name("MILLISECONDS")
+             .help("Time between syncs of the topic logs to disk when they are synced \
+                    periodically"))
+        .arg(Arg::with_name("log-level")
+             .long("log-level")
+             .value_name("LEVEL")
+             .help("One of off, error, warn, info, debug and trace"))
+}
+
+fn parse_addresses<'a, I>(setting: &'static str, addresses: I)
+                          -> Result<Vec<SocketAddr>, ConfigError>
+    where I: Iterator<Item=&'a str> {
+    addresses.map(|address| {
+        address.parse()
+            .map_err(|_| ConfigError::InvalidValue(setting, address.to_string()))
+    }).collect()
+}
+
+fn parse_mode(mode: &str) -> Result<u32, ConfigError> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(bits) if bits <= 0o777 => Ok(bits),
+        _ => Err(ConfigError::InvalidValue("Unix socket mode", mode.to_string()))
+    }
+}
+
+fn parse_log_level(level: &str) -> Result<LevelFilter, ConfigError> {
+    level.parse()
+        .map_err(|_| ConfigError::InvalidValue("log level", level.to_string()))
+}
+
+// The value of a flag, with errors naming the setting it is for
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str, setting: &'static str)
+                         -> Result<Option<T>, ConfigError> {
+    match matches.value_of(name) {
+        Some(value) => value.parse()
+            .map(Some)
+            .map_err(|_| ConfigError::InvalidValue(setting, value.to_string())),
+        None => Ok(None)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Config, ConfigError, SlowConsumerPolicy, TopicLogSync};
+    use log::LevelFilter;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::from_args(vec!["pubsub-server"]).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_args() {
+        let config = Config::from_args(vec![
+            "pubsub-server", "--bind", "127.0.0.1:1234", "-b", "[::1]:1234",
+            "--max-connections", "10", "--max-queued-events", "100", "--log-level", "DEBUG",
+            "--slow-consumer-policy", "drop-oldest", "--shutdown-timeout", "30", "-w", "4",
+            "--auth-token", "t0ken", "--credentials-file", "/etc/pubsub/users",
+            "--acl-file", "/etc/pubsub/acl.toml", "--tls-cert", "cert.pem", "--tls-key", "key.pem",
+            "--tls-client-ca", "ca.pem"
+        ]).unwrap();
+        assert_eq!(config.bind_addresses, vec!["127.0.0.1:1234".parse().unwrap(),
+                                               "[::1]:1234".parse().unwrap()]);
+        assert_eq!(config.max_connections, 10);
+        assert_eq!(config.max_queued_events, Some(100));
+        assert_eq!(config.max_queued_bytes, None);
+        assert_eq!(config.slow_consumer_policy, SlowConsumerPolicy::DropOldest);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.auth_token, Some("t0ken".to_string()));
+        assert_eq!(config.credentials_file, Some(PathBuf::from("/etc/pubsub/users")));
+        assert_eq!(config.acl_file, Some(PathBuf::from("/etc/pubsub/acl.toml")));
+        assert_eq!(config.tls_certificate, Some(PathBuf::from("cert.pem")));
+        assert_eq!(config.tls_key, Some(PathBuf::from("key.pem")));
+        assert_eq!(config.tls_client_ca, Some(PathBuf::from("ca.pem")));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let config = Config::from_args(vec![
+            "pubsub-server", "--unix-socket", "/run/pubsub.sock", "--unix-socket-mode", "660"
+        ]).unwrap();
+        assert_eq!(config.unix_socket, Some(PathBuf::from("/run/pubsub.sock")));
+        assert_eq!(config.unix_socket_mode, Some(0o660));
+        // Only the socket is listened on, unless addresses are given too
+        assert!(config.bind_addresses.is_empty());
+        let config = Config::from_args(vec![
+            "pubsub-server", "--unix-socket", "/run/pubsub.sock", "-b", "127.0.0.1:1234"
+        ]).unwrap();
+        assert_eq!(config.bind_addresses, vec!["127.0.0.1:1234".parse().unwrap()]);
+
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--unix-socket", "/run/pubsub.sock",
+                                          "--unix-socket-mode", "rw"]),
+                   Err(ConfigError::InvalidValue("Unix socket mode", "rw".to_string())));
+        assert!(Config::from_args(vec!["pubsub-server", "--unix-socket-mode", "600"]).is_err());
+        assert!(Config::from_args(vec!["pubsub-server", "--unix-socket", "/run/pubsub.sock",
+                                       "--unix-socket-mode", "1777"]).is_err());
+    }
+
+    #[test]
+    fn test_websocket() {
+        let config = Config::from_args(vec![
+            "pubsub-server", "--websocket", "127.0.0.1:8080", "--websocket", "[::1]:8080"
+        ]).unwrap();
+        assert_eq!(config.websocket_addresses, vec!["127.0.0.1:8080".parse().unwrap(),
+                                                    "[::1]:8080".parse().unwrap()]);
+        // Only WebSocket connections are accepted, unless addresses are given too
+        assert!(config.bind_addresses.is_empty());
+
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--websocket", "localhost"]),
+                   Err(ConfigError::InvalidValue("WebSocket address", "localhost".to_string())));
+        assert!(Config::from_args(vec!["pubsub-server", "-b", "127.0.0.1:1",
+                                       "--websocket", "127.0.0.1:1"]).is_err());
+    }
+
+    #[test]
+    fn test_topic_log() {
+        let config = Config::from_args(vec![
+            "pubsub-server", "--topic-log-dir", "/var/lib/pubsub", "--topic-log-segment-size", "4096",
+            "--topic-log-max-segments", "8", "--topic-log-max-open", "16",
+            "--topic-log-sync", "periodic",
+            "--topic-log-sync-interval", "100"
+        ]).unwrap();
+        assert_eq!(config.topic_log_dir, Some(PathBuf::from("/var/lib/pubsub")));
+        assert_eq!(config.topic_log_segment_size, 4096);
+        assert_eq!(config.topic_log_max_segments, Some(8));
+        assert_eq!(config.topic_log_max_open, 16);
+        assert_eq!(config.topic_log_sync, TopicLogSync::Periodic);
+        assert_eq!(config.topic_log_sync_interval, Duration::from_millis(100));
+
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--topic-log-dir", "log",
+                                          "--topic-log-segment-size", "0"]),
+                   Err(ConfigError::InvalidValue("topic log segment size", "0".to_string())));
+        assert!(Config::from_args(vec!["pubsub-server", "--topic-log-max-segments", "8"]).is_err());
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--topic-log-max-open", "0"]),
+                   Err(ConfigError::InvalidValue("topic log max open", "0".to_string())));
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--topic-log-sync-interval", "0"]),
+                   Err(ConfigError::InvalidValue("topic log sync interval", "0".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--max-connections", "many"]),
+                   Err(ConfigError::InvalidValue("max connections", "many".to_string())));
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--max-connections", "0"]),
+                   Err(ConfigError::InvalidValue("max connections", "0".to_string())));
+        assert_eq!(Config::from_args(vec!["pubsub-server", "-b", "localhost"]),
+                   Err(ConfigError::InvalidValue("bind address", "localhost".to_string())));
+        assert!(Config::from_args(vec!["pubsub-server", "-b", "127.0.0.1:1", "-b", "127.0.0.1:1"])
+                .is_err());
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--workers", "0"]),
+                   Err(ConfigError::InvalidValue("workers", "0".to_string())));
+        assert_eq!(Config::from_args(vec!["pubsub-server", "--auth-token", ""]),
+                   Err(ConfigError::InvalidValue("auth token", "none given".to_string())));
+        assert!(Config::from_args(vec!["pubsub-server", "--tls-cert", "cert.pem"]).is_err());
+        assert!(Config::from_args(vec!["pubsub-server", "--tls-client-ca", "ca.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_args() {
+        match Config::from_args(vec!["pubsub-server", "--max-conections", "10"]) {
+            Err(ConfigError::Arguments(message)) => assert!(message.contains("--max-conections")),
+            result => panic!("Unexpected result {:?}", result)
+        }
+        match Config::from_args(vec!["pubsub-server", "--help"]) {
+            Err(ConfigError::Help(text)) => assert!(text.contains("--max-connections")),
+            result => panic!("Unexpected result {:?}", result)
+        }
+    }
+
+    #[test]
+    fn test_toml() {
+        let mut config = Config::default();
+        config.merge_toml(r#"
+            bind = ["0.0.0.0:9876"]
+            unix_socket = "pubsub.sock"
+            unix_socket_mode = "600"
+            websocket = ["0.0.0.0:8080"]
+            max_frame_size = 1024
+            shutdown_timeout = 0
+            workers = 2
+            log_level = "warn"
+
+            [client_queue]
+            max_bytes = 4096
+            policy = "drop-newest"
+
+            [auth]
+            credentials_file = "users"
+            acl_file = "acl.toml"
+
+            [tls]
+            certificate = "cert.pem"
+            key = "key.pem"
+
+            [topic_log]
+            dir = "log"
+            max_segments = 4
+            sync = "never"
+        "#).unwrap();
+        assert_eq!(config.bind_addresses, vec!["0.0.0.0:9876".parse().unwrap()]);
+        assert_eq!(config.unix_socket, Some(PathBuf::from("pubsub.sock")));
+        assert_eq!(config.unix_socket_mode, Some(0o600));
+        assert_eq!(config.websocket_addresses, vec!["0.0.0.0:8080".parse().unwrap()]);
+        assert_eq!(config.max_connections, super::DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.max_frame_size, 1024);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(0));
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.max_queued_bytes, Some(4096));
+        assert_eq!(config.slow_consumer_policy, SlowConsumerPolicy::DropNewest);
+        assert_eq!(config.auth_token, None);
+        assert_eq!(config.credentials_file, Some(PathBuf::from("users")));
+        assert_eq!(config.acl_file, Some(PathBuf::from("acl.toml")));
+        assert_eq!(config.tls_certificate, Some(PathBuf::from("cert.pem")));
+        assert_eq!(config.tls_client_ca, None);
+        assert_eq!(config.topic_log_dir, Some(PathBuf::from("log")));
+        assert_eq!(config.topic_log_segment_size, super::DEFAULT_TOPIC_LOG_SEGMENT_SIZE);
+        assert_eq!(config.topic_log_max_segments, Some(4));
+        assert_eq!(config.topic_log_sync, TopicLogSync::Never);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_invalid_toml() {
+        let mut config = Config::default();
+        assert!(config.merge_toml("max_conections = 10").is_err());
+        assert!(config.merge_toml("max_connections = \"10\"").is_err());
+        assert_eq!(config.merge_toml("log_level = \"loud\""),
+                   Err(ConfigError::InvalidValue("log level", "loud".to_string())));
+        assert_eq!(config.merge_toml("[client_queue]\npolicy = \"block\""),
+                   Err(ConfigError::InvalidValue("slow consumer policy", "block".to_string())));
+        assert_eq!(config.merge_toml("[topic_log]\nsync = \"sometimes\""),
+                   Err(ConfigError::InvalidValue("topic log sync", "sometimes".to_string())));
+    }
+}
@@ -0,0 +1,67 @@
+use std::ops::{Index, IndexMut};
+
+use mio::Token;
+use slab::Slab;
+
+use client::PubsubClient;
+
+// The clients of a worker, by token. The tokens start after the ones
+// used for the worker's listeners.
+pub struct Connections {
+    clients: Slab<PubsubClient>,
+    first_token: usize
+}
+
+impl Connections {
+    pub fn new(first_token: Token, capacity: usize) -> Connections {
+        Connections {
+            clients: Slab::with_capacity(capacity),
+            first_token: first_token.0
+        }
+    }
+
+    fn key(&self, token: Token) -> Option<usize> {
+        token.0.checked_sub(self.first_token)
+    }
+
+    pub fn insert_with<F>(&mut self, client: F) -> Token
+        where F: FnOnce(Token) -> PubsubClient {
+        let entry = self.clients.vacant_entry();
+        let token = Token(entry.key() + self.first_token);
+        entry.insert(client(token));
+        token
+    }
+
+    pub fn contains(&self, token: Token) -> bool {
+        self.key(token).is_some_and(|key| self.clients.contains(key))
+    }
+
+    pub fn remove(&mut self, token: Token) -> PubsubClient {
+        let key = self.key(token).expect("Not a client token");
+        self.clients.remove(key)
+    }
+
+    pub fn count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn tokens(&self) -> Vec<Token> {
+        self.clients.iter().map(|(key, _)| Token(key + self.first_token)).collect()
+    }
+}
+
+impl Index<Token> for Connections {
+    type Output = PubsubClient;
+
+    fn index(&self, token: Token) -> &PubsubClient {
+        let key = self.key(token).expect("Not a client token");
+        &self.clients[key]
+    }
+}
+
+impl IndexMut<Token> for Connections {
+    fn index_mut(&mut self, token: Token) -> &mut PubsubClient {
+        let key = self.key(token).expect("Not a client token");
+        &mut self.clients[key]
+    }
+}
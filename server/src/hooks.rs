@@ -0,0 +1,21 @@
+use stream::PeerAddress;
+
+// Callbacks for applications embedding the server. They are called on the
+// server's event loop thread, so no other client is served while one runs
+// and they should return quickly.
+pub trait Hooks: Send {
+    fn on_connect(&mut self, _peer: &PeerAddress) {}
+
+    fn on_disconnect(&mut self, _peer: &PeerAddress) {}
+
+    fn on_subscribe(&mut self, _peer: &PeerAddress, _pattern: &str) {}
+
+    fn on_unsubscribe(&mut self, _peer: &PeerAddress, _pattern: &str) {}
+
+    fn on_publish(&mut self, _peer: &PeerAddress, _event: &str, _payload: &[u8]) {}
+}
+
+// The hooks used when none are given
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
@@ -0,0 +1,42 @@
+extern crate pubsub;
+extern crate bytes;
+extern crate mio;
+extern crate slab;
+extern crate clap;
+extern crate toml;
+extern crate sha2;
+extern crate rustls;
+extern crate tungstenite;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate log;
+
+mod server;
+pub use server::{AclHandle, ShutdownHandle, Stats};
+
+mod subscriptions;
+mod retained;
+mod topic_log;
+
+mod client;
+mod connections;
+mod pending_event;
+
+mod stream;
+pub use stream::PeerAddress;
+mod listener;
+mod tls;
+mod websocket;
+
+mod config;
+pub use config::{Config, ConfigError, SlowConsumerPolicy, TopicLogSync};
+
+mod hooks;
+pub use hooks::{Hooks, NoHooks};
+
+mod auth;
+mod acl;
+
+mod builder;
+pub use builder::{ServerBuilder, ServerHandle};
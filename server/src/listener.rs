@@ -0,0 +1,117 @@
+use mio::event::Source;
+use mio::net::{TcpListener, UnixListener};
+use mio::{Interest, Registry, Token};
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use stream::{PeerAddress, Stream};
+
+// A socket clients connect to
+pub enum Listener {
+    Tcp(TcpListener),
+    // Clients upgrade their connections to WebSocket
+    WebSocket(TcpListener),
+    // The socket file is removed once the listener is dropped
+    Unix(UnixListener, PathBuf)
+}
+
+impl Listener {
+    // Listen on a Unix socket at the given path, with the given permissions
+    // if any. The socket is created under another name and then moved into
+    // place, so that it can't be connected to before its permissions are set.
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+        try!(remove_stale_socket(path));
+        let listener = match mode {
+            Some(mode) => {
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                let temporary = path.with_file_name(format!(".{}.{}", file_name, process::id()));
+                let _ = fs::remove_file(&temporary);
+                let listener = try!(UnixListener::bind(&temporary));
+                let result = fs::set_permissions(&temporary, fs::Permissions::from_mode(mode))
+                    .and_then(|_| fs::rename(&temporary, path));
+                if let Err(e) = result {
+                    let _ = fs::remove_file(&temporary);
+                    return Err(e);
+                }
+                listener
+            },
+            None => try!(UnixListener::bind(path))
+        };
+        Ok(Listener::Unix(listener, path.to_path_buf()))
+    }
+
+    pub fn accept(&self) -> io::Result<(Stream, PeerAddress)> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                let (socket, address) = try!(listener.accept());
+                Ok((Stream::Tcp(socket), PeerAddress::Tcp(address)))
+            },
+            Listener::WebSocket(ref listener) => {
+                let (socket, address) = try!(listener.accept());
+                Ok((Stream::Tcp(socket), PeerAddress::WebSocket(address)))
+            },
+            Listener::Unix(ref listener, _) => {
+                let (socket, address) = try!(listener.accept());
+                let path = address.as_pathname().map(|path| path.to_path_buf());
+                Ok((Stream::Unix(socket), PeerAddress::Unix(path)))
+            }
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match *self {
+            Listener::Tcp(ref mut listener) | Listener::WebSocket(ref mut listener) => listener,
+            Listener::Unix(ref mut listener, _) => listener
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
+                -> io::Result<()> {
+        self.source().register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
+                  -> io::Result<()> {
+        self.source().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, ref path) = *self {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove Unix socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// A socket file left behind by a server that didn't stop cleanly would keep
+// the socket from being created. It is only removed if nothing listens on it.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  "a file that isn't a socket is in the way"));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                  "another server is listening on it"));
+    }
+    fs::remove_file(path)
+}
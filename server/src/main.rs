@@ -1,23 +1,101 @@
-extern crate pubsub;
-extern crate mio;
+extern crate pubsub_server;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate signal_hook;
 
-mod server;
-use server::{PubsubServer, SERVER_TOKEN};
+use pubsub_server::{AclHandle, Config, ConfigError, ServerBuilder, ShutdownHandle};
 
-mod subscriptions;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::low_level::pipe;
 
-mod client;
-mod pending_event;
+use std::env;
+use std::io::{self, Read};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::thread;
 
-use mio::{EventLoop, EventSet, PollOpt};
-use mio::tcp::TcpListener;
 
+// Have a byte written to the returned socket for every one of the signals.
+// Unlike signal_hook's iterator, this doesn't merge signals received in
+// quick succession, so that a second one is never missed.
+fn install_signal_handlers(signals: &[i32]) -> io::Result<UnixStream> {
+    let (receiver, sender) = try!(UnixStream::pair());
+    for &signal in signals {
+        try!(pipe::register(signal, try!(sender.try_clone())));
+    }
+    Ok(receiver)
+}
+
+// Shut the server down gracefully on SIGINT or SIGTERM.
+// A second signal stops it without waiting for clients to be flushed.
+fn handle_signals(mut signals: UnixStream, shutdown: ShutdownHandle) {
+    thread::spawn(move || {
+        let mut byte = [0];
+        while signals.read(&mut byte).is_ok_and(|len| len > 0) {
+            info!("Received shutdown signal");
+            if let Err(e) = shutdown.shutdown() {
+                error!("Failed to shut down: {}", e);
+            }
+        }
+    });
+}
+
+// Reload the ACL file on SIGHUP. The rules in use are kept if it can't be read.
+fn handle_reload_signals(mut signals: UnixStream, acl: AclHandle) {
+    thread::spawn(move || {
+        let mut byte = [0];
+        while signals.read(&mut byte).is_ok_and(|len| len > 0) {
+            info!("Received reload signal, reloading ACL");
+            if let Err(e) = acl.reload() {
+                error!("Failed to reload ACL: {}", e);
+            }
+        }
+    });
+}
+
+fn run(config: Config) -> Result<(), String> {
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .init();
+
+    // Install the signal handlers before anything is listening, so that
+    // the server can always be shut down gracefully once it accepts clients
+    let signals = try!(install_signal_handlers(&[SIGINT, SIGTERM])
+                       .map_err(|e| format!("couldn't install signal handlers: {}", e)));
+    let reload_signals = try!(install_signal_handlers(&[SIGHUP])
+                              .map_err(|e| format!("couldn't install signal handlers: {}", e)));
+
+    let server = try!(ServerBuilder::from_config(config).start()
+                      .map_err(|e| e.to_string()));
+    handle_signals(signals, server.shutdown_handle());
+    handle_reload_signals(reload_signals, server.acl_handle());
+    try!(server.wait()
+         .map_err(|e| format!("event loop failed: {}", e)));
+    info!("Server stopped");
+    Ok(())
+}
 
 fn main() {
-    let bind_address = "127.0.0.1:9876".parse().unwrap();
-    let listener = TcpListener::bind(&bind_address).unwrap();
+    let config = match Config::from_args(env::args_os()) {
+        Ok(config) => config,
+        Err(ConfigError::Help(text)) => {
+            println!("{}", text);
+            return;
+        },
+        // Already formatted by clap, usage included
+        Err(ConfigError::Arguments(message)) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("pubsub-server: {}", e);
+            process::exit(1);
+        }
+    };
 
-    let mut event_loop = EventLoop::new().unwrap();
-    event_loop.register(&listener, SERVER_TOKEN, EventSet::readable(), PollOpt::edge()).unwrap();
-    event_loop.run(&mut PubsubServer::new(listener)).unwrap();
+    if let Err(e) = run(config) {
+        eprintln!("pubsub-server: {}", e);
+        process::exit(1);
+    }
 }
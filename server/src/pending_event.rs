@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use bytes::Bytes;
 use mio;
 
 pub type EventId = mio::Token;
@@ -17,7 +18,9 @@ impl PendingEvents {
         }
     }
 
-    pub fn add_event(&mut self, data: Vec<u8>, recipients: usize) -> EventId {
+    // The data is shared, not copied, so the same encoded event can be
+    // added for several clients
+    pub fn add_event(&mut self, data: Bytes, recipients: usize) -> EventId {
         let event_id = mio::Token(self.id_counter);
         // This is fairly ugly, but wrapping around after sizeof(usize) events
         // is very unlikely to cause issues, and it's better to be explicit
@@ -44,11 +47,11 @@ impl PendingEvents {
 
 struct Event {
     remaining_recipients: usize,
-    data: Vec<u8>
+    data: Bytes
 }
 
 impl Event {
-    fn new(data: Vec<u8>, recipients: usize) -> Event {
+    fn new(data: Bytes, recipients: usize) -> Event {
         if recipients == 0 {
             panic!("Recipients must be non-zero");
         }
@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use pubsub::message::SharedMessage;
+use pubsub::topic;
+
+// The last retained value per event name, kept as a complete Event message
+// so that it can be queued as is for new subscribers
+pub struct RetainedEvents {
+    event_map: HashMap<String, Bytes>
+}
+
+impl RetainedEvents {
+    pub fn new() -> RetainedEvents {
+        RetainedEvents {
+            event_map: HashMap::new()
+        }
+    }
+
+    // An empty payload clears the retained value
+    pub fn retain(&mut self, event_name: String, payload: Bytes) {
+        if payload.is_empty() {
+            self.event_map.remove(&event_name);
+            return;
+        }
+
+        let message = SharedMessage::event(event_name.clone(), payload, true);
+        self.event_map.insert(event_name, message.to_bytes());
+    }
+
+    // Retained Event messages for all event names matching the pattern
+    pub fn matching(&self, pattern: &str) -> Vec<Bytes> {
+        self.event_map.iter()
+            .filter(|&(event_name, _)| topic::matches(pattern, event_name))
+            .map(|(_, data)| data.clone())
+            .collect()
+    }
+}
@@ -1,155 +1,1104 @@
-use mio::tcp::{TcpListener};
-use mio::util::Slab;
-use mio::{EventSet, PollOpt};
+use mio::event::Event;
+use mio::{Events, Interest, Poll, Waker};
 use mio;
 
-use pubsub::message::{MessageType, MessageBuilder};
+use bytes::Bytes;
+use rustls::ServerConfig;
 
-use client::{PubsubClient, ClientAction};
+use std::cmp;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
-use subscriptions::{SubscriptionMap, ClientMap};
-use pending_event::PendingEvents;
+use pubsub::message::{Message, SharedMessage, ErrorCode, OFFSET_LATEST};
+use pubsub::handshake::Handshake;
 
+use client::{PubsubClient, ClientAction, QueueResult};
+use connections::Connections;
 
-pub const SERVER_TOKEN: mio::Token = mio::Token(0);
+use subscriptions::SubscriptionMap;
+use retained::RetainedEvents;
+use pending_event::{EventId, PendingEvents};
+use config::Config;
+use hooks::Hooks;
+use auth::Authenticator;
+use acl::{Access, Acl};
+use listener::Listener;
+use stream::{PeerAddress, Stream};
+use tls::TlsStream;
+use topic_log::TopicLogs;
 
-pub type EventLoop = mio::EventLoop<PubsubServer>;
 
+// Wakes a worker up when there is something in its inbox
+const WAKER_TOKEN: mio::Token = mio::Token(usize::MAX);
+
+// Most readiness events handled per poll
+const EVENTS_CAPACITY: usize = 1024;
+
+// Most events, and bytes of them, read from a topic log at once for a client
+// catching up on it. The next ones are read once they have been written.
+const REPLAY_BATCH_EVENTS: usize = 256;
+const REPLAY_BATCH_BYTES: usize = 256 * 1024;
+
+// Work handed to a worker by other threads
+pub enum WorkerMessage {
+    // An accepted connection for the worker to serve
+    Connection(Stream, PeerAddress),
+    // Events published on another worker, for the worker's subscribers.
+    // Logged events are passed on through the worker's logged events instead.
+    Events(Vec<EncodedEvent>),
+    // The ACL has been replaced. Subscriptions it no longer allows are dropped.
+    AclReloaded,
+    // Stop accepting connections, flush what is queued for the clients
+    // (up to the shutdown timeout), then stop.
+    // Sending it again while shutting down stops the worker right away.
+    Shutdown
+}
+
+// A published event, encoded once for all of its subscribers
+#[derive(Clone)]
+pub struct EncodedEvent {
+    name: String,
+    message_data: Bytes,
+    // The event's offset in the topic log, and the message carrying it for
+    // the clients that negotiated offsets. None if topics aren't logged.
+    logged: Option<(u64, Bytes)>
+}
+
+impl EncodedEvent {
+    fn new(name: &str, payload: Bytes, offset: Option<u64>) -> EncodedEvent {
+        let message = SharedMessage::event(name.to_string(), payload, false);
+        let message_data = message.to_bytes();
+        let logged = offset.map(|offset| {
+            let mut message = message;
+            message.header.offset = Some(offset);
+            (offset, message.to_bytes())
+        });
+        EncodedEvent {
+            name: name.to_string(),
+            message_data,
+            logged
+        }
+    }
+}
+
+// A way to hand work to a worker
+#[derive(Clone)]
+pub struct Worker {
+    inbox: mpsc::Sender<WorkerMessage>,
+    waker: Arc<Waker>,
+    // Events logged on other workers, in offset order for each topic.
+    // They are queued while the topic's log is locked, so that is kept cheap,
+    // and the worker is only woken up for them once the publisher's
+    // worker is done reading.
+    logged_events: Arc<Mutex<Vec<EncodedEvent>>>
+}
+
+impl Worker {
+    pub fn new(poll: &Poll, inbox: mpsc::Sender<WorkerMessage>) -> io::Result<Worker> {
+        let waker = try!(Waker::new(poll.registry(), WAKER_TOKEN));
+        Ok(Worker {
+            inbox,
+            waker: Arc::new(waker),
+            logged_events: Arc::new(Mutex::new(Vec::new()))
+        })
+    }
+
+    // Messages for a worker that has stopped are dropped
+    fn send(&self, message: WorkerMessage) -> io::Result<()> {
+        if self.inbox.send(message).is_ok() {
+            try!(self.wake());
+        }
+        Ok(())
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        self.waker.wake()
+    }
+
+    // Queue a logged event without waking the worker up
+    fn push_logged_event(&self, event: EncodedEvent) {
+        self.logged_events.lock().unwrap().push(event);
+    }
+
+    fn take_logged_events(&self) -> Vec<EncodedEvent> {
+        mem::take(&mut *self.logged_events.lock().unwrap())
+    }
+}
+
+// Lets other threads ask a running server to shut down
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    workers: Vec<Worker>
+}
+
+impl ShutdownHandle {
+    pub fn new(workers: &[Worker]) -> ShutdownHandle {
+        ShutdownHandle {
+            workers: workers.to_vec()
+        }
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        for worker in &self.workers {
+            try!(worker.send(WorkerMessage::Shutdown)
+                 .map_err(|e| io::Error::other(format!("couldn't notify server: {}", e))));
+        }
+        Ok(())
+    }
+}
+
+// Lets other threads reload the ACL file of a running server
+#[derive(Clone)]
+pub struct AclHandle {
+    path: Option<PathBuf>,
+    shared: Arc<Shared>,
+    workers: Vec<Worker>
+}
+
+impl AclHandle {
+    pub fn new(path: Option<PathBuf>, shared: Arc<Shared>, workers: &[Worker]) -> AclHandle {
+        AclHandle {
+            path,
+            shared,
+            workers: workers.to_vec()
+        }
+    }
+
+    // Read the ACL file again. The rules apply to the requests made from
+    // then on, and to the existing subscriptions. If the file can't be read,
+    // the rules in use are kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let acl = try!(Acl::load(self.path.as_deref())
+                       .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())));
+        *self.shared.acl.write().unwrap() = acl;
+        for worker in &self.workers {
+            try!(worker.send(WorkerMessage::AclReloaded));
+        }
+        Ok(())
+    }
+}
+
+// State shared by all the workers of a server
+pub struct Shared {
+    // Number of clients connected to any worker
+    connections: AtomicUsize,
+    retained_events: Mutex<RetainedEvents>,
+    hooks: Mutex<Box<dyn Hooks>>,
+    auth: Authenticator,
+    acl: RwLock<Acl>,
+    // Set when clients connect with TLS
+    tls: Option<Arc<ServerConfig>>,
+    // How slow consumers have been dealt with, by all workers
+    stats: StatsCounters,
+    // Set when published events are logged
+    topic_logs: Option<Arc<TopicLogs>>
+}
+
+impl Shared {
+    pub fn new(hooks: Box<dyn Hooks>, auth: Authenticator, acl: Acl,
+               tls: Option<Arc<ServerConfig>>, topic_logs: Option<Arc<TopicLogs>>) -> Shared {
+        Shared {
+            connections: AtomicUsize::new(0),
+            retained_events: Mutex::new(RetainedEvents::new()),
+            hooks: Mutex::new(hooks),
+            auth,
+            acl: RwLock::new(acl),
+            tls,
+            stats: StatsCounters::default(),
+            topic_logs
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.stats()
+    }
+}
+
+// Counters of how slow consumers have been dealt with, since the server started
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub dropped_events: usize,
+    pub disconnected_slow_consumers: usize
+}
+
+// The counters behind Stats, updated by the server and read through its handle
+#[derive(Default)]
+pub struct StatsCounters {
+    dropped_events: AtomicUsize,
+    disconnected_slow_consumers: AtomicUsize
+}
+
+impl StatsCounters {
+    pub fn stats(&self) -> Stats {
+        Stats {
+            dropped_events: self.dropped_events.load(Ordering::SeqCst),
+            disconnected_slow_consumers: self.disconnected_slow_consumers.load(Ordering::SeqCst)
+        }
+    }
+}
+
+// The connection table starts out with room for this many clients,
+// and is grown as needed. The configured maximum applies to all workers
+// together, and is enforced when accepting connections.
+const INITIAL_CONNECTIONS: usize = 128;
+
+// Clients over the connection limit that a worker keeps around at most,
+// to tell them why once their TLS handshake is done. Any more are just
+// disconnected.
+const MAX_REFUSED_CLIENTS: usize = 64;
+
+// One of the workers of a server, serving its share of the clients on its
+// own poll loop. The first worker also accepts the connections, and hands
+// them out to all workers in turn. Events published on a worker are passed
+// on to all other workers, for their subscribers.
 pub struct PubsubServer {
-    socket: TcpListener,
-    connections: Slab<PubsubClient>,
+    poll: Poll,
+    // Cleared to stop the poll loop
+    running: bool,
+    // Listeners use the tokens from 0 up to the number of listeners,
+    // and connections the ones after that
+    listeners: Vec<Listener>,
+    first_client_token: mio::Token,
+    connections: Connections,
+    // Clients that may have more to read. The sockets are edge triggered,
+    // but each client is read from once per poll loop iteration, so that a
+    // busy client can't hold up the others.
+    unread: HashSet<mio::Token>,
+    // Clients with events queued, or closed, since their registration
+    // was last updated
+    unflushed: HashSet<mio::Token>,
+    // Clients catching up on topic logs
+    replaying: HashSet<mio::Token>,
     subscriptions: SubscriptionMap,
-    pending_events: PendingEvents
+    pending_events: PendingEvents,
+    config: Config,
+    shared: Arc<Shared>,
+    // All workers, this one included
+    workers: Vec<Worker>,
+    // This worker's index in workers
+    index: usize,
+    inbox: mpsc::Receiver<WorkerMessage>,
+    // Events to pass on to each of the other workers, sent in batches
+    // when flushing
+    outboxes: Vec<Vec<EncodedEvent>>,
+    // Workers with logged events queued since the last flush, to be woken up
+    unwoken: HashSet<usize>,
+    // The worker the next accepted connection is handed to
+    next_worker: usize,
+    // Clients being turned away, which aren't counted as connected
+    refused_clients: usize,
+    // Set once a shutdown has been requested
+    shutting_down: bool,
+    // When the clients still being flushed are disconnected
+    shutdown_deadline: Option<Instant>
 }
 
 impl PubsubServer {
-    pub fn new(socket: TcpListener) -> PubsubServer {
+    pub fn new(poll: Poll, listeners: Vec<Listener>, config: Config, shared: Arc<Shared>,
+               workers: Vec<Worker>, index: usize, inbox: mpsc::Receiver<WorkerMessage>)
+               -> PubsubServer {
+        let first_client_token = mio::Token(listeners.len());
+        let capacity = cmp::min(INITIAL_CONNECTIONS, config.max_connections);
+        let outboxes = workers.iter().map(|_| Vec::new()).collect();
         PubsubServer {
-            socket: socket,
-            connections: Slab::new_starting_at(mio::Token(1), 128),
+            poll,
+            running: true,
+            listeners,
+            first_client_token,
+            connections: Connections::new(first_client_token, capacity),
+            unread: HashSet::new(),
+            unflushed: HashSet::new(),
+            replaying: HashSet::new(),
             subscriptions: SubscriptionMap::new(),
-            pending_events: PendingEvents::new()
+            pending_events: PendingEvents::new(),
+            config,
+            shared,
+            workers,
+            index,
+            inbox,
+            outboxes,
+            unwoken: HashSet::new(),
+            next_worker: index,
+            refused_clients: 0,
+            shutting_down: false,
+            shutdown_deadline: None
         }
     }
 
-    fn on_client_connection(&mut self, event_loop: &mut EventLoop) {
-        let client_socket = match self.socket.accept() {
-            Err(e) => {
-                println!("{}", e);
-                return;
+    fn hooks(&self) -> MutexGuard<'_, Box<dyn Hooks>> {
+        self.shared.hooks.lock().unwrap()
+    }
+
+    pub fn register(&mut self) -> io::Result<()> {
+        for (i, listener) in self.listeners.iter_mut().enumerate() {
+            try!(self.poll.registry().register(listener, mio::Token(i), Interest::READABLE));
+        }
+        Ok(())
+    }
+
+    // Serve clients until shut down
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        while self.running {
+            // Don't wait for new events while there is still something to read
+            let timeout = if self.unread.is_empty() {
+                self.shutdown_deadline
+                    .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            }
+            else {
+                Some(Duration::from_secs(0))
+            };
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                if !self.running {
+                    break;
+                }
+                self.ready(event);
+            }
+            self.read_clients();
+            if self.shutdown_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.finish_shutdown();
+            }
+            self.flush();
+        }
+        Ok(())
+    }
+
+    fn ready(&mut self, event: &Event) {
+        match event.token() {
+            WAKER_TOKEN => {
+                // Logged events go first, as they were queued before any
+                // shutdown requested after their publishers were acked
+                let events = self.workers[self.index].take_logged_events();
+                self.deliver_events(events);
+                while let Ok(message) = self.inbox.try_recv() {
+                    self.on_worker_message(message);
+                }
             },
-            Ok(None) => {
-                println!("Couldn't accept connection (None)");
-                return;
+            token if token < self.first_client_token => {
+                // The listeners are gone once shutting down
+                if !self.shutting_down {
+                    self.on_client_connection(token.0);
+                }
+            },
+            token => {
+                // Events for the same poll may arrive for a client that has
+                // already been disconnected, e.g. as a slow consumer
+                if !self.connections.contains(token) {
+                    return;
+                }
+                if event.is_writable() {
+                    self.on_client_writable(token);
+                }
+                // The client may have been disconnected while writing
+                if (event.is_readable() || event.is_read_closed() || event.is_error()) &&
+                    self.connections.contains(token) {
+                    self.unread.insert(token);
+                }
+            }
+        }
+    }
+
+    fn on_client_connection(&mut self, listener: usize) {
+        // The listener is edge triggered, so accept until there are no more
+        // pending connections
+        loop {
+            let (client_socket, address) = match self.listeners[listener].accept() {
+                Ok(connection) => connection,
+                // No more pending connections
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    return;
+                }
+            };
+            if self.shared.connections.load(Ordering::SeqCst) >= self.config.max_connections {
+                warn!("Maximum number of connections reached. Rejecting client {}", address);
+                // TLS and WebSocket clients can only be told why once
+                // their handshake is done, so they are kept until then
+                let has_handshake = match (&client_socket, &address) {
+                    (_, &PeerAddress::WebSocket(_)) => true,
+                    (&Stream::Tcp(_), _) => self.shared.tls.is_some(),
+                    _ => false
+                };
+                if !has_handshake {
+                    reject_client(client_socket, ErrorCode::ServerFull);
+                }
+                else if self.refused_clients < MAX_REFUSED_CLIENTS {
+                    self.refuse_client(client_socket, address, ErrorCode::ServerFull);
+                }
+                continue;
+            }
+            self.shared.connections.fetch_add(1, Ordering::SeqCst);
+
+            let worker = self.next_worker;
+            self.next_worker = (self.next_worker + 1) % self.workers.len();
+            if worker == self.index {
+                self.add_client(client_socket, address);
             }
-            Ok(Some((socket, address))) => {
-                println!("Got a connection from {}", address);
-                socket
+            else {
+                self.send_to_worker(worker, WorkerMessage::Connection(client_socket, address));
+            }
+        }
+    }
+
+    // Start serving a client, which has already been counted as connected
+    fn add_client(&mut self, client_socket: Stream, address: PeerAddress) {
+        if self.shutting_down {
+            self.shared.connections.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+
+        let socket = match self.secure(client_socket) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Failed to set up TLS for client {}: {}", address, e);
+                self.shared.connections.fetch_sub(1, Ordering::SeqCst);
+                return;
             }
         };
+        let config = &self.config;
+        let token = self.connections.insert_with(|token| {
+            PubsubClient::new(socket, address, token, config)
+        });
+        info!("{}: connected", self.connections[token]);
+
+        if let Err(e) = self.connections[token].register(self.poll.registry()) {
+            error!("{}: failed to register with poll: {}", self.connections[token], e);
+            self.connections.remove(token);
+            self.shared.connections.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        self.hooks().on_connect(self.connections[token].peer_address());
+    }
 
+    // Keep a TLS or WebSocket client that is being turned away until it
+    // can be told why. It isn't counted as connected.
+    fn refuse_client(&mut self, client_socket: Stream, address: PeerAddress, code: ErrorCode) {
+        let socket = match self.secure(client_socket) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Failed to set up TLS for client {}: {}", address, e);
+                return;
+            }
+        };
+        let config = &self.config;
         let token = self.connections.insert_with(|token| {
-            PubsubClient::new(client_socket, token)
-        }).expect("Failed to insert new connection");
+            let mut client = PubsubClient::new(socket, address, token, config);
+            client.refuse(code);
+            client
+        });
+        if let Err(e) = self.connections[token].register(self.poll.registry()) {
+            error!("{}: failed to register with poll: {}", self.connections[token], e);
+            self.connections.remove(token);
+            return;
+        }
+        self.refused_clients += 1;
+    }
+
+    // TLS is only used over TCP, WebSocket connections included
+    fn secure(&self, client_socket: Stream) -> io::Result<Stream> {
+        match (client_socket, self.shared.tls.as_ref()) {
+            (Stream::Tcp(socket), Some(tls)) => {
+                let stream = try!(TlsStream::new(socket, tls.clone()));
+                Ok(Stream::Tls(Box::new(stream)))
+            },
+            (socket, _) => Ok(socket)
+        }
+    }
 
-        event_loop.register(self.connections[token].socket(), token,
-                            EventSet::readable(),
-                            PollOpt::edge() | PollOpt::oneshot())
-            .expect("Failed to register new connection with event loop");
+    fn read_clients(&mut self) {
+        let tokens = mem::take(&mut self.unread);
+        for token in tokens {
+            // The client may have been disconnected since. A closing client
+            // isn't read from any more.
+            if self.connections.contains(token) && !self.connections[token].is_closing() {
+                self.on_client_readable(token);
+            }
+        }
+    }
 
+    fn on_client_readable(&mut self, token: mio::Token) {
+        match self.connections[token].read() {
+            Some(action) => {
+                // Replies are queued for the requests read
+                self.unflushed.insert(token);
+                self.unread.insert(token);
+                self.on_client_action(token, action);
+            },
+            // Reading may have left TLS handshake messages to be written
+            None if self.connections[token].has_data_pending() => {
+                self.unflushed.insert(token);
+            },
+            None => {}
+        }
     }
 
-    fn on_client_readable(&mut self, event_loop: &mut EventLoop, token: mio::Token) {
-        let mut action = self.connections[token].read(event_loop);
+    fn on_client_action(&mut self, token: mio::Token, mut action: ClientAction) {
         // Might get more than one packet in a read, so loop
         // until there are no more complete packets
         loop {
             match action {
-                ClientAction::Nothing => {
-                    println!("No action. Break read loop");
-                    break;
+                ClientAction::Nothing => break,
+                ClientAction::Hello(hello) => {
+                    let client = &mut self.connections[token];
+                    debug!("{}: handshake with version {}", client, hello.version);
+                    match Handshake::new().negotiate(&hello) {
+                        Some(welcome) => {
+                            client.welcome(welcome, &mut self.pending_events);
+                        },
+                        None => {
+                            // Let the client know which version we speak, then hang up
+                            client.welcome(Handshake::new(), &mut self.pending_events);
+                            client.close();
+                            break;
+                        }
+                    }
                 },
-                ClientAction::Subscribe(event) => {
-                    println!("Subscribe to {}", event);
-                    let client_map = self.subscriptions.entry(event)
-                        .or_insert_with(ClientMap::new);
-                    client_map.insert(token);
+                ClientAction::Authenticate(user, secret) => {
+                    let client = &mut self.connections[token];
+                    // Credentials sent to a server that doesn't need them are ignored
+                    if !self.shared.auth.is_required() {
+                        client.ack(user, &mut self.pending_events);
+                    }
+                    else if self.shared.auth.authenticate(&user, &secret) {
+                        if user.is_empty() {
+                            info!("{}: authenticated with token", client);
+                            client.authenticate(None);
+                        }
+                        else {
+                            client.authenticate(Some(user.clone()));
+                            info!("{}: authenticated", client);
+                        }
+                        client.ack(user, &mut self.pending_events);
+                    }
+                    else {
+                        warn!("{}: authentication failed for user '{}'", client, user);
+                        client.reject(user, ErrorCode::AuthenticationFailed,
+                                      &mut self.pending_events);
+                        client.close();
+                        break;
+                    }
                 },
-                ClientAction::Unsubscribe(event) => {
-                    println!("Unsubscribe to {}", event);
-                    if let Some(client_map) = self.subscriptions.get_mut(&event) {
-                        client_map.remove(&token);
-                        // TODO: Remove key if no subscriptions left
+                ClientAction::Subscribe(ref event, _) if !self.allows(token, Access::Subscribe, event) => {
+                    self.deny(token, event.clone());
+                },
+                ClientAction::Subscribe(event, offset) => {
+                    if !self.subscribe(token, event, offset) {
+                        return;
                     }
                 },
-                ClientAction::Publish(event, payload) => {
-                    println!("Publish {} to {}", String::from_utf8(payload.clone()).unwrap(), event);
-                    if let Some(clients) = self.subscriptions.get(&event) {
-                        let mut builder = MessageBuilder::new();
-                        builder.message_type(MessageType::Event)
-                            .event_name(event)
-                            .payload(payload);
-                        let message_data = match builder.build() {
-                            Ok(msg) => msg.into_bytes(),
-                            Err(_) => break
-                        };
-
-                        let event_id = self.pending_events.add_event(message_data,
-                                                                     clients.len());
-                        for client_token in clients {
-                            self.connections[*client_token].publish(event_id, event_loop);
-                        }
+                ClientAction::Unsubscribe(event) => {
+                    debug!("{}: unsubscribe from {}", self.connections[token], event);
+                    self.hooks().on_unsubscribe(self.connections[token].peer_address(), &event);
+                    self.subscriptions.unsubscribe(&event, token);
+                    self.connections[token].unsubscribed(&event);
+                    self.connections[token].ack(event, &mut self.pending_events);
+                },
+                ClientAction::Publish(ref event, _, _) if !self.allows(token, Access::Publish, event) => {
+                    self.deny(token, event.clone());
+                },
+                ClientAction::Publish(event, payload, retain) => {
+                    debug!("{}: publish {} bytes to {}", self.connections[token], payload.len(), event);
+                    self.hooks().on_publish(self.connections[token].peer_address(), &event, &payload);
+                    if retain {
+                        self.shared.retained_events.lock().unwrap()
+                            .retain(event.clone(), payload.clone());
+                    }
+                    if !self.log_event(&event, &payload) {
+                        self.publish(&event, payload);
                     }
+                    // The publisher might have been one of the subscribers
+                    if !self.connections.contains(token) {
+                        return;
+                    }
+                    self.connections[token].ack(event, &mut self.pending_events);
+                },
+                ClientAction::Invalid(event, code) => {
+                    debug!("{}: invalid request for {}: {:?}", self.connections[token], event, code);
+                    self.connections[token].reject(event, code, &mut self.pending_events);
                 },
-                ClientAction::Error => {
-                    println!("Error!");
+                ClientAction::Error(code) => {
+                    let client = &mut self.connections[token];
+                    warn!("{}: protocol error: {:?}", client, code);
+                    client.reject(String::new(), code, &mut self.pending_events);
+                    client.close();
+                    break;
+                },
+                ClientAction::Refused(code) => {
+                    let client = &mut self.connections[token];
+                    debug!("{}: refused: {:?}", client, code);
+                    client.reject(String::new(), code, &mut self.pending_events);
+                    client.close();
+                    break;
+                },
+                ClientAction::Disconnected => {
                     self.disconnect_client(token);
                     break;
                 }
             }
-            action = self.connections[token].handle_read(0);
+            action = self.connections[token].next_action();
         }
     }
 
-    fn on_client_writable(&mut self, event_loop: &mut EventLoop, token: mio::Token) {
-        match self.connections[token].write(event_loop, &mut self.pending_events) {
-            Ok(_) => {},
-            Err(_) => { self.disconnect_client(token); }
+    // Subscribe a client, and queue the retained events matching the pattern.
+    // With an offset, the events logged from there on are queued instead.
+    // Returns whether the client is still connected.
+    fn subscribe(&mut self, token: mio::Token, event: String, offset: Option<u64>) -> bool {
+        let start = match offset {
+            Some(offset) => match self.replay_start(&event, offset) {
+                Some(start) => Some(start),
+                None => {
+                    let client = &mut self.connections[token];
+                    debug!("{}: can't subscribe to {} from offset {}", client, event, offset);
+                    client.reject(event, ErrorCode::InvalidOffset, &mut self.pending_events);
+                    return true;
+                }
+            },
+            None => None
         };
+        debug!("{}: subscribe to {}", self.connections[token], event);
+        self.hooks().on_subscribe(self.connections[token].peer_address(), &event);
+        self.subscriptions.subscribe(&event, token);
+        self.connections[token].subscribed(&event);
+        if let Some(start) = start {
+            self.connections[token].replay_from(&event, start);
+            self.connections[token].ack(event, &mut self.pending_events);
+            self.replaying.insert(token);
+            return self.replay(token);
+        }
+        let retained = self.shared.retained_events.lock().unwrap().matching(&event);
+        self.connections[token].ack(event, &mut self.pending_events);
+        for message_data in retained {
+            let event_len = message_data.len();
+            let event_id = self.pending_events.add_event(message_data, 1);
+            if !self.queue_event(token, event_id, event_len) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // The offset a subscription to the topic from the given one starts
+    // replaying its log at, or None if it can't be replayed from there.
+    // Events that are no longer kept are skipped.
+    fn replay_start(&self, topic: &str, offset: u64) -> Option<u64> {
+        let topic_logs = match self.shared.topic_logs {
+            Some(ref topic_logs) => topic_logs,
+            None => return None
+        };
+        match topic_logs.bounds(topic) {
+            Ok((_, end)) if offset == OFFSET_LATEST => Some(end),
+            Ok((_, end)) if offset > end => None,
+            Ok((earliest, _)) => Some(cmp::max(offset, earliest)),
+            Err(e) => {
+                error!("Failed to open the log of {}: {}", topic, e);
+                None
+            }
+        }
+    }
+
+    // Queue the next events of the topic logs a client is catching up on.
+    // Called again once they have been written, so that the logs are read
+    // no faster than the client takes the events. Returns whether the
+    // client is still connected.
+    fn replay(&mut self, token: mio::Token) -> bool {
+        let shared = self.shared.clone();
+        let topic_logs = match shared.topic_logs {
+            Some(ref topic_logs) => topic_logs,
+            None => return true
+        };
+        for (topic, next_offset) in self.connections[token].replaying() {
+            // The end is only looked up after reading, so that the client
+            // is never taken to have caught up on events it didn't get
+            let result = topic_logs.read(&topic, next_offset, REPLAY_BATCH_EVENTS, REPLAY_BATCH_BYTES)
+                .and_then(|events| topic_logs.bounds(&topic).map(|(_, end)| (events, end)));
+            let (events, end) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    // Carry on with the events published from now on
+                    error!("{}: failed to read the log of {}: {}", self.connections[token], topic, e);
+                    self.connections[token].replayed(&topic, next_offset, true);
+                    continue;
+                }
+            };
+            let next_offset = events.last().map_or(next_offset, |&(offset, _)| offset + 1);
+            self.connections[token].replayed(&topic, next_offset, next_offset >= end);
+            let with_offsets = self.connections[token].wants_offsets();
+            for (offset, payload) in events {
+                let mut message = SharedMessage::event(topic.clone(), payload, false);
+                if with_offsets {
+                    message.header.offset = Some(offset);
+                }
+                let message_data = message.to_bytes();
+                let event_len = message_data.len();
+                let event_id = self.pending_events.add_event(message_data, 1);
+                if !self.queue_event(token, event_id, event_len) {
+                    return false;
+                }
+            }
+        }
+        if self.connections[token].replaying().is_empty() {
+            self.replaying.remove(&token);
+        }
+        true
+    }
+
+    // Append a published event to its topic's log, if topics are logged,
+    // and queue it for the subscribers on every worker. It is queued for the
+    // other workers before the log is unlocked, after the events this worker
+    // got from them, so that each worker gets the topic's events in offset
+    // order, whichever workers they were published on. The publisher is acked
+    // after this, so with TopicLogSync::Always, acked events are on disk.
+    // Returns whether the event was logged.
+    fn log_event(&mut self, event: &str, payload: &Bytes) -> bool {
+        let topic_logs = match self.shared.topic_logs {
+            Some(ref topic_logs) => topic_logs.clone(),
+            None => return false
+        };
+        let mut events = Vec::new();
+        let result = topic_logs.append(event, payload, |offset| {
+            events = self.workers[self.index].take_logged_events();
+            let encoded = EncodedEvent::new(event, payload.clone(), Some(offset));
+            for (i, worker) in self.workers.iter().enumerate() {
+                if i != self.index {
+                    worker.push_logged_event(encoded.clone());
+                    self.unwoken.insert(i);
+                }
+            }
+            events.push(encoded);
+        });
+        if let Err(ref e) = result {
+            error!("Failed to log event {}: {}", event, e);
+        }
+        self.deliver_events(events);
+        result.is_ok()
+    }
+
+    fn deliver_events(&mut self, events: Vec<EncodedEvent>) {
+        for event in events {
+            let clients = self.subscriptions.subscribers(&event.name);
+            self.deliver(clients, &event);
+        }
+    }
+
+    fn allows(&self, token: mio::Token, access: Access, topic: &str) -> bool {
+        let acl = self.shared.acl.read().unwrap();
+        acl.allows(self.connections[token].user(), access, topic)
+    }
+
+    // Refuse a request the ACL doesn't allow
+    fn deny(&mut self, token: mio::Token, event: String) {
+        let client = &mut self.connections[token];
+        info!("{}: access to {} denied", client, event);
+        client.reject(event, ErrorCode::AccessDenied, &mut self.pending_events);
+    }
+
+    // Drop the subscriptions a reloaded ACL no longer allows, telling
+    // the clients with an AccessDenied error for each
+    fn on_acl_reloaded(&mut self) {
+        let acl = self.shared.acl.read().unwrap();
+        for token in self.connections.tokens() {
+            let client = &mut self.connections[token];
+            let revoked: Vec<String> = client.subscriptions().iter()
+                .filter(|pattern| !acl.allows(client.user(), Access::Subscribe, pattern))
+                .cloned()
+                .collect();
+            if revoked.is_empty() {
+                continue;
+            }
+            for pattern in revoked {
+                warn!("{}: access to {} revoked, unsubscribing", client, pattern);
+                self.subscriptions.unsubscribe(&pattern, token);
+                client.unsubscribed(&pattern);
+                client.revoked(pattern, &mut self.pending_events);
+            }
+            self.unflushed.insert(token);
+        }
+    }
+
+    // Queue an event that wasn't logged for the subscribers on this worker,
+    // and pass it on to the others
+    fn publish(&mut self, event: &str, payload: Bytes) {
+        let clients = self.subscriptions.subscribers(event);
+        if clients.is_empty() && self.workers.len() == 1 {
+            return;
+        }
+        // Encoded once, and shared by the write queues of all subscribers
+        let encoded = EncodedEvent::new(event, payload, None);
+        for (i, outbox) in self.outboxes.iter_mut().enumerate() {
+            if i != self.index {
+                outbox.push(encoded.clone());
+            }
+        }
+        self.deliver(clients, &encoded);
+    }
+
+    // Queue an event for its subscribers, with its offset for those that
+    // negotiated offsets. Clients catching up on the topic's log get it
+    // from there instead.
+    fn deliver(&mut self, clients: HashSet<mio::Token>, event: &EncodedEvent) {
+        let offset = event.logged.as_ref().map(|&(offset, _)| offset);
+        let mut with_offsets = HashSet::new();
+        let mut without_offsets = HashSet::new();
+        for token in clients {
+            let client = &self.connections[token];
+            if !client.accepts_event(&event.name, offset) {
+                continue;
+            }
+            if offset.is_some() && client.wants_offsets() {
+                with_offsets.insert(token);
+            }
+            else {
+                without_offsets.insert(token);
+            }
+        }
+        if let Some((_, ref message_data)) = event.logged {
+            self.queue_for_subscribers(with_offsets, message_data.clone());
+        }
+        self.queue_for_subscribers(without_offsets, event.message_data.clone());
+    }
+
+    fn queue_for_subscribers(&mut self, clients: HashSet<mio::Token>, message_data: Bytes) {
+        // Clients that didn't negotiate extended frames can't receive large events
+        let clients: Vec<_> = clients.into_iter()
+            .filter(|client_token| self.connections[*client_token].accepts(&message_data))
+            .collect();
+        if clients.is_empty() {
+            return;
+        }
+        let event_len = message_data.len();
+        let event_id = self.pending_events.add_event(message_data, clients.len());
+        for client_token in clients {
+            self.queue_event(client_token, event_id, event_len);
+        }
+    }
+
+    fn on_worker_message(&mut self, message: WorkerMessage) {
+        match message {
+            WorkerMessage::Connection(client_socket, address) => {
+                self.add_client(client_socket, address);
+            },
+            WorkerMessage::Events(events) => self.deliver_events(events),
+            WorkerMessage::AclReloaded => self.on_acl_reloaded(),
+            WorkerMessage::Shutdown => self.begin_shutdown()
+        }
+    }
+
+    fn send_to_worker(&self, worker: usize, message: WorkerMessage) {
+        if let Err(e) = self.workers[worker].send(message) {
+            error!("Failed to wake up worker {}: {}", worker, e);
+        }
+    }
+
+    // Send out what has been queued. Events are passed on to the other workers
+    // before the publishers' acks can be written, so that a shutdown requested
+    // after an ack can't overtake the events.
+    fn flush(&mut self) {
+        self.flush_outboxes();
+        self.wake_workers();
+        self.flush_clients();
+    }
+
+    // Pass on the events published since the last flush
+    fn flush_outboxes(&mut self) {
+        let batches: Vec<(usize, Vec<EncodedEvent>)> = self.outboxes.iter_mut()
+            .enumerate()
+            .filter(|(_, outbox)| !outbox.is_empty())
+            .map(|(i, outbox)| (i, mem::take(outbox)))
+            .collect();
+        for (i, events) in batches {
+            self.send_to_worker(i, WorkerMessage::Events(events));
+        }
+    }
+
+    // Wake up the workers that logged events have been queued for,
+    // once for all the events logged since the last flush
+    fn wake_workers(&mut self) {
+        for i in mem::take(&mut self.unwoken) {
+            if let Err(e) = self.workers[i].wake() {
+                error!("Failed to wake up worker {}: {}", i, e);
+            }
+        }
+    }
+
+    // Have the clients with events queued since the last flush
+    // written to once their sockets are writable
+    fn flush_clients(&mut self) {
+        let tokens = mem::take(&mut self.unflushed);
+        for token in tokens {
+            // The client may have been disconnected since
+            if self.connections.contains(token) {
+                self.update_interest(token);
+            }
+        }
+    }
+
+    fn update_interest(&mut self, token: mio::Token) {
+        if let Err(e) = self.connections[token].update_interest(self.poll.registry()) {
+            error!("{}: failed to reregister with poll: {}", self.connections[token], e);
+            self.disconnect_client(token);
+        }
+    }
+
+    // Queue an event for a client, applying the slow consumer policy if that
+    // would exceed its queue limits. Returns whether the client is still connected.
+    fn queue_event(&mut self, token: mio::Token, event_id: EventId, event_len: usize) -> bool {
+        let result = self.connections[token].publish(event_id, event_len,
+                                                     &mut self.pending_events);
+        self.unflushed.insert(token);
+        match result {
+            QueueResult::Queued => true,
+            QueueResult::Dropped(dropped) => {
+                let total = self.shared.stats.dropped_events.fetch_add(dropped, Ordering::SeqCst)
+                    + dropped;
+                let client = &self.connections[token];
+                debug!("{}: exceeded its queue limits, dropped {} events \
+                        ({} for the client, {} in total)",
+                       client, dropped, client.dropped_events(), total);
+                true
+            },
+            QueueResult::Full => {
+                let total = self.shared.stats.disconnected_slow_consumers
+                    .fetch_add(1, Ordering::SeqCst) + 1;
+                warn!("{}: exceeded its queue limits, disconnecting \
+                       ({} slow consumers disconnected in total)",
+                      self.connections[token], total);
+                self.pending_events.finish_event(event_id);
+                self.disconnect_client(token);
+                false
+            }
+        }
+    }
+
+    fn on_client_writable(&mut self, token: mio::Token) {
+        loop {
+            if self.connections[token].write(&mut self.pending_events).is_err() {
+                self.disconnect_client(token);
+                return;
+            }
+            if self.connections[token].is_closed() {
+                self.disconnect_client(token);
+                return;
+            }
+            // Carry on catching up once everything queued has been written.
+            // The socket is edge triggered, so writing goes on until it is full.
+            let client = &self.connections[token];
+            if !self.replaying.contains(&token) || client.is_closing() || client.has_data_pending() {
+                break;
+            }
+            if !self.replay(token) {
+                return;
+            }
+        }
+        self.update_interest(token);
     }
 
     fn disconnect_client(&mut self, token: mio::Token) {
+        let client = &self.connections[token];
+        if client.is_refused() {
+            debug!("{}: disconnected after being refused", client);
+            self.connections[token].clear_events(&mut self.pending_events);
+            self.connections.remove(token);
+            self.refused_clients -= 1;
+            self.check_shutdown_done();
+            return;
+        }
+        if client.dropped_events() > 0 {
+            info!("{}: disconnected, {} events were dropped for it",
+                  client, client.dropped_events());
+        }
+        else {
+            info!("{}: disconnected", client);
+        }
+        self.hooks().on_disconnect(client.peer_address());
+
         // Remove the client from pending events queue
         self.connections[token].clear_events(&mut self.pending_events);
 
-        // A bit inefficient...
         // Unsubscribe the client from all events
-        for clients in self.subscriptions.values_mut() {
-            clients.remove(&token);
-        }
+        self.subscriptions.remove_client(token);
+        self.replaying.remove(&token);
 
         self.connections.remove(token);
+        self.shared.connections.fetch_sub(1, Ordering::SeqCst);
+        self.check_shutdown_done();
+    }
+
+    fn check_shutdown_done(&mut self) {
+        if self.shutting_down && self.connections.count() == 0 {
+            info!("All clients disconnected, shutting down");
+            self.running = false;
+        }
     }
-}
 
-impl mio::Handler for PubsubServer {
-    type Timeout = ();
-    type Message = ();
+    fn begin_shutdown(&mut self) {
+        if self.shutting_down {
+            warn!("Shutdown requested again, shutting down without flushing");
+            self.running = false;
+            return;
+        }
+        self.shutting_down = true;
+        info!("Shutting down, flushing {} connections for up to {} seconds",
+              self.connections.count(), self.config.shutdown_timeout.as_secs());
 
-    fn ready(&mut self, event_loop: &mut EventLoop,
-             token: mio::Token, events: mio::EventSet) {
-        match token {
-            SERVER_TOKEN => {
-                self.on_client_connection(event_loop);
-            },
-            _ => {
-                if events.is_readable() {
-                    self.on_client_readable(event_loop, token);
-                }
-                if events.is_writable() {
-                    self.on_client_writable(event_loop, token);
-                }
+        // Stop accepting connections
+        for listener in &mut self.listeners {
+            if let Err(e) = self.poll.registry().deregister(listener) {
+                warn!("Failed to deregister listener: {}", e);
+            }
+        }
+        self.listeners.clear();
+
+        // Stop reading from the clients, and disconnect the ones with
+        // nothing left to write right away. The rest are disconnected
+        // once their queues have been flushed.
+        for token in self.connections.tokens() {
+            self.connections[token].close();
+            if self.connections[token].is_closed() {
+                self.disconnect_client(token);
             }
+            else {
+                self.unflushed.insert(token);
+            }
+        }
+        if self.connections.count() == 0 {
+            self.running = false;
+            return;
+        }
+        self.shutdown_deadline = Some(Instant::now() + self.config.shutdown_timeout);
+    }
+
+    // The shutdown timeout expired before all clients could be flushed
+    fn finish_shutdown(&mut self) {
+        let tokens = self.connections.tokens();
+        warn!("Shutdown timeout expired, disconnecting {} clients with unsent events",
+              tokens.len());
+        for token in tokens {
+            self.disconnect_client(token);
         }
+        self.running = false;
+    }
+}
+
+// Tell a client that hasn't been added why it is being turned away. The
+// message is small enough to fit in the socket's send buffer, so this is done
+// with a single write rather than through the poll loop.
+fn reject_client(mut client_socket: Stream, code: ErrorCode) {
+    let data = Message::error(String::new(), 0, code).into_bytes();
+    if let Err(e) = client_socket.write(&data) {
+        warn!("Failed to reject client: {}", e);
     }
 }
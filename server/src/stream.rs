@@ -0,0 +1,120 @@
+use mio::event::Source;
+use mio::net::{TcpStream, UnixStream};
+use mio::{Interest, Registry, Token};
+
+use std::fmt;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use tls::TlsStream;
+
+// The connection to a client, over TCP, either plain or secured with TLS,
+// or over a Unix socket
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream>),
+    Unix(UnixStream)
+}
+
+// Where a client connected from
+#[derive(PartialEq, Debug, Clone)]
+pub enum PeerAddress {
+    Tcp(SocketAddr),
+    // A TCP connection to the WebSocket listener
+    WebSocket(SocketAddr),
+    // The path the client's socket is bound to. Clients usually
+    // don't bind theirs, and have none.
+    Unix(Option<PathBuf>)
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerAddress::Tcp(ref address) => write!(f, "{}", address),
+            PeerAddress::WebSocket(ref address) => write!(f, "ws:{}", address),
+            PeerAddress::Unix(Some(ref path)) => write!(f, "unix:{}", path.display()),
+            PeerAddress::Unix(None) => write!(f, "unix")
+        }
+    }
+}
+
+impl Stream {
+    // Whether data the client hasn't been sent yet is kept outside of
+    // the write queue, so that the socket has to be written to again
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            Stream::Tcp(_) | Stream::Unix(_) => false,
+            Stream::Tls(ref stream) => stream.wants_write()
+        }
+    }
+
+    // Whether the TLS handshake has yet to complete, so that nothing can
+    // be sent to the client
+    pub fn is_handshaking(&self) -> bool {
+        match *self {
+            Stream::Tcp(_) | Stream::Unix(_) => false,
+            Stream::Tls(ref stream) => stream.is_handshaking()
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket,
+            Stream::Tls(ref mut stream) => stream.socket_mut(),
+            Stream::Unix(ref mut socket) => socket
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+            Stream::Unix(ref mut socket) => socket.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut socket) => socket.write(buf)
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.write_vectored(bufs),
+            Stream::Tls(ref mut stream) => stream.write_vectored(bufs),
+            Stream::Unix(ref mut socket) => socket.write_vectored(bufs)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut socket) => socket.flush()
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
+                -> io::Result<()> {
+        self.source().register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
+                  -> io::Result<()> {
+        self.source().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
@@ -2,5 +2,173 @@ use std::collections::{HashMap, HashSet};
 
 use mio;
 
+use pubsub::topic::{self, SINGLE_LEVEL_WILDCARD, MULTI_LEVEL_WILDCARD};
+
 pub type ClientMap = HashSet<mio::Token>;
-pub type SubscriptionMap = HashMap<String, ClientMap>;
+
+// A trie keyed on topic levels. Wildcard levels ("*" and "#") are stored
+// as ordinary children and are treated specially only when matching.
+#[derive(Default)]
+struct Node {
+    clients: ClientMap,
+    children: HashMap<String, Node>
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.clients.is_empty() && self.children.is_empty()
+    }
+
+    // Returns true if the node has no subscriptions left and can be pruned
+    fn remove(&mut self, levels: &[&str], token: &mio::Token) -> bool {
+        match levels.split_first() {
+            None => {
+                self.clients.remove(token);
+            },
+            Some((level, rest)) => {
+                let prune = match self.children.get_mut(*level) {
+                    Some(child) => child.remove(rest, token),
+                    None => false
+                };
+                if prune {
+                    self.children.remove(*level);
+                }
+            }
+        }
+        self.is_empty()
+    }
+
+    fn remove_client(&mut self, token: &mio::Token) -> bool {
+        self.clients.remove(token);
+        self.children.retain(|_, child| !child.remove_client(token));
+        self.is_empty()
+    }
+
+    fn collect(&self, levels: &[&str], matched: &mut ClientMap) {
+        if let Some(child) = self.children.get(MULTI_LEVEL_WILDCARD) {
+            matched.extend(&child.clients);
+        }
+        match levels.split_first() {
+            None => matched.extend(&self.clients),
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get(*level) {
+                    child.collect(rest, matched);
+                }
+                if let Some(child) = self.children.get(SINGLE_LEVEL_WILDCARD) {
+                    child.collect(rest, matched);
+                }
+            }
+        }
+    }
+}
+
+pub struct SubscriptionMap {
+    root: Node
+}
+
+impl SubscriptionMap {
+    pub fn new() -> SubscriptionMap {
+        SubscriptionMap {
+            root: Node::default()
+        }
+    }
+
+    pub fn subscribe(&mut self, pattern: &str, token: mio::Token) {
+        let node = topic::levels(pattern).fold(&mut self.root, |node, level| {
+            node.children.entry(level.to_string()).or_insert_with(Node::default)
+        });
+        node.clients.insert(token);
+    }
+
+    pub fn unsubscribe(&mut self, pattern: &str, token: mio::Token) {
+        let levels: Vec<&str> = topic::levels(pattern).collect();
+        self.root.remove(&levels, &token);
+    }
+
+    pub fn remove_client(&mut self, token: mio::Token) {
+        self.root.remove_client(&token);
+    }
+
+    // All clients subscribed to a pattern matching the topic. A client
+    // matching through several patterns is only included once.
+    pub fn subscribers(&self, topic: &str) -> ClientMap {
+        let levels: Vec<&str> = topic::levels(topic).collect();
+        let mut matched = ClientMap::new();
+        self.root.collect(&levels, &mut matched);
+        matched
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::SubscriptionMap;
+    use mio::Token;
+
+    #[test]
+    fn test_exact_subscription() {
+        let mut subscriptions = SubscriptionMap::new();
+        subscriptions.subscribe("sensors.kitchen.temp", Token(1));
+        assert!(subscriptions.subscribers("sensors.kitchen.temp").contains(&Token(1)));
+        assert!(subscriptions.subscribers("sensors.kitchen").is_empty());
+        assert!(subscriptions.subscribers("sensors.kitchen.temp.max").is_empty());
+    }
+
+    #[test]
+    fn test_wildcard_subscriptions() {
+        let mut subscriptions = SubscriptionMap::new();
+        subscriptions.subscribe("sensors.*.temp", Token(1));
+        subscriptions.subscribe("sensors.#", Token(2));
+        subscriptions.subscribe("#", Token(3));
+
+        let matched = subscriptions.subscribers("sensors.kitchen.temp");
+        assert_eq!(matched.len(), 3);
+
+        let matched = subscriptions.subscribers("sensors.kitchen.humidity");
+        assert_eq!(matched.len(), 2);
+        assert!(!matched.contains(&Token(1)));
+
+        let matched = subscriptions.subscribers("sensors");
+        assert_eq!(matched.len(), 2);
+        assert!(!matched.contains(&Token(1)));
+
+        let matched = subscriptions.subscribers("lights");
+        assert_eq!(matched.len(), 1);
+        assert!(matched.contains(&Token(3)));
+    }
+
+    #[test]
+    fn test_overlapping_subscriptions_are_deduplicated() {
+        let mut subscriptions = SubscriptionMap::new();
+        subscriptions.subscribe("sensors.kitchen.temp", Token(1));
+        subscriptions.subscribe("sensors.*.temp", Token(1));
+        subscriptions.subscribe("sensors.#", Token(1));
+        assert_eq!(subscriptions.subscribers("sensors.kitchen.temp").len(), 1);
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let mut subscriptions = SubscriptionMap::new();
+        subscriptions.subscribe("sensors.*.temp", Token(1));
+        subscriptions.subscribe("sensors.#", Token(1));
+        subscriptions.unsubscribe("sensors.*.temp", Token(1));
+        assert!(subscriptions.subscribers("sensors.kitchen.temp").contains(&Token(1)));
+        subscriptions.unsubscribe("sensors.#", Token(1));
+        assert!(subscriptions.subscribers("sensors.kitchen.temp").is_empty());
+        assert!(subscriptions.root.is_empty());
+    }
+
+    #[test]
+    fn test_remove_client() {
+        let mut subscriptions = SubscriptionMap::new();
+        subscriptions.subscribe("sensors.*.temp", Token(1));
+        subscriptions.subscribe("sensors.#", Token(1));
+        subscriptions.subscribe("sensors.#", Token(2));
+        subscriptions.remove_client(Token(1));
+        let matched = subscriptions.subscribers("sensors.kitchen.temp");
+        assert_eq!(matched.len(), 1);
+        assert!(matched.contains(&Token(2)));
+        subscriptions.remove_client(Token(2));
+        assert!(subscriptions.root.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use mio::net::TcpStream;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+
+use std::fmt;
+use std::io::{self, IoSlice, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use config::{Config, ConfigError};
+
+// The TLS settings clients are served with, or None if the server
+// doesn't use TLS
+pub fn load_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, ConfigError> {
+    let (certificate_path, key_path) = match (config.tls_certificate.as_ref(),
+                                              config.tls_key.as_ref()) {
+        (Some(certificate), Some(key)) => (certificate, key),
+        _ => return Ok(None)
+    };
+    let certificates = try!(load_certificates(certificate_path));
+    let key = try!(PrivateKeyDer::from_pem_file(key_path)
+                   .map_err(|e| tls_error(key_path, e)));
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = try!(ServerConfig::builder_with_provider(provider.clone())
+                       .with_safe_default_protocol_versions()
+                       .map_err(|e| tls_error(certificate_path, e)));
+    let builder = match config.tls_client_ca {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in try!(load_certificates(path)) {
+                try!(roots.add(certificate).map_err(|e| tls_error(path, e)));
+            }
+            let verifier = try!(WebPkiClientVerifier::builder_with_provider(Arc::new(roots),
+                                                                            provider)
+                                .build()
+                                .map_err(|e| tls_error(path, e)));
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+    let tls = try!(builder.with_single_cert(certificates, key)
+                   .map_err(|e| tls_error(certificate_path, e)));
+    Ok(Some(Arc::new(tls)))
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let certificates: Vec<CertificateDer> = try!(CertificateDer::pem_file_iter(path)
+                                                 .and_then(|certificates| certificates.collect())
+                                                 .map_err(|e| tls_error(path, e)));
+    if certificates.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certificates)
+}
+
+fn tls_error<E: fmt::Display>(path: &Path, error: E) -> ConfigError {
+    ConfigError::Tls(path.to_string_lossy().into_owned(), error.to_string())
+}
+
+// A client connection secured with TLS. Reads and writes are of the
+// plaintext, and never block: handshake messages and encrypted data that
+// don't fit in the socket are kept until the socket is writable again.
+pub struct TlsStream {
+    socket: TcpStream,
+    tls: ServerConnection
+}
+
+impl TlsStream {
+    pub fn new(socket: TcpStream, config: Arc<ServerConfig>) -> io::Result<TlsStream> {
+        let tls = try!(ServerConnection::new(config)
+                       .map_err(io::Error::other));
+        Ok(TlsStream {
+            socket,
+            tls
+        })
+    }
+
+    pub fn socket_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    // Whether there is encrypted data left to write to the socket
+    pub fn wants_write(&self) -> bool {
+        self.tls.wants_write()
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.tls.is_handshaking()
+    }
+
+    // Write out as much of the encrypted data as the socket takes.
+    // Fails with WouldBlock if some of it is left.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.tls.wants_write() {
+            try!(self.tls.write_tls(&mut self.socket));
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // Ok(0) once the client has closed the connection cleanly,
+            // and UnexpectedEof if the socket was closed without that
+            match self.tls.reader().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                result => return result
+            }
+            // Nothing decrypted yet, so read more from the socket
+            if try!(self.tls.read_tls(&mut self.socket)) == 0 {
+                continue;
+            }
+            let processed = self.tls.process_new_packets();
+            // Send handshake messages, or the alert telling why the
+            // connection failed, right away
+            if let Err(e) = self.write_tls() {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    return Err(e);
+                }
+            }
+            try!(processed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        // Data from earlier writes goes first, so that what is buffered
+        // stays bounded
+        try!(self.write_tls());
+        let written = try!(self.tls.writer().write_vectored(bufs));
+        if written == 0 && bufs.iter().any(|buf| !buf.is_empty()) {
+            // Only happens while the handshake hasn't completed
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "TLS handshake in progress"));
+        }
+        // The data has been taken either way, errors show up on the next write
+        let _ = self.write_tls();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()
+    }
+}
+
+// Let the client know the connection is closed on purpose,
+// if the socket takes it
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.tls.send_close_notify();
+        let _ = self.write_tls();
+    }
+}
@@ -0,0 +1,718 @@
+use bytes::Bytes;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use config::{Config, TopicLogSync};
+
+// Each record is the event's offset (u64) and payload length (u32),
+// followed by the payload
+const RECORD_HEADER_LEN: u64 = 8 + 4;
+// A segment's index has an entry for at most one record in this many bytes
+const INDEX_INTERVAL: u64 = 4096;
+const SEGMENT_EXTENSION: &str = "log";
+
+// A file of records with consecutive offsets, named after the first one
+struct Segment {
+    base_offset: u64,
+    path: PathBuf,
+    len: u64,
+    // Offsets and positions of some of the records, for reads to start
+    // close to the record they are after
+    index: Vec<(u64, u64)>
+}
+
+impl Segment {
+    fn path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{:020}.{}", base_offset, SEGMENT_EXTENSION))
+    }
+
+    // Open the segment, creating it if it doesn't exist. A record left
+    // incomplete, e.g. by a crash while it was being written, is cut off.
+    // Returns the segment and the offset following its last record.
+    fn open(dir: &Path, base_offset: u64) -> io::Result<(Segment, u64)> {
+        let path = Segment::path(dir, base_offset);
+        let file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(false)
+                         .open(&path));
+        let file_len = try!(file.metadata()).len();
+        let mut segment = Segment {
+            base_offset,
+            path,
+            len: 0,
+            index: Vec::new()
+        };
+        let mut next_offset = base_offset;
+        let mut reader = BufReader::new(&file);
+        while let Some((offset, payload_len)) = try!(read_record_header(&mut reader)) {
+            let record_len = RECORD_HEADER_LEN + payload_len as u64;
+            if offset != next_offset || segment.len + record_len > file_len {
+                break;
+            }
+            try!(reader.seek_relative(payload_len as i64));
+            segment.add_record(offset, record_len);
+            next_offset += 1;
+        }
+        if segment.len < file_len {
+            warn!("Cutting off {} bytes of incomplete records from {}",
+                  file_len - segment.len, segment.path.display());
+            try!(file.set_len(segment.len));
+        }
+        Ok((segment, next_offset))
+    }
+
+    fn add_record(&mut self, offset: u64, record_len: u64) {
+        let indexed = self.index.last().is_some_and(|&(_, position)| {
+            self.len - position < INDEX_INTERVAL
+        });
+        if !indexed {
+            self.index.push((offset, self.len));
+        }
+        self.len += record_len;
+    }
+
+    // Where to start reading for the record with the given offset
+    fn position(&self, offset: u64) -> u64 {
+        match self.index.binary_search_by_key(&offset, |&(offset, _)| offset) {
+            Ok(i) => self.index[i].1,
+            Err(0) => 0,
+            Err(i) => self.index[i - 1].1
+        }
+    }
+
+    // Read the records from the given offset on, until the limits are
+    // exceeded (but at least one record) or the end of the segment
+    fn read(&self, from: u64, max_events: usize, max_bytes: usize,
+            events: &mut Vec<(u64, Bytes)>, bytes: &mut usize) -> io::Result<()> {
+        let mut file = try!(File::open(&self.path));
+        let mut position = self.position(from);
+        try!(file.seek(SeekFrom::Start(position)));
+        let mut reader = BufReader::new(file);
+        while position < self.len {
+            if !events.is_empty() && (events.len() >= max_events || *bytes >= max_bytes) {
+                break;
+            }
+            let (offset, payload_len) = match try!(read_record_header(&mut reader)) {
+                Some(header) => header,
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "segment shorter than expected"))
+            };
+            position += RECORD_HEADER_LEN + payload_len as u64;
+            if offset < from {
+                try!(reader.seek_relative(payload_len as i64));
+                continue;
+            }
+            let mut payload = vec![0; payload_len as usize];
+            try!(reader.read_exact(&mut payload));
+            *bytes += payload.len();
+            events.push((offset, Bytes::from(payload)));
+        }
+        Ok(())
+    }
+}
+
+// The header of the next record, or None at the end of the file
+fn read_record_header<R: Read>(reader: &mut R) -> io::Result<Option<(u64, u32)>> {
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) => return Ok(None),
+            Ok(len) => read += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    let mut offset = [0; 8];
+    let mut payload_len = [0; 4];
+    offset.copy_from_slice(&header[..8]);
+    payload_len.copy_from_slice(&header[8..]);
+    Ok(Some((u64::from_be_bytes(offset), u32::from_be_bytes(payload_len))))
+}
+
+// Make the creation and deletion of the files in a directory durable
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    try!(File::open(dir)).sync_all()
+}
+
+// The events published to a topic, in a directory of segment files.
+// Events are appended to the last segment, and a new one is started
+// once it has grown to the segment size.
+struct TopicLog {
+    dir: PathBuf,
+    // Oldest first
+    segments: Vec<Segment>,
+    // The last segment, opened for appending
+    file: File,
+    next_offset: u64,
+    sync: TopicLogSync,
+    // Whether events have been appended since the file was last synced
+    unsynced: bool
+}
+
+impl TopicLog {
+    fn open(dir: PathBuf, sync: TopicLogSync) -> io::Result<TopicLog> {
+        let created = !dir.exists();
+        try!(fs::create_dir_all(&dir));
+        let mut base_offsets = Vec::new();
+        for entry in try!(fs::read_dir(&dir)) {
+            let path = try!(entry).path();
+            if path.extension().is_none_or(|extension| extension != SEGMENT_EXTENSION) {
+                continue;
+            }
+            let base_offset = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok());
+            if let Some(base_offset) = base_offset {
+                base_offsets.push(base_offset);
+            }
+        }
+        base_offsets.sort();
+        if base_offsets.is_empty() {
+            base_offsets.push(0);
+        }
+
+        let mut segments = Vec::new();
+        let mut next_offset = 0;
+        for base_offset in base_offsets {
+            let (segment, segment_end) = try!(Segment::open(&dir, base_offset));
+            segments.push(segment);
+            next_offset = segment_end;
+        }
+        let file = try!(OpenOptions::new().append(true).open(&segments.last().unwrap().path));
+        if created && sync != TopicLogSync::Never {
+            try!(sync_dir(&dir));
+            if let Some(parent) = dir.parent() {
+                try!(sync_dir(parent));
+            }
+        }
+        Ok(TopicLog {
+            dir,
+            segments,
+            file,
+            next_offset,
+            sync,
+            unsynced: false
+        })
+    }
+
+    fn earliest_offset(&self) -> u64 {
+        self.segments[0].base_offset
+    }
+
+    // Append an event, returning its offset. With TopicLogSync::Always,
+    // the event is on disk once this returns.
+    fn append(&mut self, payload: &[u8], segment_size: u64, max_segments: Option<usize>)
+              -> io::Result<u64> {
+        if self.segments.last().unwrap().len >= segment_size {
+            try!(self.roll(max_segments));
+        }
+        let offset = self.next_offset;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&offset.to_be_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(payload);
+        let segment = self.segments.last_mut().unwrap();
+        let mut result = self.file.write_all(&record);
+        if result.is_ok() && self.sync == TopicLogSync::Always {
+            result = self.file.sync_data();
+        }
+        if let Err(e) = result {
+            // Don't leave part of the record behind for the next one to follow,
+            // nor a record that may not have made it to disk
+            let _ = self.file.set_len(segment.len);
+            return Err(e);
+        }
+        segment.add_record(offset, record.len() as u64);
+        self.next_offset += 1;
+        self.unsynced = self.sync == TopicLogSync::Periodic;
+        Ok(offset)
+    }
+
+    // Sync the events appended since the last sync to disk
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            try!(self.file.sync_data());
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    // Start a new segment, deleting the oldest ones beyond the maximum
+    fn roll(&mut self, max_segments: Option<usize>) -> io::Result<()> {
+        // The events appended to the current segment are synced with it,
+        // as the file is replaced
+        try!(self.sync());
+        let (segment, _) = try!(Segment::open(&self.dir, self.next_offset));
+        self.file = try!(OpenOptions::new().append(true).open(&segment.path));
+        self.segments.push(segment);
+        if let Some(max_segments) = max_segments {
+            while self.segments.len() > max_segments {
+                let segment = self.segments.remove(0);
+                if let Err(e) = fs::remove_file(&segment.path) {
+                    warn!("Failed to delete {}: {}", segment.path.display(), e);
+                }
+            }
+        }
+        // So that the new segment is found after a crash
+        if self.sync != TopicLogSync::Never {
+            try!(sync_dir(&self.dir));
+        }
+        Ok(())
+    }
+
+    // Events from the given offset on, or from the earliest one kept
+    // if it is older than that
+    fn read(&self, from: u64, max_events: usize, max_bytes: usize)
+            -> io::Result<Vec<(u64, Bytes)>> {
+        let mut events = Vec::new();
+        let mut bytes = 0;
+        let mut next = ::std::cmp::max(from, self.earliest_offset());
+        // Starting with the last segment beginning at or before the offset
+        let first = self.segments.iter()
+            .rposition(|segment| segment.base_offset <= next)
+            .unwrap_or(0);
+        for segment in &self.segments[first..] {
+            try!(segment.read(next, max_events, max_bytes, &mut events, &mut bytes));
+            if events.len() >= max_events || bytes >= max_bytes {
+                break;
+            }
+            next = events.last().map_or(next, |&(offset, _)| offset + 1);
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for TopicLog {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("Failed to sync the log in {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+// A topic log kept open, and when it was last used
+struct OpenLog {
+    log: Arc<Mutex<TopicLog>>,
+    last_used: u64
+}
+
+// The topic logs kept open
+struct OpenLogs {
+    logs: HashMap<String, OpenLog>,
+    // Counts the uses of the logs, for telling which was used least recently
+    clock: u64
+}
+
+impl OpenLogs {
+    // Close the logs used least recently until no more than max are open.
+    // Logs that are in use are left open, even if that is more than max.
+    fn close_idle(&mut self, max: usize) {
+        while self.logs.len() > max {
+            let idle = self.logs.iter()
+                .filter(|&(_, open)| Arc::strong_count(&open.log) == 1)
+                .min_by_key(|&(_, open)| open.last_used)
+                .map(|(topic, _)| topic.clone());
+            match idle {
+                Some(topic) => { self.logs.remove(&topic); },
+                None => return
+            }
+        }
+    }
+}
+
+// The logs of all topics, each in a directory of its own.
+// A topic's log is opened when it is first used, and closed again once it
+// is among the least recently used beyond the maximum kept open. Each log
+// has a lock of its own, so that workers using different topics don't wait
+// for each other.
+pub struct TopicLogs {
+    dir: PathBuf,
+    segment_size: u64,
+    max_segments: Option<usize>,
+    max_open: usize,
+    sync: TopicLogSync,
+    // Only locked to look up, open or close a topic's log. Once looked up,
+    // a log isn't closed until the last reference to it has been dropped.
+    topics: Mutex<OpenLogs>
+}
+
+impl TopicLogs {
+    // None if the config doesn't have topics logged. With
+    // TopicLogSync::Periodic, a thread syncing the logs is started,
+    // which stops once the logs have been dropped.
+    pub fn open(config: &Config) -> io::Result<Option<Arc<TopicLogs>>> {
+        let dir = match config.topic_log_dir {
+            Some(ref dir) => dir.clone(),
+            None => return Ok(None)
+        };
+        try!(fs::create_dir_all(&dir));
+        let logs = Arc::new(TopicLogs {
+            dir,
+            segment_size: config.topic_log_segment_size,
+            max_segments: config.topic_log_max_segments,
+            max_open: config.topic_log_max_open,
+            sync: config.topic_log_sync,
+            topics: Mutex::new(OpenLogs {
+                logs: HashMap::new(),
+                clock: 0
+            })
+        });
+        if config.topic_log_sync == TopicLogSync::Periodic {
+            let interval = config.topic_log_sync_interval;
+            let weak = Arc::downgrade(&logs);
+            try!(thread::Builder::new()
+                 .name("pubsub-log-sync".to_string())
+                 .spawn(move || sync_periodically(weak, interval)));
+        }
+        Ok(Some(logs))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn topic_dir(&self, topic: &str) -> PathBuf {
+        self.dir.join(escape_topic(topic))
+    }
+
+    // The log of the topic, if it has one or should be created
+    fn topic(&self, topic: &str, create: bool) -> io::Result<Option<Arc<Mutex<TopicLog>>>> {
+        let mut topics = self.topics.lock().unwrap();
+        topics.clock += 1;
+        let clock = topics.clock;
+        if let Some(open) = topics.logs.get_mut(topic) {
+            open.last_used = clock;
+            return Ok(Some(open.log.clone()));
+        }
+
+        let dir = self.topic_dir(topic);
+        if !create && !dir.exists() {
+            return Ok(None);
+        }
+        let log = Arc::new(Mutex::new(try!(TopicLog::open(dir, self.sync))));
+        topics.close_idle(self.max_open - 1);
+        topics.logs.insert(topic.to_string(), OpenLog {
+            log: log.clone(),
+            last_used: clock
+        });
+        Ok(Some(log))
+    }
+
+    // Append an event to the topic's log, returning its offset.
+    // The offset is also handed to logged before the log is unlocked, so
+    // that whatever it does with the topic's events happens in offset order.
+    pub fn append<F: FnOnce(u64)>(&self, topic: &str, payload: &[u8], logged: F)
+                                  -> io::Result<u64> {
+        let log = try!(self.topic(topic, true)).unwrap();
+        let mut log = log.lock().unwrap();
+        let offset = try!(log.append(payload, self.segment_size, self.max_segments));
+        logged(offset);
+        Ok(offset)
+    }
+
+    // The offset of the earliest event kept for the topic,
+    // and the offset the next event will get
+    pub fn bounds(&self, topic: &str) -> io::Result<(u64, u64)> {
+        match try!(self.topic(topic, false)) {
+            Some(log) => {
+                let log = log.lock().unwrap();
+                Ok((log.earliest_offset(), log.next_offset))
+            },
+            None => Ok((0, 0))
+        }
+    }
+
+    // At most max_events events, and about max_bytes of payload, from the
+    // given offset on. Events no longer kept are skipped.
+    pub fn read(&self, topic: &str, from: u64, max_events: usize, max_bytes: usize)
+                -> io::Result<Vec<(u64, Bytes)>> {
+        match try!(self.topic(topic, false)) {
+            Some(log) => log.lock().unwrap().read(from, max_events, max_bytes),
+            None => Ok(Vec::new())
+        }
+    }
+
+    // Sync the events appended to any of the logs since their last sync
+    fn sync(&self) {
+        let logs: Vec<_> = self.topics.lock().unwrap().logs.values()
+            .map(|open| open.log.clone())
+            .collect();
+        for log in logs {
+            let mut log = log.lock().unwrap();
+            if let Err(e) = log.sync() {
+                error!("Failed to sync the log in {}: {}", log.dir.display(), e);
+            }
+        }
+    }
+}
+
+fn sync_periodically(logs: Weak<TopicLogs>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match logs.upgrade() {
+            Some(logs) => logs.sync(),
+            None => return
+        }
+    }
+}
+
+// The name of a topic's directory. Bytes that could make it something other
+// than a plain file name, like a path separator or a leading dot, are
+// written as %XX.
+fn escape_topic(topic: &str) -> String {
+    let mut name = String::with_capacity(topic.len());
+    for (i, byte) in topic.bytes().enumerate() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            b'.' if i > 0 => name.push('.'),
+            _ => name.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    name
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{escape_topic, TopicLogs, Segment};
+    use config::{Config, TopicLogSync};
+
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn open(test: &str, segment_size: u64, max_segments: Option<usize>)
+            -> (Arc<TopicLogs>, PathBuf) {
+        let dir = env::temp_dir().join(format!("pubsub-topic-log-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        let config = Config {
+            topic_log_dir: Some(dir.clone()),
+            topic_log_segment_size: segment_size,
+            topic_log_max_segments: max_segments,
+            ..Config::default()
+        };
+        (TopicLogs::open(&config).unwrap().unwrap(), dir)
+    }
+
+    fn payloads(events: Vec<(u64, ::bytes::Bytes)>) -> Vec<(u64, Vec<u8>)> {
+        events.into_iter().map(|(offset, payload)| (offset, payload.to_vec())).collect()
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let (logs, dir) = open("append", 1024, None);
+        assert_eq!(logs.bounds("event").unwrap(), (0, 0));
+        assert!(logs.read("event", 0, 10, 1024).unwrap().is_empty());
+        // Nothing is created for topics that are only read
+        assert!(!dir.join("event").exists());
+
+        for i in 0..5u8 {
+            assert_eq!(logs.append("event", &[i], |_| {}).unwrap(), i as u64);
+        }
+        assert_eq!(logs.append("other", b"other", |_| {}).unwrap(), 0);
+        assert_eq!(logs.bounds("event").unwrap(), (0, 5));
+        assert_eq!(payloads(logs.read("event", 3, 10, 1024).unwrap()),
+                   vec![(3, vec![3]), (4, vec![4])]);
+        assert_eq!(payloads(logs.read("event", 0, 2, 1024).unwrap()),
+                   vec![(0, vec![0]), (1, vec![1])]);
+        // At least one event, however large
+        assert_eq!(payloads(logs.read("event", 1, 10, 0).unwrap()), vec![(1, vec![1])]);
+        assert!(logs.read("event", 5, 10, 1024).unwrap().is_empty());
+
+        // The offsets carry on after reopening
+        drop(logs);
+        let config = Config {
+            topic_log_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let logs = TopicLogs::open(&config).unwrap().unwrap();
+        assert_eq!(logs.bounds("event").unwrap(), (0, 5));
+        assert_eq!(logs.append("event", b"again", |_| {}).unwrap(), 5);
+        assert_eq!(payloads(logs.read("event", 4, 10, 1024).unwrap()),
+                   vec![(4, vec![4]), (5, b"again".to_vec())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_segments() {
+        // Two 100 byte events to a segment
+        let (logs, dir) = open("segments", 200, Some(3));
+        let payload = vec![0x2A; 100 - 12];
+        for i in 0..10 {
+            assert_eq!(logs.append("event", &payload, |_| {}).unwrap(), i);
+        }
+        let segments: Vec<_> = fs::read_dir(dir.join("event")).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(segments.len(), 3);
+        assert!(segments.contains(&"00000000000000000004.log".to_string()));
+
+        // The oldest events have been deleted, and reading them
+        // starts from the earliest one kept
+        assert_eq!(logs.bounds("event").unwrap(), (4, 10));
+        let offsets: Vec<u64> = logs.read("event", 0, 100, 100 * 1024).unwrap()
+            .into_iter().map(|(offset, _)| offset).collect();
+        assert_eq!(offsets, vec![4, 5, 6, 7, 8, 9]);
+        let offsets: Vec<u64> = logs.read("event", 5, 3, 100 * 1024).unwrap()
+            .into_iter().map(|(offset, _)| offset).collect();
+        assert_eq!(offsets, vec![5, 6, 7]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index() {
+        let (logs, dir) = open("index", 1024 * 1024, None);
+        let payload = vec![0x2A; 1000];
+        for i in 0..100 {
+            assert_eq!(logs.append("event", &payload, |_| {}).unwrap(), i);
+        }
+        let events = logs.read("event", 57, 2, 1024 * 1024).unwrap();
+        assert_eq!(events.iter().map(|&(offset, _)| offset).collect::<Vec<_>>(), vec![57, 58]);
+        assert_eq!(&events[0].1[..], &payload[..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete_record() {
+        let (logs, dir) = open("incomplete", 1024, None);
+        logs.append("event", b"first", |_| {}).unwrap();
+        logs.append("event", b"second", |_| {}).unwrap();
+        drop(logs);
+
+        // As if the server crashed while writing the third event
+        let path = Segment::path(&dir.join("event"), 0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 5, b't']).unwrap();
+        drop(file);
+
+        let (segment, next_offset) = Segment::open(&dir.join("event"), 0).unwrap();
+        assert_eq!(next_offset, 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), segment.len);
+
+        let config = Config {
+            topic_log_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let logs = TopicLogs::open(&config).unwrap().unwrap();
+        assert_eq!(logs.append("event", b"third", |_| {}).unwrap(), 2);
+        assert_eq!(payloads(logs.read("event", 1, 10, 1024).unwrap()),
+                   vec![(1, b"second".to_vec()), (2, b"third".to_vec())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_periodic_sync() {
+        let dir = env::temp_dir().join(format!("pubsub-topic-log-{}-sync", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = Config {
+            topic_log_dir: Some(dir.clone()),
+            topic_log_sync: TopicLogSync::Periodic,
+            topic_log_sync_interval: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let logs = TopicLogs::open(&config).unwrap().unwrap();
+        logs.append("event", b"payload", |_| {}).unwrap();
+
+        // Synced by the thread in the background
+        let log = logs.topic("event", false).unwrap().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while log.lock().unwrap().unsynced {
+            assert!(Instant::now() < deadline, "log wasn't synced");
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(log);
+        drop(logs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_close_idle_logs() {
+        let dir = env::temp_dir().join(format!("pubsub-topic-log-{}-idle", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = Config {
+            topic_log_dir: Some(dir.clone()),
+            topic_log_max_open: 2,
+            ..Config::default()
+        };
+        let logs = TopicLogs::open(&config).unwrap().unwrap();
+        let open_topics = || {
+            let mut topics: Vec<String> = logs.topics.lock().unwrap().logs.keys().cloned().collect();
+            topics.sort();
+            topics
+        };
+
+        logs.append("a", b"a0", |_| {}).unwrap();
+        logs.append("b", b"b0", |_| {}).unwrap();
+        logs.append("a", b"a1", |_| {}).unwrap();
+        logs.append("c", b"c0", |_| {}).unwrap();
+        assert_eq!(open_topics(), vec!["a", "c"]);
+
+        // Reopened where it was left
+        assert_eq!(logs.append("b", b"b1", |_| {}).unwrap(), 1);
+        assert_eq!(open_topics(), vec!["b", "c"]);
+        assert_eq!(payloads(logs.read("a", 0, 10, 1024).unwrap()),
+                   vec![(0, b"a0".to_vec()), (1, b"a1".to_vec())]);
+        assert_eq!(open_topics(), vec!["a", "b"]);
+
+        // Logs in use stay open
+        let a = logs.topic("a", false).unwrap().unwrap();
+        let b = logs.topic("b", false).unwrap().unwrap();
+        logs.append("c", b"c1", |_| {}).unwrap();
+        assert_eq!(open_topics(), vec!["a", "b", "c"]);
+        drop(a);
+        drop(b);
+        logs.append("d", b"d0", |_| {}).unwrap();
+        assert_eq!(open_topics(), vec!["c", "d"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_appends() {
+        let (logs, dir) = open("concurrent", 1024, None);
+        let threads: Vec<_> = (0..4).map(|i| {
+            let logs = logs.clone();
+            thread::spawn(move || {
+                let topic = if i % 2 == 0 { "even" } else { "odd" };
+                let offsets: Vec<u64> = (0..100)
+                    .map(|_| logs.append(topic, b"payload", |_| {}).unwrap())
+                    .collect();
+                (topic, offsets)
+            })
+        }).collect();
+        let mut even = Vec::new();
+        let mut odd = Vec::new();
+        for thread in threads {
+            match thread.join().unwrap() {
+                ("even", offsets) => even.extend(offsets),
+                (_, offsets) => odd.extend(offsets)
+            }
+        }
+        // Each of a topic's offsets is handed out once
+        even.sort();
+        odd.sort();
+        assert_eq!(even, (0..200).collect::<Vec<_>>());
+        assert_eq!(odd, (0..200).collect::<Vec<_>>());
+        assert_eq!(logs.bounds("even").unwrap(), (0, 200));
+        assert_eq!(logs.bounds("odd").unwrap(), (0, 200));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_escape_topic() {
+        assert_eq!(escape_topic("sensors.kitchen.temp"), "sensors.kitchen.temp");
+        assert_eq!(escape_topic("a/b"), "a%2Fb");
+        assert_eq!(escape_topic(".."), "%2E.");
+        assert_eq!(escape_topic("caf\u{e9}"), "caf%C3%A9");
+        assert_eq!(escape_topic("100%"), "100%25");
+    }
+}
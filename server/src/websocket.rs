@@ -0,0 +1,189 @@
+use bytes::Bytes;
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use tungstenite::{Error, Message, WebSocket};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::protocol::WebSocketConfig;
+
+use std::io::{self, IoSlice, Write};
+use std::mem;
+
+use stream::Stream;
+
+enum State {
+    // Waiting for the client's upgrade request, or for the response
+    // to be written
+    Handshake(MidHandshake<ServerHandshake<Stream, NoCallback>>),
+    Open(WebSocket<Stream>),
+    // The handshake failed, and the socket is gone
+    Failed
+}
+
+// A client connection carrying binary WebSocket messages, once the HTTP
+// upgrade handshake is done. Like the socket underneath, it never blocks:
+// messages that don't fit in the socket are kept until it is writable again.
+pub struct WebSocketStream {
+    state: State,
+    // Set when a message, or the reply to a ping or close, is left to write
+    unflushed: bool
+}
+
+impl WebSocketStream {
+    // Messages larger than max_message_size are refused
+    pub fn new(socket: Stream, max_message_size: usize) -> WebSocketStream {
+        // Messages are written out right away rather than buffered
+        let config = WebSocketConfig::default()
+            .write_buffer_size(0)
+            .max_message_size(Some(max_message_size))
+            .max_frame_size(Some(max_message_size));
+        WebSocketStream {
+            state: State::Handshake(ServerHandshake::start(socket, NoCallback, Some(config))),
+            unflushed: false
+        }
+    }
+
+    // Whether the handshake response, messages or TLS data may be left
+    // to write to the socket
+    pub fn wants_write(&self) -> bool {
+        match self.state {
+            State::Handshake(_) => true,
+            State::Open(ref websocket) => self.unflushed || websocket.get_ref().wants_write(),
+            State::Failed => false
+        }
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        match self.state {
+            State::Handshake(_) => true,
+            State::Open(ref websocket) => websocket.get_ref().is_handshaking(),
+            State::Failed => false
+        }
+    }
+
+    // The payload of the next binary message received, or None if it was
+    // a control message, which is replied to when flushing. Fails with
+    // WouldBlock once there is nothing left to read, and with InvalidData
+    // on text messages.
+    pub fn read_message(&mut self) -> io::Result<Option<Bytes>> {
+        let message = try!(try!(self.open()).read().map_err(websocket_error));
+        match message {
+            Message::Binary(data) => Ok(Some(data)),
+            Message::Text(_) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                   "text message received")),
+            Message::Ping(_) | Message::Close(_) => {
+                self.unflushed = true;
+                Ok(None)
+            },
+            Message::Pong(_) | Message::Frame(_) => Ok(None)
+        }
+    }
+
+    // Carry on with the handshake if it isn't done yet.
+    // Fails with WouldBlock until it is.
+    fn open(&mut self) -> io::Result<&mut WebSocket<Stream>> {
+        if let State::Handshake(_) = self.state {
+            let handshake = match mem::replace(&mut self.state, State::Failed) {
+                State::Handshake(handshake) => handshake,
+                _ => unreachable!()
+            };
+            match handshake.handshake() {
+                Ok(websocket) => self.state = State::Open(websocket),
+                Err(HandshakeError::Interrupted(handshake)) => {
+                    self.state = State::Handshake(handshake);
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                              "WebSocket handshake in progress"));
+                },
+                Err(HandshakeError::Failure(e)) => return Err(websocket_error(e))
+            }
+        }
+        match self.state {
+            State::Open(ref mut websocket) => Ok(websocket),
+            _ => Err(not_connected())
+        }
+    }
+
+    fn socket_mut(&mut self) -> io::Result<&mut Stream> {
+        match self.state {
+            State::Handshake(ref mut handshake) => Ok(handshake.get_mut().get_mut()),
+            State::Open(ref mut websocket) => Ok(websocket.get_mut()),
+            State::Failed => Err(not_connected())
+        }
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    // Each buffer is sent as a message of its own
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        // Messages from earlier writes go first, so that what is buffered
+        // stays bounded
+        try!(self.flush());
+        let mut written = 0;
+        for buf in bufs {
+            let result = try!(self.open()).write(Message::Binary(Bytes::copy_from_slice(buf)));
+            match result {
+                Ok(()) => written += buf.len(),
+                // The message has been taken, and is written out when flushing
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.unflushed = true;
+                    written += buf.len();
+                    break;
+                },
+                // Errors show up again on the next write
+                Err(_) if written > 0 => break,
+                Err(e) => return Err(websocket_error(e))
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(try!(self.open()).flush().map_err(websocket_error));
+        self.unflushed = false;
+        Ok(())
+    }
+}
+
+impl Source for WebSocketStream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
+                -> io::Result<()> {
+        try!(self.socket_mut()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
+                  -> io::Result<()> {
+        try!(self.socket_mut()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        try!(self.socket_mut()).deregister(registry)
+    }
+}
+
+// Let the client know the connection is closed on purpose,
+// if the socket takes it
+impl Drop for WebSocketStream {
+    fn drop(&mut self) {
+        if let State::Open(ref mut websocket) = self.state {
+            let _ = websocket.close(None);
+        }
+    }
+}
+
+fn websocket_error(error: Error) -> io::Error {
+    match error {
+        Error::Io(e) => e,
+        Error::ConnectionClosed | Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "WebSocket closed")
+        },
+        e => io::Error::other(e.to_string())
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "WebSocket handshake failed")
+}
@@ -0,0 +1,148 @@
+extern crate pubsub;
+extern crate pubsub_server;
+
+mod common;
+use common::{Server, connect, send, receive, write_temp_file};
+
+use pubsub::message::{Message, MessageType, ErrorCode};
+use pubsub_server::ServerBuilder;
+
+use std::fs;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Passwords "secret" and "hunter2"
+const CREDENTIALS: &str = "\
+alice:s4lt:56fb59a0918a8e898cc46532428936acd1717e608c197eef668b7c6ef2f3c655
+bob:pepper:ca458f67a1e64e60f40414c062c57abbfc1d41b5d0c30cd07d12704540067f21
+";
+
+const ACL: &str = r#"
+[[rule]]
+user = "alice"
+topic = "sensors.#"
+action = "allow"
+
+[[rule]]
+user = "*"
+topic = "sensors.*.temp"
+access = "subscribe"
+action = "allow"
+"#;
+
+fn connect_as(server: &Server, user: &str, password: &str) -> TcpStream {
+    let mut client = server.connect_client();
+    send(&mut client, MessageType::Authenticate, user, Some(password.as_bytes()));
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+    client
+}
+
+fn request(client: &mut TcpStream, message_type: MessageType, topic: &str) -> Message {
+    let payload = if message_type == MessageType::Publish { Some(&b"payload"[..]) } else { None };
+    send(client, message_type, topic, payload);
+    receive(client)
+}
+
+#[test]
+fn test_denied_requests() {
+    let credentials = write_temp_file("denied", "credentials", CREDENTIALS);
+    let acl = write_temp_file("denied", "acl", ACL);
+    let server = Server::start(&["--credentials-file", credentials.to_str().unwrap(),
+                                 "--acl-file", acl.to_str().unwrap()]);
+    let mut alice = connect_as(&server, "alice", "secret");
+    let mut bob = connect_as(&server, "bob", "hunter2");
+
+    assert_eq!(request(&mut bob, MessageType::Subscribe, "sensors.kitchen.temp").header.message_type,
+               MessageType::Ack);
+    // Could receive events from sensors.kitchen.humidity
+    let error = request(&mut bob, MessageType::Subscribe, "sensors.kitchen.*");
+    assert_eq!(error.error_code(), Some(ErrorCode::AccessDenied));
+    assert_eq!(error.correlation_id(), Some(2));
+    assert_eq!(error.header.event_name, "sensors.kitchen.*");
+    let error = request(&mut bob, MessageType::Publish, "sensors.kitchen.temp");
+    assert_eq!(error.error_code(), Some(ErrorCode::AccessDenied));
+
+    // Denied requests aren't carried out, and the client stays connected
+    let error = request(&mut alice, MessageType::Publish, "lights.kitchen");
+    assert_eq!(error.error_code(), Some(ErrorCode::AccessDenied));
+    assert_eq!(request(&mut alice, MessageType::Publish, "sensors.kitchen.temp").header.message_type,
+               MessageType::Ack);
+    let event = receive(&mut bob);
+    assert_eq!(event.header.message_type, MessageType::Event);
+    assert_eq!(event.header.event_name, "sensors.kitchen.temp");
+
+    fs::remove_file(credentials).unwrap();
+    fs::remove_file(acl).unwrap();
+}
+
+#[test]
+fn test_reload_on_sighup() {
+    let credentials = write_temp_file("reload", "credentials", CREDENTIALS);
+    let acl = write_temp_file("reload", "acl", ACL);
+    let server = Server::start(&["--credentials-file", credentials.to_str().unwrap(),
+                                 "--acl-file", acl.to_str().unwrap()]);
+    let mut alice = connect_as(&server, "alice", "secret");
+    let mut bob = connect_as(&server, "bob", "hunter2");
+    assert_eq!(request(&mut bob, MessageType::Subscribe, "sensors.kitchen.temp").header.message_type,
+               MessageType::Ack);
+
+    // Only alice keeps access to sensors
+    fs::write(&acl, "[[rule]]\nuser = \"alice\"\ntopic = \"#\"\naction = \"allow\"\n").unwrap();
+    server.signal("HUP");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let reply = request(&mut alice, MessageType::Publish, "lights.kitchen");
+        if reply.header.message_type == MessageType::Ack {
+            break;
+        }
+        assert!(Instant::now() < deadline, "ACL wasn't reloaded");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Bob is told the subscription has been dropped, and gets no events
+    // from it, so the next message is the reply to the request
+    let revoked = receive(&mut bob);
+    assert_eq!(revoked.error_code(), Some(ErrorCode::AccessDenied));
+    assert_eq!(revoked.correlation_id(), Some(0));
+    assert_eq!(revoked.header.event_name, "sensors.kitchen.temp");
+    assert_eq!(request(&mut alice, MessageType::Publish, "sensors.kitchen.temp").header.message_type,
+               MessageType::Ack);
+    let error = request(&mut bob, MessageType::Subscribe, "sensors.kitchen.temp");
+    assert_eq!(error.error_code(), Some(ErrorCode::AccessDenied));
+
+    // A broken file leaves the rules as they were
+    fs::write(&acl, "[[rule]]\nuser = \"bob\"\n").unwrap();
+    server.signal("HUP");
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(request(&mut alice, MessageType::Publish, "lights.kitchen").header.message_type,
+               MessageType::Ack);
+
+    fs::remove_file(credentials).unwrap();
+    fs::remove_file(acl).unwrap();
+}
+
+#[test]
+fn test_acl_handle() {
+    let acl = write_temp_file("handle", "acl", "default = \"deny\"");
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .acl_file(acl.clone())
+        .start()
+        .unwrap();
+    let mut client = connect(server.local_addr());
+
+    // Clients that don't authenticate only get the rules for everyone
+    let error = request(&mut client, MessageType::Publish, "event");
+    assert_eq!(error.error_code(), Some(ErrorCode::AccessDenied));
+
+    fs::write(&acl, "[[rule]]\nuser = \"*\"\ntopic = \"event\"\naction = \"allow\"\n").unwrap();
+    server.acl_handle().reload().unwrap();
+    // The new rules apply as soon as reload returns
+    assert_eq!(request(&mut client, MessageType::Publish, "event").header.message_type,
+               MessageType::Ack);
+
+    fs::write(&acl, "default = \"sometimes\"").unwrap();
+    assert!(server.acl_handle().reload().is_err());
+    fs::remove_file(acl).unwrap();
+}
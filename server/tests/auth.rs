@@ -0,0 +1,141 @@
+extern crate pubsub;
+
+mod common;
+use common::{Server, send, receive, assert_closed};
+
+use pubsub::handshake::{Handshake, FEATURE_ACKS, HANDSHAKE_LEN};
+use pubsub::message::{MessageType, ErrorCode};
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+
+// Passwords "secret" and "hunter2", salted with "s4lt" and "pepper"
+const CREDENTIALS: &str = "\
+# Users allowed to connect
+alice:s4lt:56fb59a0918a8e898cc46532428936acd1717e608c197eef668b7c6ef2f3c655
+bob:pepper:ca458f67a1e64e60f40414c062c57abbfc1d41b5d0c30cd07d12704540067f21
+";
+
+fn credentials_file(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("pubsub-credentials-{}-{}", process::id(), name));
+    fs::write(&path, CREDENTIALS).unwrap();
+    path
+}
+
+fn authenticate(stream: &mut TcpStream, user: &str, secret: &[u8]) -> Option<ErrorCode> {
+    send(stream, MessageType::Authenticate, user, Some(secret));
+    let reply = receive(stream);
+    assert_eq!(reply.correlation_id(), Some(0));
+    reply.error_code()
+}
+
+#[test]
+fn test_token() {
+    let server = Server::start(&["--auth-token", "t0ken"]);
+
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "", b"t0ken"), None);
+    send(&mut client, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "", b"guess"), Some(ErrorCode::AuthenticationFailed));
+    assert_closed(&mut client);
+}
+
+#[test]
+fn test_passwords() {
+    let path = credentials_file("passwords");
+    let server = Server::start(&["--credentials-file", path.to_str().unwrap()]);
+
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "alice", b"secret"), None);
+    send(&mut client, MessageType::Publish, "event", Some(b"payload"));
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "bob", b"hunter2"), None);
+
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "bob", b"secret"), Some(ErrorCode::AuthenticationFailed));
+    assert_closed(&mut client);
+
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "carol", b"secret"), Some(ErrorCode::AuthenticationFailed));
+    assert_closed(&mut client);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_unauthenticated_requests() {
+    let server = Server::start(&["--auth-token", "t0ken"]);
+
+    let mut subscriber = server.connect_client();
+    assert_eq!(authenticate(&mut subscriber, "", b"t0ken"), None);
+    send(&mut subscriber, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+
+    // Refused, and not carried out
+    let mut client = server.connect_client();
+    send(&mut client, MessageType::Publish, "event", Some(b"payload"));
+    let error = receive(&mut client);
+    assert_eq!(error.correlation_id(), Some(1));
+    assert_eq!(error.error_code(), Some(ErrorCode::NotAuthenticated));
+    assert_closed(&mut client);
+
+    send(&mut subscriber, MessageType::Publish, "event", Some(b"own"));
+    let event = receive(&mut subscriber);
+    assert_eq!(event.header.message_type, MessageType::Event);
+    assert_eq!(event.payload, Some(b"own".to_vec()));
+}
+
+#[test]
+fn test_authenticate_only_first() {
+    let server = Server::start(&["--auth-token", "t0ken"]);
+
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "", b"t0ken"), None);
+    assert_eq!(authenticate(&mut client, "", b"t0ken"), Some(ErrorCode::UnexpectedMessage));
+    assert_closed(&mut client);
+}
+
+#[test]
+fn test_authenticate_not_negotiated() {
+    let server = Server::start(&["--auth-token", "t0ken"]);
+
+    let mut client = server.connect();
+    let hello = Handshake {
+        features: FEATURE_ACKS,
+        ..Handshake::new()
+    };
+    client.write_all(&hello.into_bytes()).unwrap();
+    let mut welcome = [0; HANDSHAKE_LEN];
+    client.read_exact(&mut welcome).unwrap();
+    assert_eq!(authenticate(&mut client, "", b"t0ken"), Some(ErrorCode::UnexpectedMessage));
+    assert_closed(&mut client);
+}
+
+#[test]
+fn test_not_required() {
+    let server = Server::start(&[]);
+
+    // Credentials are accepted, whatever they are
+    let mut client = server.connect_client();
+    assert_eq!(authenticate(&mut client, "alice", b"anything"), None);
+    send(&mut client, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+}
+
+#[test]
+fn test_missing_credentials_file() {
+    let status = process::Command::new(env!("CARGO_BIN_EXE_pubsub-server"))
+        .args(["--bind", "127.0.0.1:0", "--credentials-file", "/nonexistent/users"])
+        .stderr(process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
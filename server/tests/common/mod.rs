@@ -0,0 +1,182 @@
+// Helpers shared by the integration tests, which run the server binary
+// and talk to it over TCP, TLS or Unix sockets
+#![allow(dead_code)]
+
+use pubsub::handshake::{Handshake, HANDSHAKE_LEN};
+use pubsub::message::{Message, MessageBuilder, MessageType};
+use pubsub::parser::{parse, ParseResult};
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Server {
+    process: Child,
+    address: String
+}
+
+impl Server {
+    pub fn start(args: &[&str]) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        let mut command = Command::new(env!("CARGO_BIN_EXE_pubsub-server"));
+        command.arg("--bind").arg(&address).args(args);
+        Server::spawn(command, address)
+    }
+
+    // Start a server listening on the given Unix socket only
+    pub fn start_unix(path: &Path, args: &[&str]) -> Server {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_pubsub-server"));
+        command.arg("--unix-socket").arg(path).args(args);
+        Server::spawn(command, String::new())
+    }
+
+    fn spawn(mut command: Command, address: String) -> Server {
+        let mut process = command
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // Wait for the server to start listening, then keep draining its
+        // log output so that it never blocks on a full pipe
+        let mut output = BufReader::new(process.stderr.take().unwrap());
+        let mut line = String::new();
+        output.read_line(&mut line).unwrap();
+        assert!(line.contains("Listening on"), "Unexpected output: {}", line);
+        thread::spawn(move || {
+            let mut sink = Vec::new();
+            let _ = output.read_to_end(&mut sink);
+        });
+
+        Server {
+            process,
+            address
+        }
+    }
+
+    // Connect without sending the handshake
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(&self.address[..]).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream
+    }
+
+    pub fn connect_client(&self) -> TcpStream {
+        connect(self.address.parse().unwrap())
+    }
+
+    // Send a signal, e.g. "TERM", to the server process
+    pub fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(self.process.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    // Wait for the server to exit, for no longer than the timeout
+    pub fn wait(&mut self, timeout: Duration) -> ExitStatus {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.process.try_wait().unwrap() {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "Server didn't exit");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+// Connect to a server, binary or embedded, and complete the handshake
+pub fn connect(address: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    handshake(&mut stream).unwrap();
+    stream
+}
+
+// Connect to a server's Unix socket, and complete the handshake
+pub fn connect_unix(path: &Path) -> UnixStream {
+    let mut stream = UnixStream::connect(path).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    handshake(&mut stream).unwrap();
+    stream
+}
+
+// Send the handshake over a connected stream, plain or not, and check the reply
+pub fn handshake<S: Read + Write>(stream: &mut S) -> io::Result<()> {
+    try!(stream.write_all(&Handshake::new().into_bytes()));
+    let mut welcome = [0; HANDSHAKE_LEN];
+    try!(stream.read_exact(&mut welcome));
+    assert_eq!(&welcome[..], &Handshake::new().into_bytes()[..]);
+    Ok(())
+}
+
+// Write a file for a test to the temporary directory. The path includes the
+// process id and the test, so that tests running at the same time don't clash.
+pub fn write_temp_file(test: &str, name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("pubsub-{}-{}-{}", process::id(), test, name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+pub fn send<S: Write>(stream: &mut S, message_type: MessageType, event_name: &str,
+        payload: Option<&[u8]>) {
+    let mut builder = MessageBuilder::new();
+    builder.message_type(message_type)
+        .event_name(event_name.to_string());
+    if let Some(payload) = payload {
+        builder.payload(payload.to_vec());
+    }
+    stream.write_all(&builder.build().unwrap().into_bytes()).unwrap();
+}
+
+pub fn receive<S: Read>(stream: &mut S) -> Message {
+    let mut data = Vec::new();
+    let mut byte = [0];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        data.push(byte[0]);
+        match parse(&data) {
+            ParseResult::Completed(header, _, payload_len) => {
+                let payload = if header.message_type.expects_payload() {
+                    let mut payload = vec![0; payload_len];
+                    stream.read_exact(&mut payload).unwrap();
+                    Some(payload)
+                }
+                else {
+                    None
+                };
+                return Message {
+                    header,
+                    payload
+                };
+            },
+            ParseResult::Incomplete => continue,
+            ParseResult::Error => panic!("Received invalid message")
+        }
+    }
+}
+
+pub fn assert_closed<S: Read>(stream: &mut S) {
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+}
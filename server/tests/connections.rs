@@ -0,0 +1,94 @@
+extern crate pubsub;
+
+mod common;
+use common::{Server, send, receive, assert_closed};
+
+use pubsub::handshake::{Handshake, HANDSHAKE_LEN};
+use pubsub::message::{MessageType, ErrorCode};
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_hundreds_of_connections() {
+    let server = Server::start(&["--max-connections", "1000"]);
+
+    let mut subscribers: Vec<TcpStream> = (0..500).map(|_| server.connect_client()).collect();
+    for subscriber in &mut subscribers {
+        send(subscriber, MessageType::Subscribe, "load", None);
+        assert_eq!(receive(subscriber).header.message_type, MessageType::Ack);
+    }
+
+    let mut publisher = server.connect_client();
+    send(&mut publisher, MessageType::Publish, "load", Some(b"payload"));
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+
+    for subscriber in &mut subscribers {
+        let event = receive(subscriber);
+        assert_eq!(event.header.message_type, MessageType::Event);
+        assert_eq!(event.payload, Some(b"payload".to_vec()));
+    }
+}
+
+#[test]
+fn test_many_small_events() {
+    let server = Server::start(&[]);
+    let mut subscriber = server.connect_client();
+    send(&mut subscriber, MessageType::Subscribe, "small", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+
+    // Let the events pile up in the subscriber's queue before reading them
+    let mut publisher = server.connect_client();
+    for i in 0..5000u32 {
+        send(&mut publisher, MessageType::Publish, "small", Some(&i.to_string().into_bytes()));
+    }
+    for _ in 0..5000 {
+        assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+    }
+
+    for i in 0..5000u32 {
+        let event = receive(&mut subscriber);
+        assert_eq!(event.payload, Some(i.to_string().into_bytes()));
+    }
+}
+
+#[test]
+fn test_connection_limit() {
+    let server = Server::start(&["--max-connections", "200"]);
+
+    let mut clients: Vec<TcpStream> = (0..200).map(|_| server.connect_client()).collect();
+
+    // Don't send anything on the rejected connection, as closing a socket
+    // with unread data makes the peer see a reset rather than the error
+    let mut rejected = server.connect();
+    let error = receive(&mut rejected);
+    assert_eq!(error.error_code(), Some(ErrorCode::ServerFull));
+    assert_closed(&mut rejected);
+
+    // The server keeps serving the clients it has
+    send(&mut clients[0], MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut clients[0]).header.message_type, MessageType::Ack);
+
+    // And accepts new clients once others have disconnected
+    clients.truncate(150);
+    // An accepted client is sent nothing until it has sent its handshake
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut client = loop {
+        let mut client = server.connect();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut buf = [0; 1];
+        match client.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                break client;
+            },
+            _ => {}
+        }
+        assert!(Instant::now() < deadline, "Server never accepted a new client");
+    };
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    client.write_all(&Handshake::new().into_bytes()).unwrap();
+    let mut welcome = [0; HANDSHAKE_LEN];
+    client.read_exact(&mut welcome).unwrap();
+    assert_eq!(&welcome[..], &Handshake::new().into_bytes()[..]);
+}
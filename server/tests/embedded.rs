@@ -0,0 +1,137 @@
+extern crate pubsub;
+extern crate pubsub_server;
+
+mod common;
+use common::{connect, connect_unix, send, receive, assert_closed};
+
+use pubsub::message::MessageType;
+use pubsub_server::{Hooks, PeerAddress, ServerBuilder, ServerHandle};
+
+use std::env;
+use std::fs;
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+fn start() -> ServerHandle {
+    ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap()
+}
+
+#[derive(Clone, Default)]
+struct RecordingHooks {
+    calls: Arc<Mutex<Vec<String>>>
+}
+
+impl Hooks for RecordingHooks {
+    fn on_connect(&mut self, _peer: &PeerAddress) {
+        self.calls.lock().unwrap().push("connect".to_string());
+    }
+
+    fn on_disconnect(&mut self, _peer: &PeerAddress) {
+        self.calls.lock().unwrap().push("disconnect".to_string());
+    }
+
+    fn on_subscribe(&mut self, _peer: &PeerAddress, pattern: &str) {
+        self.calls.lock().unwrap().push(format!("subscribe {}", pattern));
+    }
+
+    fn on_publish(&mut self, _peer: &PeerAddress, event: &str, payload: &[u8]) {
+        self.calls.lock().unwrap().push(format!("publish {} {}", event, payload.len()));
+    }
+}
+
+// Records where clients connect from
+#[derive(Clone, Default)]
+struct RecordingPeers {
+    peers: Arc<Mutex<Vec<PeerAddress>>>
+}
+
+impl Hooks for RecordingPeers {
+    fn on_connect(&mut self, peer: &PeerAddress) {
+        self.peers.lock().unwrap().push(peer.clone());
+    }
+}
+
+#[test]
+fn test_publish_and_subscribe() {
+    let server = start();
+    assert!(server.local_addr().port() != 0);
+
+    let mut subscriber = connect(server.local_addr());
+    send(&mut subscriber, MessageType::Subscribe, "sensors.*", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+
+    let mut publisher = connect(server.local_addr());
+    send(&mut publisher, MessageType::Publish, "sensors.temp", Some(b"21"));
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+
+    let event = receive(&mut subscriber);
+    assert_eq!(event.header.event_name, "sensors.temp");
+    assert_eq!(event.payload, Some(b"21".to_vec()));
+}
+
+#[test]
+fn test_shutdown() {
+    let server = start();
+    let address = server.local_addr();
+    let mut client = connect(server.local_addr());
+
+    server.shutdown().unwrap();
+    assert_closed(&mut client);
+    assert!(TcpStream::connect(address).is_err());
+}
+
+#[test]
+fn test_hooks() {
+    let hooks = RecordingHooks::default();
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .hooks(hooks.clone())
+        .start()
+        .unwrap();
+
+    let mut client = connect(server.local_addr());
+    send(&mut client, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+    send(&mut client, MessageType::Publish, "event", Some(b"payload"));
+    // The client gets its own event along with the ack
+    for _ in 0..2 {
+        receive(&mut client);
+    }
+
+    server.shutdown().unwrap();
+    assert_eq!(*hooks.calls.lock().unwrap(), vec![
+        "connect", "subscribe event", "publish event 7", "disconnect"
+    ]);
+}
+
+#[test]
+fn test_unix_socket() {
+    let path = env::temp_dir().join(format!("pubsub-embedded-{}.sock", process::id()));
+    let hooks = RecordingPeers::default();
+    let server = ServerBuilder::new()
+        .unix_socket(path.clone())
+        .unix_socket_mode(0o660)
+        .hooks(hooks.clone())
+        .start()
+        .unwrap();
+    // Only listening on the socket
+    assert!(server.local_addrs().is_empty());
+    assert_eq!(server.unix_socket(), Some(path.as_path()));
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+
+    let mut client = connect_unix(&path);
+    server.shutdown().unwrap();
+    assert_closed(&mut client);
+    assert_eq!(*hooks.peers.lock().unwrap(), vec![PeerAddress::Unix(None)]);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_invalid_config() {
+    assert!(ServerBuilder::new().max_connections(0).start().is_err());
+}
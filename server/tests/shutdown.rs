@@ -0,0 +1,78 @@
+extern crate pubsub;
+
+mod common;
+use common::{Server, send, receive, assert_closed};
+
+use pubsub::message::MessageType;
+
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const EVENT_COUNT: u8 = 10;
+// Large enough for the events to back up in the server's queue
+// while the subscriber isn't reading
+const PAYLOAD_LEN: usize = 1024 * 1024;
+
+// Subscribe a client that doesn't read yet, and publish events to it
+fn queue_events(server: &Server) -> (TcpStream, TcpStream) {
+    let mut subscriber = server.connect_client();
+    send(&mut subscriber, MessageType::Subscribe, "shutdown", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+
+    let mut publisher = server.connect_client();
+    for i in 0..EVENT_COUNT {
+        send(&mut publisher, MessageType::Publish, "shutdown", Some(&vec![i; PAYLOAD_LEN]));
+    }
+    for _ in 0..EVENT_COUNT {
+        assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+    }
+    (subscriber, publisher)
+}
+
+#[test]
+fn test_shutdown_flushes_queued_events() {
+    let mut server = Server::start(&[]);
+    let (mut subscriber, mut publisher) = queue_events(&server);
+
+    server.signal("TERM");
+
+    // Idle clients are disconnected right away
+    assert_closed(&mut publisher);
+    // And no new clients are accepted
+    assert!(TcpStream::connect(server.address()).is_err());
+
+    // The subscriber gets everything that was queued for it before being disconnected
+    for i in 0..EVENT_COUNT {
+        let event = receive(&mut subscriber);
+        assert_eq!(event.header.message_type, MessageType::Event);
+        assert_eq!(event.payload, Some(vec![i; PAYLOAD_LEN]));
+    }
+    assert_closed(&mut subscriber);
+
+    assert!(server.wait(Duration::from_secs(10)).success());
+}
+
+#[test]
+fn test_shutdown_timeout() {
+    let mut server = Server::start(&["--shutdown-timeout", "1"]);
+    let (_subscriber, _publisher) = queue_events(&server);
+
+    // The subscriber never reads, so the server gives up on it after the timeout
+    let start = Instant::now();
+    server.signal("INT");
+    assert!(server.wait(Duration::from_secs(10)).success());
+    assert!(start.elapsed() >= Duration::from_secs(1));
+}
+
+#[test]
+fn test_second_signal_stops_immediately() {
+    let mut server = Server::start(&["--shutdown-timeout", "60"]);
+    let (_subscriber, _publisher) = queue_events(&server);
+
+    server.signal("TERM");
+    // Signals sent back to back are coalesced, so let the first one be handled
+    thread::sleep(Duration::from_millis(500));
+    server.signal("TERM");
+    assert!(server.wait(Duration::from_secs(10)).success());
+}
@@ -0,0 +1,140 @@
+extern crate pubsub;
+extern crate pubsub_server;
+
+mod common;
+use common::{Server, connect, send, receive};
+
+use pubsub::message::MessageType;
+use pubsub_server::{ServerBuilder, SlowConsumerPolicy, Stats};
+
+use std::io::{self, ErrorKind, Read};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const EVENT_COUNT: u8 = 30;
+// Large enough for the events to fill the socket buffers and back up
+// in the server's queue while the subscriber isn't reading
+const PAYLOAD_LEN: usize = 1024 * 1024;
+
+// Subscribe a client that doesn't read, then publish EVENT_COUNT events.
+// Each event's payload is filled with its index.
+fn flood(server: &Server) -> TcpStream {
+    flood_with(|| server.connect_client())
+}
+
+fn flood_with<F: Fn() -> TcpStream>(connect: F) -> TcpStream {
+    let mut subscriber = connect();
+    send(&mut subscriber, MessageType::Subscribe, "flood", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+
+    let mut publisher = connect();
+    for i in 0..EVENT_COUNT {
+        send(&mut publisher, MessageType::Publish, "flood", Some(&vec![i; PAYLOAD_LEN]));
+    }
+    for _ in 0..EVENT_COUNT {
+        assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+    }
+    subscriber
+}
+
+// Read events until the server stops sending, returning their indices
+fn drain(subscriber: &mut TcpStream) -> Vec<u8> {
+    let mut received = Vec::new();
+    subscriber.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    loop {
+        let mut byte = [0];
+        match subscriber.peek(&mut byte) {
+            Ok(0) => return received,
+            Ok(_) => {},
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return received;
+            },
+            Err(e) => panic!("{}", e)
+        }
+        let event = receive(subscriber);
+        assert_eq!(event.header.message_type, MessageType::Event);
+        let payload = event.payload.unwrap();
+        assert_eq!(payload.len(), PAYLOAD_LEN);
+        received.push(payload[0]);
+    }
+}
+
+#[test]
+fn test_disconnect_slow_consumer() {
+    let server = Server::start(&["--max-queued-events", "2",
+                                 "--slow-consumer-policy", "disconnect"]);
+    let mut subscriber = flood(&server);
+    let received = drain(&mut subscriber);
+    assert!(received.len() < EVENT_COUNT as usize);
+
+    // The connection was closed by the server
+    let mut buf = [0; 1];
+    assert_eq!(subscriber.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_drop_newest_events() {
+    let server = Server::start(&["--max-queued-events", "2",
+                                 "--slow-consumer-policy", "drop-newest"]);
+    let mut subscriber = flood(&server);
+    let received = drain(&mut subscriber);
+    assert!(received.len() < EVENT_COUNT as usize);
+    assert_eq!(received, (0..received.len() as u8).collect::<Vec<_>>());
+
+    // The subscriber stays connected
+    send(&mut subscriber, MessageType::Unsubscribe, "flood", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+}
+
+#[test]
+fn test_drop_oldest_events() {
+    let server = Server::start(&["--max-queued-events", "2",
+                                 "--slow-consumer-policy", "drop-oldest"]);
+    let mut subscriber = flood(&server);
+    let received = drain(&mut subscriber);
+    assert!(received.len() < EVENT_COUNT as usize);
+    assert_eq!(received.last(), Some(&(EVENT_COUNT - 1)));
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+    send(&mut subscriber, MessageType::Unsubscribe, "flood", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+}
+
+// The counters of an embedded server after a flood, with the subscriber
+// and the publisher on different workers
+fn stats_after_flood(policy: SlowConsumerPolicy) -> (Vec<u8>, Stats) {
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .max_queued_events(2)
+        .slow_consumer_policy(policy)
+        .workers(2)
+        .start()
+        .unwrap();
+    assert_eq!(server.stats(), Stats::default());
+    let mut subscriber = flood_with(|| connect(server.local_addr()));
+    let received = if policy == SlowConsumerPolicy::Disconnect {
+        // The connection may be closed in the middle of an event
+        io::copy(&mut subscriber, &mut io::sink()).ok();
+        Vec::new()
+    }
+    else {
+        drain(&mut subscriber)
+    };
+    (received, server.stats())
+}
+
+#[test]
+fn test_stats() {
+    for &policy in &[SlowConsumerPolicy::DropOldest, SlowConsumerPolicy::DropNewest] {
+        let (received, stats) = stats_after_flood(policy);
+        assert!(stats.dropped_events > 0);
+        assert_eq!(stats.dropped_events + received.len(), EVENT_COUNT as usize);
+        assert_eq!(stats.disconnected_slow_consumers, 0);
+    }
+
+    let (_, stats) = stats_after_flood(SlowConsumerPolicy::Disconnect);
+    assert_eq!(stats, Stats {
+        dropped_events: 0,
+        disconnected_slow_consumers: 1
+    });
+}
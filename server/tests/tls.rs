@@ -0,0 +1,253 @@
+extern crate pubsub;
+extern crate rcgen;
+extern crate rustls;
+extern crate tungstenite;
+
+mod common;
+use common::{Server, handshake, send, receive, assert_closed, write_temp_file};
+
+use pubsub::handshake::{Handshake, HANDSHAKE_LEN};
+use pubsub::decoder::Decoder;
+use pubsub::message::{ErrorCode, MessageBuilder, MessageType};
+
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type TlsClient = StreamOwned<ClientConnection, TcpStream>;
+
+// A certificate authority made up for a test
+struct Authority {
+    certificate: rcgen::Certificate,
+    key: KeyPair
+}
+
+impl Authority {
+    fn new() -> Authority {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        Authority {
+            certificate: params.self_signed(&key).unwrap(),
+            key
+        }
+    }
+
+    fn pem(&self) -> String {
+        self.certificate.pem()
+    }
+
+    // The PEM encoded certificate and key of a new certificate for the name
+    fn issue(&self, name: &str) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec![name.to_string()]).unwrap()
+            .signed_by(&key, &self.certificate, &self.key)
+            .unwrap();
+        (certificate.pem(), key.serialize_pem())
+    }
+}
+
+// Connect with TLS, trusting the given CA, and perform the handshake.
+// Fails if the server refuses the connection.
+fn connect_tls(server: &Server, ca: &str, client_certificate: Option<&(String, String)>)
+               -> io::Result<TlsClient> {
+    let mut stream = open_tls(server, ca, client_certificate);
+    handshake(&mut stream)?;
+    Ok(stream)
+}
+
+// Connect with TLS, trusting the given CA. The TLS handshake is done on
+// the first read or write.
+fn open_tls(server: &Server, ca: &str, client_certificate: Option<&(String, String)>)
+            -> TlsClient {
+    secure(server.connect(), ca, client_certificate)
+}
+
+// Secure a connection with TLS, trusting the given CA
+fn secure(socket: TcpStream, ca: &str, client_certificate: Option<&(String, String)>)
+          -> TlsClient {
+    let mut roots = RootCertStore::empty();
+    for certificate in CertificateDer::pem_slice_iter(ca.as_bytes()) {
+        roots.add(certificate.unwrap()).unwrap();
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = match client_certificate {
+        Some((certificate, key)) => {
+            let chain = CertificateDer::pem_slice_iter(certificate.as_bytes())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let key = PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap();
+            builder.with_client_auth_cert(chain, key).unwrap()
+        },
+        None => builder.with_no_client_auth()
+    };
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+    StreamOwned::new(connection, socket)
+}
+
+#[test]
+fn test_tls() {
+    let authority = Authority::new();
+    let (certificate, key) = authority.issue("localhost");
+    let certificate = write_temp_file("tls", "cert.pem", &certificate);
+    let key = write_temp_file("tls", "key.pem", &key);
+    let server = Server::start(&["--tls-cert", certificate.to_str().unwrap(),
+                                 "--tls-key", key.to_str().unwrap()]);
+
+    let mut subscriber = connect_tls(&server, &authority.pem(), None).unwrap();
+    send(&mut subscriber, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    let mut publisher = connect_tls(&server, &authority.pem(), None).unwrap();
+    // More than fits in a single TLS record
+    let payload = vec![7; 100 * 1024];
+    send(&mut publisher, MessageType::Publish, "event", Some(&payload));
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+    let event = receive(&mut subscriber);
+    assert_eq!(event.header.event_name, "event");
+    assert_eq!(event.payload, Some(payload));
+
+    // Closed cleanly after a protocol error
+    send(&mut publisher, MessageType::Event, "event", Some(b"payload"));
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Error);
+    assert_closed(&mut publisher);
+
+    // The server doesn't speak the protocol in plaintext any more
+    let mut client = server.connect();
+    client.write_all(&Handshake::new().into_bytes()).unwrap();
+    let mut welcome = [0; HANDSHAKE_LEN];
+    assert!(client.read_exact(&mut welcome).is_err() ||
+            welcome[..] != Handshake::new().into_bytes()[..]);
+
+    // Nor with a certificate the client doesn't trust
+    assert!(connect_tls(&server, &Authority::new().pem(), None).is_err());
+
+    fs::remove_file(certificate).unwrap();
+    fs::remove_file(key).unwrap();
+}
+
+#[test]
+fn test_client_certificates() {
+    let authority = Authority::new();
+    let (certificate, key) = authority.issue("localhost");
+    let certificate = write_temp_file("clients", "cert.pem", &certificate);
+    let key = write_temp_file("clients", "key.pem", &key);
+    let client_authority = Authority::new();
+    let client_ca = write_temp_file("clients", "ca.pem", &client_authority.pem());
+    let server = Server::start(&["--tls-cert", certificate.to_str().unwrap(),
+                                 "--tls-key", key.to_str().unwrap(),
+                                 "--tls-client-ca", client_ca.to_str().unwrap()]);
+
+    let client_certificate = client_authority.issue("client");
+    let mut client = connect_tls(&server, &authority.pem(), Some(&client_certificate)).unwrap();
+    send(&mut client, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+
+    assert!(connect_tls(&server, &authority.pem(), None).is_err());
+    // Signed by a CA the server doesn't trust
+    let untrusted = authority.issue("client");
+    assert!(connect_tls(&server, &authority.pem(), Some(&untrusted)).is_err());
+
+    fs::remove_file(certificate).unwrap();
+    fs::remove_file(key).unwrap();
+    fs::remove_file(client_ca).unwrap();
+}
+
+#[test]
+fn test_connection_limit() {
+    let authority = Authority::new();
+    let (certificate, key) = authority.issue("localhost");
+    let certificate = write_temp_file("limit", "cert.pem", &certificate);
+    let key = write_temp_file("limit", "key.pem", &key);
+    let server = Server::start(&["--tls-cert", certificate.to_str().unwrap(),
+                                 "--tls-key", key.to_str().unwrap(),
+                                 "--max-connections", "1"]);
+
+    let mut client = connect_tls(&server, &authority.pem(), None).unwrap();
+
+    // Told why once the TLS handshake is done
+    let mut rejected = open_tls(&server, &authority.pem(), None);
+    let error = receive(&mut rejected);
+    assert_eq!(error.error_code(), Some(ErrorCode::ServerFull));
+    assert_closed(&mut rejected);
+
+    send(&mut client, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+
+    fs::remove_file(certificate).unwrap();
+    fs::remove_file(key).unwrap();
+}
+
+#[test]
+fn test_invalid_files() {
+    let authority = Authority::new();
+    let certificate = write_temp_file("invalid", "cert.pem", &authority.issue("localhost").0);
+    let not_a_key = write_temp_file("invalid", "key.pem", &authority.pem());
+    for args in &[&["--tls-cert", certificate.to_str().unwrap(), "--tls-key", "/nonexistent/key"],
+                  &["--tls-cert", certificate.to_str().unwrap(),
+                    "--tls-key", not_a_key.to_str().unwrap()],
+                  &["--tls-cert", "/nonexistent/cert", "--tls-key", not_a_key.to_str().unwrap()]] {
+        let status = process::Command::new(env!("CARGO_BIN_EXE_pubsub-server"))
+            .args(["--bind", "127.0.0.1:0"])
+            .args(&args[..])
+            .stderr(process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(!status.success());
+    }
+    fs::remove_file(certificate).unwrap();
+    fs::remove_file(not_a_key).unwrap();
+}
+
+#[test]
+fn test_websocket() {
+    let authority = Authority::new();
+    let (certificate, key) = authority.issue("localhost");
+    let certificate = write_temp_file("websocket", "cert.pem", &certificate);
+    let key = write_temp_file("websocket", "key.pem", &key);
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let address = format!("127.0.0.1:{}", port);
+    let _server = Server::start(&["--websocket", &address,
+                                  "--tls-cert", certificate.to_str().unwrap(),
+                                  "--tls-key", key.to_str().unwrap()]);
+
+    // WebSocket connections are secured too. The listener is bound after
+    // the one whose address is logged first.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let socket = loop {
+        match TcpStream::connect(&address[..]) {
+            Ok(socket) => break socket,
+            Err(e) => assert!(Instant::now() < deadline, "Couldn't connect: {}", e)
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let stream = secure(socket, &authority.pem(), None);
+    let (mut websocket, _) = tungstenite::client("wss://localhost/", stream).unwrap();
+    websocket.send(Handshake::new().into_bytes().into()).unwrap();
+    assert_eq!(&websocket.read().unwrap().into_data()[..], &Handshake::new().into_bytes()[..]);
+    let mut builder = MessageBuilder::new();
+    builder.message_type(MessageType::Subscribe)
+        .event_name("event".to_string());
+    websocket.send(builder.build().unwrap().into_bytes().into()).unwrap();
+    let mut decoder = Decoder::new();
+    decoder.feed(&websocket.read().unwrap().into_data());
+    assert_eq!(decoder.decode().unwrap().unwrap().header.message_type, MessageType::Ack);
+
+    fs::remove_file(certificate).unwrap();
+    fs::remove_file(key).unwrap();
+}
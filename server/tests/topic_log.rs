@@ -0,0 +1,240 @@
+extern crate pubsub;
+extern crate pubsub_server;
+
+mod common;
+use common::{send, receive, assert_closed};
+
+use pubsub::handshake::{Handshake, HANDSHAKE_LEN, FEATURE_OFFSETS};
+use pubsub::message::{ErrorCode, Message, MessageBuilder, MessageType, OFFSET_EARLIEST,
+                      OFFSET_LATEST};
+use pubsub_server::{ServerBuilder, ServerHandle};
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+fn log_dir(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("pubsub-topic-log-{}-{}", process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn start(dir: &Path) -> ServerHandle {
+    ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .topic_log_dir(dir)
+        .start()
+        .unwrap()
+}
+
+fn connect_with(server: &ServerHandle, handshake: Handshake) -> TcpStream {
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(&handshake.into_bytes()).unwrap();
+    let mut welcome = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut welcome).unwrap();
+    stream
+}
+
+fn connect(server: &ServerHandle) -> TcpStream {
+    common::connect(server.local_addr())
+}
+
+fn subscribe_from(stream: &mut TcpStream, topic: &str, offset: u64) {
+    let mut builder = MessageBuilder::new();
+    builder.message_type(MessageType::Subscribe)
+        .event_name(topic.to_string())
+        .offset(offset);
+    stream.write_all(&builder.build().unwrap().into_bytes()).unwrap();
+}
+
+fn publish(stream: &mut TcpStream, topic: &str, payload: &[u8]) {
+    send(stream, MessageType::Publish, topic, Some(payload));
+    assert_eq!(receive(stream).header.message_type, MessageType::Ack);
+}
+
+// The offset and payload of the next message, which must be an event
+fn receive_event(stream: &mut TcpStream) -> (Option<u64>, Vec<u8>) {
+    let message: Message = receive(stream);
+    assert_eq!(message.header.message_type, MessageType::Event);
+    (message.header.offset, message.payload.unwrap())
+}
+
+#[test]
+fn test_replay() {
+    let dir = log_dir("replay");
+    let server = start(&dir);
+    let mut publisher = connect(&server);
+    for i in 0..3u8 {
+        publish(&mut publisher, "sensors.temp", &[i]);
+    }
+
+    // From the start, then on to the events published from then on
+    let mut subscriber = connect(&server);
+    subscribe_from(&mut subscriber, "sensors.temp", OFFSET_EARLIEST);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    for i in 0..3u8 {
+        assert_eq!(receive_event(&mut subscriber), (Some(i as u64), vec![i]));
+    }
+    publish(&mut publisher, "sensors.temp", &[3]);
+    assert_eq!(receive_event(&mut subscriber), (Some(3), vec![3]));
+
+    // From an offset in the middle
+    let mut subscriber = connect(&server);
+    subscribe_from(&mut subscriber, "sensors.temp", 2);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    assert_eq!(receive_event(&mut subscriber), (Some(2), vec![2]));
+    assert_eq!(receive_event(&mut subscriber), (Some(3), vec![3]));
+
+    // Only what is published from then on
+    let mut subscriber = connect(&server);
+    subscribe_from(&mut subscriber, "sensors.temp", OFFSET_LATEST);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    publish(&mut publisher, "sensors.temp", &[4]);
+    assert_eq!(receive_event(&mut subscriber), (Some(4), vec![4]));
+
+    // Plain subscriptions get the offsets too, unless the client
+    // didn't negotiate them
+    let mut subscriber = connect(&server);
+    send(&mut subscriber, MessageType::Subscribe, "sensors.*", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    let mut old_client = connect_with(&server, Handshake {
+        version: 1,
+        features: Handshake::new().features & !FEATURE_OFFSETS
+    });
+    send(&mut old_client, MessageType::Subscribe, "sensors.*", None);
+    assert_eq!(receive(&mut old_client).header.message_type, MessageType::Ack);
+    publish(&mut publisher, "sensors.temp", &[5]);
+    assert_eq!(receive_event(&mut subscriber), (Some(5), vec![5]));
+    assert_eq!(receive_event(&mut old_client), (None, vec![5]));
+
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_invalid_offsets() {
+    let dir = log_dir("invalid");
+    let server = start(&dir);
+    let mut client = connect(&server);
+    publish(&mut client, "event", b"payload");
+
+    // Past the end of the log
+    subscribe_from(&mut client, "event", 2);
+    assert_eq!(receive(&mut client).error_code(), Some(ErrorCode::InvalidOffset));
+    // The end itself is where the next event goes
+    subscribe_from(&mut client, "event", 1);
+    assert_eq!(receive(&mut client).header.message_type, MessageType::Ack);
+    // Only a single topic's log can be replayed
+    subscribe_from(&mut client, "sensors.*", OFFSET_EARLIEST);
+    assert_eq!(receive(&mut client).error_code(), Some(ErrorCode::InvalidTopic));
+    // Nor by a client that didn't negotiate offsets
+    let mut old_client = connect_with(&server, Handshake {
+        version: 1,
+        features: Handshake::new().features & !FEATURE_OFFSETS
+    });
+    subscribe_from(&mut old_client, "event", OFFSET_EARLIEST);
+    assert_eq!(receive(&mut old_client).error_code(), Some(ErrorCode::MalformedMessage));
+    assert_closed(&mut old_client);
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Nor can it without a log
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap();
+    let mut client = connect(&server);
+    subscribe_from(&mut client, "event", OFFSET_EARLIEST);
+    assert_eq!(receive(&mut client).error_code(), Some(ErrorCode::InvalidOffset));
+}
+
+#[test]
+fn test_restart() {
+    let dir = log_dir("restart");
+    let server = start(&dir);
+    let mut publisher = connect(&server);
+    publish(&mut publisher, "event", b"before");
+    server.shutdown().unwrap();
+
+    // The events outlive the server, and the offsets carry on
+    let server = start(&dir);
+    let mut publisher = connect(&server);
+    publish(&mut publisher, "event", b"after");
+    let mut subscriber = connect(&server);
+    subscribe_from(&mut subscriber, "event", OFFSET_EARLIEST);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    assert_eq!(receive_event(&mut subscriber), (Some(0), b"before".to_vec()));
+    assert_eq!(receive_event(&mut subscriber), (Some(1), b"after".to_vec()));
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_catch_up_while_publishing() {
+    let dir = log_dir("catch-up");
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .topic_log_dir(dir.clone())
+        .topic_log_segment_size(4096)
+        .workers(2)
+        .start()
+        .unwrap();
+    let mut publisher = connect(&server);
+    for i in 0..1000u32 {
+        publish(&mut publisher, "event", &i.to_be_bytes());
+    }
+
+    // More than is read from the log at once, with events being published
+    // on another worker meanwhile. Each event arrives once, in order.
+    let mut subscriber = connect(&server);
+    subscribe_from(&mut subscriber, "event", 10);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    for i in 1000..1200u32 {
+        publish(&mut publisher, "event", &i.to_be_bytes());
+    }
+    for i in 10..1200u32 {
+        assert_eq!(receive_event(&mut subscriber), (Some(i as u64), i.to_be_bytes().to_vec()));
+    }
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_publish_on_several_workers() {
+    let dir = log_dir("workers");
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .topic_log_dir(dir.clone())
+        .workers(4)
+        .start()
+        .unwrap();
+    let mut subscriber = connect(&server);
+    subscribe_from(&mut subscriber, "event", OFFSET_LATEST);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+
+    // Connections are handed to the workers in turn, so each publisher
+    // is on a worker of its own. Whichever worker logged an event, the
+    // subscriber gets them all in offset order.
+    let publishers: Vec<_> = (0..4).map(|_| {
+        let mut publisher = connect(&server);
+        thread::spawn(move || {
+            for i in 0..250u32 {
+                publish(&mut publisher, "event", &i.to_be_bytes());
+            }
+        })
+    }).collect();
+    for offset in 0..1000 {
+        assert_eq!(receive_event(&mut subscriber).0, Some(offset));
+    }
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,101 @@
+extern crate pubsub;
+
+mod common;
+use common::{Server, connect_unix, send, receive};
+
+use pubsub::message::MessageType;
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn socket_path(test: &str) -> PathBuf {
+    env::temp_dir().join(format!("pubsub-{}-{}.sock", process::id(), test))
+}
+
+// The socket is bound after the TCP listeners, whose address is logged first
+fn wait_for_socket(path: &Path) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !path.exists() {
+        assert!(Instant::now() < deadline, "Socket wasn't created");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_unix_socket() {
+    let path = socket_path("unix");
+    let mut server = Server::start_unix(&path, &["--unix-socket-mode", "600"]);
+    let metadata = fs::metadata(&path).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+    let mut subscriber = connect_unix(&path);
+    send(&mut subscriber, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    let mut publisher = connect_unix(&path);
+    send(&mut publisher, MessageType::Publish, "event", Some(b"local"));
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+    let event = receive(&mut subscriber);
+    assert_eq!(event.header.event_name, "event");
+    assert_eq!(event.payload, Some(b"local".to_vec()));
+
+    // The socket is removed once the server stops
+    server.signal("TERM");
+    assert!(server.wait(Duration::from_secs(10)).success());
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_alongside_tcp() {
+    let path = socket_path("alongside");
+    let server = Server::start(&["--unix-socket", path.to_str().unwrap()]);
+    wait_for_socket(&path);
+
+    let mut subscriber = server.connect_client();
+    send(&mut subscriber, MessageType::Subscribe, "event", None);
+    assert_eq!(receive(&mut subscriber).header.message_type, MessageType::Ack);
+    let mut publisher = connect_unix(&path);
+    send(&mut publisher, MessageType::Publish, "event", Some(b"local"));
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+    assert_eq!(receive(&mut subscriber).payload, Some(b"local".to_vec()));
+    drop(server);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_stale_socket() {
+    // Left behind by a server that was killed
+    let path = socket_path("stale");
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let server = Server::start_unix(&path, &[]);
+    connect_unix(&path);
+
+    // Another server can't take the socket over while in use
+    let status = process::Command::new(env!("CARGO_BIN_EXE_pubsub-server"))
+        .arg("--unix-socket").arg(&path)
+        .stderr(process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    drop(server);
+    fs::remove_file(&path).unwrap();
+
+    // Nor replace other files
+    let path = socket_path("not-a-socket");
+    fs::write(&path, "data").unwrap();
+    let status = process::Command::new(env!("CARGO_BIN_EXE_pubsub-server"))
+        .arg("--unix-socket").arg(&path)
+        .stderr(process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert_eq!(fs::read(&path).unwrap(), b"data");
+    fs::remove_file(path).unwrap();
+}
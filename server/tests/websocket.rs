@@ -0,0 +1,213 @@
+extern crate pubsub;
+extern crate pubsub_server;
+extern crate tungstenite;
+
+mod common;
+use common::{send, receive, assert_closed};
+
+use pubsub::decoder::Decoder;
+use pubsub::handshake::Handshake;
+use pubsub::message::{ErrorCode, Message, MessageBuilder, MessageType};
+use pubsub_server::{Hooks, PeerAddress, ServerBuilder, ServerHandle};
+use tungstenite::WebSocket;
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn start() -> ServerHandle {
+    ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .websocket("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap()
+}
+
+// Upgrade a connection to WebSocket, without sending the handshake
+fn connect(server: &ServerHandle) -> WebSocket<TcpStream> {
+    let address = server.websocket_addrs()[0];
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let url = format!("ws://{}/", address);
+    let (websocket, _) = tungstenite::client(&url[..], stream).unwrap();
+    websocket
+}
+
+fn connect_client(server: &ServerHandle) -> WebSocket<TcpStream> {
+    let mut websocket = connect(server);
+    websocket.send(Handshake::new().into_bytes().into()).unwrap();
+    let welcome = websocket.read().unwrap().into_data();
+    assert_eq!(&welcome[..], &Handshake::new().into_bytes()[..]);
+    websocket
+}
+
+fn message(message_type: MessageType, event_name: &str, payload: Option<&[u8]>) -> Vec<u8> {
+    let mut builder = MessageBuilder::new();
+    builder.message_type(message_type)
+        .event_name(event_name.to_string());
+    if let Some(payload) = payload {
+        builder.payload(payload.to_vec());
+    }
+    builder.build().unwrap().into_bytes()
+}
+
+// The next message, which must carry exactly one frame
+fn receive_message(websocket: &mut WebSocket<TcpStream>) -> Message {
+    let data = websocket.read().unwrap();
+    assert!(data.is_binary());
+    let mut decoder = Decoder::new();
+    decoder.feed(&data.into_data());
+    let message = decoder.decode().unwrap().expect("incomplete message");
+    assert!(decoder.buffered().is_empty());
+    message
+}
+
+// The connection has been closed by the server
+fn assert_websocket_closed(websocket: &mut WebSocket<TcpStream>) {
+    loop {
+        match websocket.read() {
+            Ok(message) => assert!(message.is_close(), "unexpected message {:?}", message),
+            Err(tungstenite::Error::ConnectionClosed) => return,
+            Err(e) => panic!("connection failed: {}", e)
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct RecordingPeers {
+    peers: Arc<Mutex<Vec<PeerAddress>>>
+}
+
+impl Hooks for RecordingPeers {
+    fn on_connect(&mut self, peer: &PeerAddress) {
+        self.peers.lock().unwrap().push(peer.clone());
+    }
+}
+
+#[test]
+fn test_websocket() {
+    let hooks = RecordingPeers::default();
+    let server = ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .websocket("127.0.0.1:0".parse().unwrap())
+        .hooks(hooks.clone())
+        .start()
+        .unwrap();
+
+    let mut subscriber = connect_client(&server);
+    subscriber.send(message(MessageType::Subscribe, "sensors.*", None).into()).unwrap();
+    let ack = receive_message(&mut subscriber);
+    assert_eq!(ack.header.message_type, MessageType::Ack);
+    assert_eq!(ack.correlation_id(), Some(1));
+
+    // Events published by TCP clients reach WebSocket subscribers
+    // and the other way around
+    let mut tcp_client = common::connect(server.local_addr());
+    send(&mut tcp_client, MessageType::Subscribe, "alerts", None);
+    assert_eq!(receive(&mut tcp_client).header.message_type, MessageType::Ack);
+    send(&mut tcp_client, MessageType::Publish, "sensors.temp", Some(b"21"));
+    assert_eq!(receive(&mut tcp_client).header.message_type, MessageType::Ack);
+    let event = receive_message(&mut subscriber);
+    assert_eq!(event.header.message_type, MessageType::Event);
+    assert_eq!(event.header.event_name, "sensors.temp");
+    assert_eq!(event.payload, Some(b"21".to_vec()));
+
+    subscriber.send(message(MessageType::Publish, "alerts", Some(b"fire")).into()).unwrap();
+    assert_eq!(receive_message(&mut subscriber).header.message_type, MessageType::Ack);
+    assert_eq!(receive(&mut tcp_client).payload, Some(b"fire".to_vec()));
+
+    // Pings are answered
+    subscriber.send(tungstenite::Message::Ping(b"ping".to_vec().into())).unwrap();
+    assert_eq!(subscriber.read().unwrap(), tungstenite::Message::Pong(b"ping".to_vec().into()));
+
+    assert_eq!(hooks.peers.lock().unwrap()[0],
+               PeerAddress::WebSocket(subscriber.get_ref().local_addr().unwrap()));
+
+    // Closing the WebSocket disconnects the client
+    subscriber.close(None).unwrap();
+    assert_websocket_closed(&mut subscriber);
+    server.shutdown().unwrap();
+    assert_closed(&mut tcp_client);
+}
+
+#[test]
+fn test_one_frame_per_message() {
+    let server = start();
+
+    // Two messages in one
+    let mut client = connect_client(&server);
+    let mut data = message(MessageType::Subscribe, "a", None);
+    data.extend(message(MessageType::Subscribe, "b", None));
+    client.send(data.into()).unwrap();
+    let error = receive_message(&mut client);
+    assert_eq!(error.error_code(), Some(ErrorCode::MalformedMessage));
+    assert_websocket_closed(&mut client);
+
+    // Part of a message
+    let mut client = connect_client(&server);
+    let data = message(MessageType::Publish, "event", Some(b"payload"));
+    client.send(data[..data.len() - 1].to_vec().into()).unwrap();
+    let error = receive_message(&mut client);
+    assert_eq!(error.error_code(), Some(ErrorCode::MalformedMessage));
+    assert_eq!(error.correlation_id(), Some(1));
+    assert_websocket_closed(&mut client);
+
+    // The handshake has to come on its own too
+    let mut client = connect(&server);
+    let mut data = Handshake::new().into_bytes();
+    data.extend(message(MessageType::Subscribe, "a", None));
+    client.send(data.into()).unwrap();
+    let error = receive_message(&mut client);
+    assert_eq!(error.error_code(), Some(ErrorCode::HandshakeFailed));
+    assert_websocket_closed(&mut client);
+
+    // The protocol is binary
+    let mut client = connect_client(&server);
+    client.send(tungstenite::Message::Text("subscribe a".into())).unwrap();
+    let error = receive_message(&mut client);
+    assert_eq!(error.error_code(), Some(ErrorCode::MalformedMessage));
+    assert_websocket_closed(&mut client);
+}
+
+#[test]
+fn test_connection_limit() {
+    let server = ServerBuilder::new()
+        .websocket("127.0.0.1:0".parse().unwrap())
+        .max_connections(1)
+        .start()
+        .unwrap();
+    let mut client = connect_client(&server);
+
+    // Told why once the connection has been upgraded
+    let mut rejected = connect(&server);
+    let error = receive_message(&mut rejected);
+    assert_eq!(error.error_code(), Some(ErrorCode::ServerFull));
+    assert_websocket_closed(&mut rejected);
+
+    client.send(message(MessageType::Subscribe, "event", None).into()).unwrap();
+    assert_eq!(receive_message(&mut client).header.message_type, MessageType::Ack);
+}
+
+#[test]
+fn test_not_upgraded() {
+    let server = start();
+    // Connections that aren't upgraded to WebSocket are dropped
+    let mut stream = TcpStream::connect(server.websocket_addrs()[0]).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(&Handshake::new().into_bytes()).unwrap();
+    stream.write_all(b"\r\n\r\n").unwrap();
+    assert_closed(&mut stream);
+}
+
+#[test]
+fn test_websocket_only() {
+    let server = ServerBuilder::new()
+        .websocket("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap();
+    assert!(server.local_addrs().is_empty());
+    let mut client = connect_client(&server);
+    client.send(message(MessageType::Subscribe, "event", None).into()).unwrap();
+    assert_eq!(receive_message(&mut client).header.message_type, MessageType::Ack);
+}
@@ -0,0 +1,117 @@
+extern crate pubsub;
+extern crate pubsub_server;
+
+mod common;
+use common::{connect, send, receive, assert_closed};
+
+use pubsub::message::{ErrorCode, MessageBuilder, MessageType};
+use pubsub_server::{ServerBuilder, ServerHandle};
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn start(workers: usize, max_connections: usize) -> ServerHandle {
+    ServerBuilder::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .workers(workers)
+        .max_connections(max_connections)
+        .start()
+        .unwrap()
+}
+
+fn subscribe(stream: &mut TcpStream, pattern: &str) {
+    send(stream, MessageType::Subscribe, pattern, None);
+    assert_eq!(receive(stream).header.message_type, MessageType::Ack);
+}
+
+#[test]
+fn test_events_reach_all_workers() {
+    let server = start(4, 100);
+    // Connections are handed to the workers in turn, so these are spread over all of them
+    let mut subscribers: Vec<TcpStream> = (0..8).map(|_| connect(server.local_addr())).collect();
+    for subscriber in &mut subscribers {
+        subscribe(subscriber, "sensors.*");
+    }
+
+    let mut publishers: Vec<TcpStream> = (0..4).map(|_| connect(server.local_addr())).collect();
+    for (i, publisher) in publishers.iter_mut().enumerate() {
+        for j in 0..100u32 {
+            let payload = format!("{}-{}", i, j).into_bytes();
+            send(publisher, MessageType::Publish, "sensors.temp", Some(&payload));
+        }
+    }
+    for publisher in &mut publishers {
+        for _ in 0..100 {
+            assert_eq!(receive(publisher).header.message_type, MessageType::Ack);
+        }
+    }
+
+    // Every subscriber gets every event, in the order of each publisher
+    for subscriber in &mut subscribers {
+        let mut next = [0; 4];
+        for _ in 0..400 {
+            let event = receive(subscriber);
+            let payload = String::from_utf8(event.payload.unwrap()).unwrap();
+            let mut parts = payload.split('-').map(|p| p.parse::<usize>().unwrap());
+            let (publisher, number) = (parts.next().unwrap(), parts.next().unwrap());
+            assert_eq!(number, next[publisher]);
+            next[publisher] += 1;
+        }
+    }
+}
+
+#[test]
+fn test_retained_events_are_shared() {
+    let server = start(4, 100);
+    let mut publisher = connect(server.local_addr());
+    let mut builder = MessageBuilder::new();
+    builder.message_type(MessageType::Publish)
+        .event_name("status".to_string())
+        .payload(b"online".to_vec())
+        .retain(true);
+    publisher.write_all(&builder.build().unwrap().into_bytes()).unwrap();
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+
+    for _ in 0..4 {
+        let mut subscriber = connect(server.local_addr());
+        subscribe(&mut subscriber, "status");
+        let event = receive(&mut subscriber);
+        assert!(event.header.retain);
+        assert_eq!(event.payload, Some(b"online".to_vec()));
+    }
+}
+
+#[test]
+fn test_connection_limit_spans_workers() {
+    let server = start(4, 6);
+    let mut clients: Vec<TcpStream> = (0..6).map(|_| connect(server.local_addr())).collect();
+
+    let mut rejected = TcpStream::connect(server.local_addr()).unwrap();
+    rejected.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(receive(&mut rejected).error_code(), Some(ErrorCode::ServerFull));
+    assert_closed(&mut rejected);
+
+    // The clients are still served, wherever they are
+    for client in &mut clients {
+        subscribe(client, "event");
+    }
+}
+
+#[test]
+fn test_shutdown_flushes_all_workers() {
+    let server = start(4, 100);
+    let mut subscribers: Vec<TcpStream> = (0..4).map(|_| connect(server.local_addr())).collect();
+    for subscriber in &mut subscribers {
+        subscribe(subscriber, "event");
+    }
+    let mut publisher = connect(server.local_addr());
+    send(&mut publisher, MessageType::Publish, "event", Some(b"last"));
+    assert_eq!(receive(&mut publisher).header.message_type, MessageType::Ack);
+
+    server.shutdown().unwrap();
+    for subscriber in &mut subscribers {
+        assert_eq!(receive(subscriber).payload, Some(b"last".to_vec()));
+        assert_closed(subscriber);
+    }
+}
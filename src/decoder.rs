@@ -0,0 +1,345 @@
+use std::io::{self, Read};
+use std::mem;
+
+use bytes::{Buf, BytesMut};
+
+use message::{Message, MessageHeader, SharedMessage};
+use parser::{parse, ParseResult};
+
+// Least amount of free space made available for each read
+const READ_CHUNK_SIZE: usize = 4096;
+// Most free space made available for a read. The payload announced by a
+// header is read in pieces, so that a bogus length can't make the decoder
+// allocate it all before any of it has arrived.
+const MAX_READ_SIZE: usize = 64 * READ_CHUNK_SIZE;
+
+// Default upper limit of a single message, header included
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DecodeError {
+    // The data isn't a valid message
+    Malformed,
+    // The message is larger than the decoder's limit
+    FrameTooLarge
+}
+
+enum State {
+    Header,
+    // The header has been decoded, and the payload of the given length
+    // is being waited for
+    Payload(MessageHeader, usize)
+}
+
+// Turns a stream of bytes, received in pieces of any size, into messages.
+// The header of a message is only parsed once, however many pieces its
+// payload arrives in. After an error the decoder can't be used any more,
+// as there is no telling where the next message starts.
+pub struct Decoder {
+    // The data that hasn't been decoded yet
+    buffer: BytesMut,
+    state: State,
+    max_frame_size: usize
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder {
+            buffer: BytesMut::new(),
+            state: State::Header,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    // A decoder that fails on messages larger than the given size, header included,
+    // as soon as their header has been received. Decoders made with new()
+    // use DEFAULT_MAX_FRAME_SIZE.
+    pub fn with_max_frame_size(max_frame_size: usize) -> Decoder {
+        let mut decoder = Decoder::new();
+        decoder.max_frame_size = max_frame_size;
+        decoder
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // Read from the reader once, straight into the decoder's buffer.
+    // Returns the number of bytes read.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let len = self.buffer.len();
+        let missing = missing_payload_bytes(&self.state, len);
+        let wanted = missing.clamp(READ_CHUNK_SIZE, MAX_READ_SIZE);
+        self.buffer.resize(len + wanted, 0);
+        let result = reader.read(&mut self.buffer[len..]);
+        let read = *result.as_ref().unwrap_or(&0);
+        self.buffer.truncate(len + read);
+        result
+    }
+
+    // The data that hasn't been decoded yet. Together with consume, lets
+    // data preceding the messages, like a handshake, be handled elsewhere.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.buffer.advance(len);
+        self.release_memory();
+    }
+
+    // The next complete message, if it has been received
+    pub fn decode(&mut self) -> Result<Option<Message>, DecodeError> {
+        self.decode_shared().map(|message| message.map(SharedMessage::into_message))
+    }
+
+    // Like decode, but without copying the payload out of the receive buffer
+    pub fn decode_shared(&mut self) -> Result<Option<SharedMessage>, DecodeError> {
+        let (frame, consumed) = try!(decode_next(&mut self.state, self.max_frame_size, &self.buffer[..]));
+        let data = self.buffer.split_to(consumed).freeze();
+        self.release_memory();
+        Ok(frame.map(|(header, payload_len)| SharedMessage {
+            header,
+            payload: payload_len.map(|len| data.slice(consumed - len..))
+        }))
+    }
+
+    // Decode from data kept outside of the decoder, which starts with what
+    // the last call didn't use up. Returns the next complete message, if
+    // any, and the number of bytes to drop from the front of the data.
+    pub fn decode_from(&mut self, data: &[u8]) -> Result<(Option<Message>, usize), DecodeError> {
+        let (frame, consumed) = try!(decode_next(&mut self.state, self.max_frame_size, data));
+        let message = frame.map(|(header, payload_len)| Message {
+            header,
+            payload: payload_len.map(|len| data[consumed - len..consumed].to_vec())
+        });
+        Ok((message, consumed))
+    }
+
+    // Whether the header of a message has been decoded, but not its payload
+    pub fn has_partial_message(&self) -> bool {
+        matches!(self.state, State::Payload(..))
+    }
+
+    fn release_memory(&mut self) {
+        // Don't hold on to the memory after receiving a large message
+        if self.buffer.is_empty() && self.buffer.capacity() > READ_CHUNK_SIZE {
+            self.buffer = BytesMut::new();
+        }
+    }
+}
+
+fn missing_payload_bytes(state: &State, buffered: usize) -> usize {
+    match *state {
+        State::Payload(_, payload_len) => payload_len.saturating_sub(buffered),
+        State::Header => 0
+    }
+}
+
+// The header of a message, and the length of its payload if it has one
+type Frame = (MessageHeader, Option<usize>);
+
+// The next frame in data, given the decoder's state and limit, along with
+// the number of bytes of data used up. The payload, if the message has one,
+// is the given number of bytes at the end of those.
+fn decode_next(state: &mut State, max_frame_size: usize, data: &[u8])
+               -> Result<(Option<Frame>, usize), DecodeError> {
+    let mut header_len = 0;
+    if let State::Header = *state {
+        match parse(data) {
+            ParseResult::Completed(header, len, payload_len) => {
+                if len + payload_len > max_frame_size {
+                    return Err(DecodeError::FrameTooLarge);
+                }
+                if !header.message_type.expects_payload() {
+                    return Ok((Some((header, None)), len));
+                }
+                if header.message_type.fixed_payload_len().is_some_and(|fixed| fixed != payload_len) {
+                    return Err(DecodeError::Malformed);
+                }
+                header_len = len;
+                *state = State::Payload(header, payload_len);
+            },
+            ParseResult::Incomplete => {
+                if data.len() > max_frame_size {
+                    return Err(DecodeError::FrameTooLarge);
+                }
+                return Ok((None, 0));
+            },
+            ParseResult::Error => return Err(DecodeError::Malformed)
+        }
+    }
+
+    if missing_payload_bytes(state, data.len() - header_len) > 0 {
+        return Ok((None, header_len));
+    }
+    let (header, payload_len) = match mem::replace(state, State::Header) {
+        State::Payload(header, payload_len) => (header, payload_len),
+        State::Header => unreachable!()
+    };
+    Ok((Some((header, Some(payload_len))), header_len + payload_len))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::{Message, MessageBuilder, MessageType};
+    use handshake::Handshake;
+    use std::io::Cursor;
+
+    fn publish(event_name: &str, payload: &[u8]) -> Message {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Publish)
+            .event_name(event_name.to_string())
+            .payload(payload.to_vec());
+        builder.build().unwrap()
+    }
+
+    fn subscribe(event_name: &str) -> Message {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Subscribe)
+            .event_name(event_name.to_string());
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_decode_byte_by_byte() {
+        let bytes = publish("event", b"payload").into_bytes();
+        let mut decoder = Decoder::new();
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.feed(&[*byte]);
+            assert_eq!(decoder.decode(), Ok(None));
+        }
+        decoder.feed(&bytes[bytes.len() - 1..]);
+        assert_eq!(decoder.decode(), Ok(Some(publish("event", b"payload"))));
+        assert_eq!(decoder.decode(), Ok(None));
+        assert!(decoder.buffered().is_empty());
+    }
+
+    #[test]
+    fn test_decode_several_messages() {
+        let mut bytes = subscribe("event").into_bytes();
+        bytes.extend(publish("event", b"payload").into_bytes());
+        bytes.extend(Message::ack("event".to_string(), 7).into_bytes());
+        let mut decoder = Decoder::new();
+        decoder.feed(&bytes);
+        assert_eq!(decoder.decode(), Ok(Some(subscribe("event"))));
+        assert_eq!(decoder.decode(), Ok(Some(publish("event", b"payload"))));
+        assert_eq!(decoder.decode().unwrap().unwrap().correlation_id(), Some(7));
+        assert_eq!(decoder.decode(), Ok(None));
+    }
+
+    #[test]
+    fn test_decode_large_payload() {
+        let payload = vec![0x2a; 100000];
+        let bytes = publish("event", &payload).into_bytes();
+        let mut reader = Cursor::new(bytes);
+        let mut decoder = Decoder::new();
+        let mut message = None;
+        while message.is_none() {
+            assert!(decoder.read_from(&mut reader).unwrap() > 0);
+            message = decoder.decode().unwrap();
+        }
+        assert_eq!(message, Some(publish("event", &payload)));
+        assert_eq!(decoder.read_from(&mut reader).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_decode_shared() {
+        let mut bytes = publish("first", b"payload").into_bytes();
+        bytes.extend(subscribe("second").into_bytes());
+        let mut decoder = Decoder::new();
+        decoder.feed(&bytes);
+        let message = decoder.decode_shared().unwrap().unwrap();
+        assert_eq!(message.into_message(), publish("first", b"payload"));
+        assert_eq!(decoder.decode(), Ok(Some(subscribe("second"))));
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&[0x08, 0x05]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Malformed));
+
+        // An Ack must carry a correlation id
+        let mut decoder = Decoder::new();
+        decoder.feed(&[0x05, 0x00, 0x00, 0x02, 0x00, 0x01]);
+        assert_eq!(decoder.decode(), Err(DecodeError::Malformed));
+    }
+
+    #[test]
+    fn test_decode_frame_too_large() {
+        let bytes = publish("event", &[0; 100]).into_bytes();
+        let mut decoder = Decoder::with_max_frame_size(50);
+        // Known to be too large as soon as the header is in
+        decoder.feed(&bytes[..10]);
+        assert_eq!(decoder.decode(), Err(DecodeError::FrameTooLarge));
+
+        let mut decoder = Decoder::with_max_frame_size(bytes.len());
+        decoder.feed(&bytes);
+        assert_eq!(decoder.decode(), Ok(Some(publish("event", &[0; 100]))));
+    }
+
+    #[test]
+    fn test_default_max_frame_size() {
+        // An extended Publish header announcing a 4 GiB payload
+        let header = [0x43, 0x00, 0x01, 0x65, 0xff, 0xff, 0xff, 0xff];
+        let mut decoder = Decoder::new();
+        decoder.feed(&header);
+        assert_eq!(decoder.decode(), Err(DecodeError::FrameTooLarge));
+    }
+
+    #[test]
+    fn test_read_announced_payload_in_pieces() {
+        let header = [0x43, 0x00, 0x01, 0x65, 0xff, 0xff, 0xff, 0xff];
+        let mut reader = Cursor::new(header.to_vec());
+        let mut decoder = Decoder::with_max_frame_size(usize::MAX);
+        decoder.read_from(&mut reader).unwrap();
+        assert_eq!(decoder.decode(), Ok(None));
+        assert!(decoder.has_partial_message());
+
+        let mut reader = Cursor::new(vec![0; 10]);
+        assert_eq!(decoder.read_from(&mut reader).unwrap(), 10);
+        assert!(decoder.buffer.capacity() <= 2 * MAX_READ_SIZE);
+    }
+
+    #[test]
+    fn test_decode_from() {
+        let mut bytes = subscribe("event").into_bytes();
+        bytes.extend(publish("event", b"payload").into_bytes());
+        let mut decoder = Decoder::new();
+
+        let (message, consumed) = decoder.decode_from(&bytes).unwrap();
+        assert_eq!(message, Some(subscribe("event")));
+        bytes.drain(..consumed);
+
+        // Only the header of the Publish
+        let (message, consumed) = decoder.decode_from(&bytes[..9]).unwrap();
+        assert_eq!(message, None);
+        assert!(decoder.has_partial_message());
+        bytes.drain(..consumed);
+
+        let (message, consumed) = decoder.decode_from(&bytes).unwrap();
+        assert_eq!(message, Some(publish("event", b"payload")));
+        assert_eq!(consumed, bytes.len());
+        assert!(!decoder.has_partial_message());
+    }
+
+    #[test]
+    fn test_consume_handshake() {
+        let mut bytes = Handshake::new().into_bytes();
+        bytes.extend(subscribe("event").into_bytes());
+        let mut decoder = Decoder::new();
+        decoder.feed(&bytes);
+        assert_eq!(&decoder.buffered()[..4], b"PSUB");
+        decoder.consume(::handshake::HANDSHAKE_LEN);
+        assert_eq!(decoder.decode(), Ok(Some(subscribe("event"))));
+    }
+}
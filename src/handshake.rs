@@ -0,0 +1,181 @@
+use byteorder::{BigEndian, WriteBytesExt};
+
+use parser::{take_n, read_u8, read_u32};
+
+// Before sending any messages, a client sends a Hello, to which the server
+// answers with a Welcome. Both have the same layout:
+//   magic bytes, protocol version (u8), feature bitmask (u32)
+// The Welcome carries the version the server will speak on the connection,
+// and the features supported by both sides. If the server can't speak any
+// version the client can, it closes the connection after the Welcome.
+pub const MAGIC: &[u8; 4] = b"PSUB";
+pub const HANDSHAKE_LEN: usize = 4 + 1 + 4;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+// Ack and Error replies to requests
+pub const FEATURE_ACKS: u32 = 1 << 0;
+// Retained messages
+pub const FEATURE_RETAIN: u32 = 1 << 1;
+// Extended frames for large event names and payloads
+pub const FEATURE_EXTENDED_FRAMES: u32 = 1 << 2;
+// The Authenticate message
+pub const FEATURE_AUTH: u32 = 1 << 3;
+// Offsets on the events of logged topics, and subscribing from an offset
+pub const FEATURE_OFFSETS: u32 = 1 << 4;
+
+pub const SUPPORTED_FEATURES: u32 =
+    FEATURE_ACKS
+    | FEATURE_RETAIN
+    | FEATURE_EXTENDED_FRAMES
+    | FEATURE_AUTH
+    | FEATURE_OFFSETS;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Handshake {
+    pub version: u8,
+    pub features: u32
+}
+
+impl Default for Handshake {
+    // A handshake for the protocol version and features of this crate
+    fn default() -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES
+        }
+    }
+}
+
+impl Handshake {
+    pub fn new() -> Handshake {
+        Handshake::default()
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.version >= MIN_PROTOCOL_VERSION && self.version <= PROTOCOL_VERSION
+    }
+
+    // The handshake to answer a received one with, or None if there is
+    // no version both sides can speak
+    pub fn negotiate(&self, received: &Handshake) -> Option<Handshake> {
+        let accepted = Handshake {
+            version: ::std::cmp::min(self.version, received.version),
+            features: self.features & received.features
+        };
+        if accepted.is_supported() {
+            Some(accepted)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut vec = Vec::<u8>::with_capacity(HANDSHAKE_LEN);
+        // Should be safe to use unwrap, as writing to a Vec should not fail
+        vec.extend(MAGIC.iter());
+        vec.write_u8(self.version).unwrap();
+        vec.write_u32::<BigEndian>(self.features).unwrap();
+        vec
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum HandshakeResult {
+    // The handshake and the number of bytes it took up
+    Completed(Handshake, usize),
+    Incomplete,
+    Error
+}
+
+pub fn parse(data: &[u8]) -> HandshakeResult {
+    // Check as much of the magic as has been received,
+    // to fail early on connections not speaking the protocol
+    let magic_len = ::std::cmp::min(data.len(), MAGIC.len());
+    if data[..magic_len] != MAGIC[..magic_len] {
+        return HandshakeResult::Error;
+    }
+
+    let rest = match take_n(MAGIC.len(), data) {
+        Some((_, rest)) => rest,
+        None => return HandshakeResult::Incomplete
+    };
+    let (version, rest) = match read_u8(rest) {
+        Some(res) => res,
+        None => return HandshakeResult::Incomplete
+    };
+    let (features, _) = match read_u32(rest) {
+        Some(res) => res,
+        None => return HandshakeResult::Incomplete
+    };
+
+    let handshake = Handshake {
+        version,
+        features
+    };
+    HandshakeResult::Completed(handshake, HANDSHAKE_LEN)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_into_bytes() {
+        let handshake = Handshake {
+            version: 1,
+            features: FEATURE_ACKS | FEATURE_EXTENDED_FRAMES
+        };
+        let expected_bytes = vec![
+            0x50, 0x53, 0x55, 0x42, // Magic (PSUB)
+            0x01, // Version
+            0x00, 0x00, 0x00, 0x05 // Features
+                ];
+        assert_eq!(handshake.into_bytes(), expected_bytes);
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut bytes = Handshake::new().into_bytes();
+        bytes.extend(vec![0x01, 0x05]); // Start of the next message
+        assert_eq!(parse(&bytes), HandshakeResult::Completed(Handshake::new(), HANDSHAKE_LEN));
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        let bytes = Handshake::new().into_bytes();
+        for len in 0..HANDSHAKE_LEN {
+            assert_eq!(parse(&bytes[..len]), HandshakeResult::Incomplete);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_magic() {
+        // A Subscribe message sent without a handshake
+        let bytes = vec![0x01, 0x05, 0x65, 0x76, 0x65, 0x6e, 0x74];
+        assert_eq!(parse(&bytes), HandshakeResult::Error);
+        assert_eq!(parse(&bytes[..1]), HandshakeResult::Error);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let server = Handshake::new();
+        let client = Handshake {
+            version: PROTOCOL_VERSION + 1,
+            features: FEATURE_ACKS | 1 << 31
+        };
+        assert_eq!(server.negotiate(&client), Some(Handshake {
+            version: PROTOCOL_VERSION,
+            features: FEATURE_ACKS
+        }));
+
+        let client = Handshake {
+            version: MIN_PROTOCOL_VERSION - 1,
+            features: SUPPORTED_FEATURES
+        };
+        assert_eq!(server.negotiate(&client), None);
+    }
+}
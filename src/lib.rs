@@ -1,7 +1,11 @@
 extern crate byteorder;
+extern crate bytes;
 
+pub mod decoder;
+pub mod handshake;
 pub mod message;
 pub mod parser;
+pub mod topic;
 
 #[test]
 fn it_works() {
@@ -1,29 +1,129 @@
 use std::u8;
 use std::u16;
 
-use byteorder::{BigEndian, WriteBytesExt};
+use std::io;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum MessageType {
     Subscribe = 1,
     Unsubscribe,
     Publish,
-    Event
+    Event,
+    // Replies from the server. Every Subscribe, Unsubscribe and Publish
+    // sent on a connection is implicitly numbered, starting at 1, and
+    // the server answers each of them with either an Ack or an Error
+    // carrying that number as the correlation id.
+    Ack,
+    Error,
+    // Credentials sent by the client, as its first message after the
+    // handshake, to servers requiring authentication. The event name is the
+    // user name (empty for a shared token) and the payload the password or
+    // token. It isn't numbered, so the reply carries correlation id 0.
+    Authenticate
 }
 
 impl MessageType {
     pub fn expects_payload(&self) -> bool {
         match *self {
             MessageType::Subscribe | MessageType::Unsubscribe => false,
-            MessageType::Publish | MessageType::Event => true
+            MessageType::Publish | MessageType::Event => true,
+            MessageType::Ack | MessageType::Error => true,
+            MessageType::Authenticate => true
+        }
+    }
+
+    // Length of the payload if it is fixed by the message type
+    pub fn fixed_payload_len(&self) -> Option<usize> {
+        match *self {
+            MessageType::Ack => Some(4), // Correlation id
+            MessageType::Error => Some(4 + 1), // Correlation id + error code
+            _ => None
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+// Flags are carried in the high bits of the message type byte
+pub const FLAGS_MASK: u8 = 0xF0;
+// Set on a Publish to have the server keep the payload as the last value
+// of the event, which is then sent to every client subscribing later on.
+// Publishing an empty retained payload clears the retained value.
+// Set on an Event when it is such a retained value.
+pub const RETAIN_FLAG: u8 = 0x80;
+// Set when the message uses the extended (v2) frame format, where the event
+// name length is a u16 and the payload length a u32, instead of a u8 and a u16.
+// Only used when the event name or payload doesn't fit in the original format.
+pub const EXTENDED_FLAG: u8 = 0x40;
+// Set when the event name is followed by an offset (u64) in the topic's log.
+// On a Subscribe, the events logged from that offset on are sent before the
+// ones published from then on. On an Event, it is the event's own offset,
+// sent to clients that negotiated the offsets feature.
+pub const OFFSET_FLAG: u8 = 0x20;
+
+// Subscribe offsets replaying the whole log, and none of it
+pub const OFFSET_EARLIEST: u64 = 0;
+pub const OFFSET_LATEST: u64 = u64::MAX;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ErrorCode {
+    // The bytes received could not be parsed as a message.
+    // The server closes the connection after sending this.
+    MalformedMessage = 1,
+    // The event name is not a valid topic (for Publish)
+    // or topic pattern (for Subscribe and Unsubscribe)
+    InvalidTopic,
+    // The message type may not be sent by a client.
+    // The server closes the connection after sending this.
+    UnexpectedMessage,
+    // The message is larger than the server accepts.
+    // The server closes the connection after sending this.
+    FrameTooLarge,
+    // The client didn't start the connection with a valid handshake.
+    // The server closes the connection after sending this.
+    HandshakeFailed,
+    // The server has reached its maximum number of connections.
+    // Sent in place of the handshake, after which the connection is closed.
+    ServerFull,
+    // The credentials sent with Authenticate were not accepted.
+    // The server closes the connection after sending this.
+    AuthenticationFailed,
+    // A request was sent without authenticating first, to a server
+    // requiring it. The server closes the connection after sending this.
+    NotAuthenticated,
+    // The access control list doesn't let the client subscribe
+    // or publish to the topic
+    AccessDenied,
+    // The Subscribe offset is past the end of the topic's log, or the
+    // server doesn't log topics
+    InvalidOffset
+}
+
+impl ErrorCode {
+    pub fn from_u8(val: u8) -> Option<ErrorCode> {
+        match val {
+            1 => Some(ErrorCode::MalformedMessage),
+            2 => Some(ErrorCode::InvalidTopic),
+            3 => Some(ErrorCode::UnexpectedMessage),
+            4 => Some(ErrorCode::FrameTooLarge),
+            5 => Some(ErrorCode::HandshakeFailed),
+            6 => Some(ErrorCode::ServerFull),
+            7 => Some(ErrorCode::AuthenticationFailed),
+            8 => Some(ErrorCode::NotAuthenticated),
+            9 => Some(ErrorCode::AccessDenied),
+            10 => Some(ErrorCode::InvalidOffset),
+            _ => None
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct MessageHeader {
     pub message_type: MessageType,
     pub event_name: String,
+    pub retain: bool,
+    pub offset: Option<u64>
 }
 
 #[derive(PartialEq, Debug)]
@@ -33,25 +133,186 @@ pub struct Message {
 }
 
 impl Message {
+    pub fn ack(event_name: String, correlation_id: u32) -> Message {
+        let mut payload = Vec::new();
+        payload.write_u32::<BigEndian>(correlation_id).unwrap();
+        Message {
+            header: MessageHeader {
+                message_type: MessageType::Ack,
+                event_name,
+                retain: false,
+                offset: None
+            },
+            payload: Some(payload)
+        }
+    }
+
+    pub fn error(event_name: String, correlation_id: u32, code: ErrorCode) -> Message {
+        let mut payload = Vec::new();
+        payload.write_u32::<BigEndian>(correlation_id).unwrap();
+        payload.write_u8(code as u8).unwrap();
+        Message {
+            header: MessageHeader {
+                message_type: MessageType::Error,
+                event_name,
+                retain: false,
+                offset: None
+            },
+            payload: Some(payload)
+        }
+    }
+
+    // The correlation id of an Ack or Error, None for other message types
+    pub fn correlation_id(&self) -> Option<u32> {
+        match (self.header.message_type, self.payload.as_ref()) {
+            (MessageType::Ack, Some(payload)) | (MessageType::Error, Some(payload)) => {
+                io::Cursor::new(payload).read_u32::<BigEndian>().ok()
+            },
+            _ => None
+        }
+    }
+
+    // The error code of an Error, None for other message types
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match (self.header.message_type, self.payload.as_ref()) {
+            (MessageType::Error, Some(payload)) => {
+                payload.get(4).and_then(|code| ErrorCode::from_u8(*code))
+            },
+            _ => None
+        }
+    }
+
+    // Whether the message needs the extended frame format
+    pub fn is_extended(&self) -> bool {
+        is_extended(&self.header, self.payload.as_ref().map(|p| p.len()))
+    }
+
     pub fn into_bytes(self) -> Vec<u8> {
-        let mut vec = Vec::<u8>::new();
-        // Should be safe to use unwrap, as writing to a Vec should not fail
-        vec.write_u8(self.header.message_type as u8).unwrap();
-        vec.write_u8(self.header.event_name.len() as u8).unwrap();
-        vec.extend(self.header.event_name.into_bytes());
+        let payload_len = self.payload.as_ref().map(|p| p.len());
+        let mut vec = Vec::with_capacity(frame_len(&self.header, payload_len));
+        write_header(&mut vec, &self.header, payload_len);
         if let Some(payload) = self.payload {
-            vec.write_u16::<BigEndian>(payload.len() as u16).unwrap();
             vec.extend(payload);
         }
         vec
     }
 }
 
+// A message whose payload is a shared buffer, such as part of the buffer it was
+// received in. Cloning it, or passing the payload on, doesn't copy the payload.
+#[derive(PartialEq, Debug, Clone)]
+pub struct SharedMessage {
+    pub header: MessageHeader,
+    pub payload: Option<Bytes>
+}
+
+impl SharedMessage {
+    pub fn event(event_name: String, payload: Bytes, retain: bool) -> SharedMessage {
+        SharedMessage {
+            header: MessageHeader {
+                message_type: MessageType::Event,
+                event_name,
+                retain,
+                offset: None
+            },
+            payload: Some(payload)
+        }
+    }
+
+    // Whether the message needs the extended frame format
+    pub fn is_extended(&self) -> bool {
+        is_extended(&self.header, self.payload.as_ref().map(|p| p.len()))
+    }
+
+    // Encode the message once, into a buffer that can then be shared
+    // by any number of write queues
+    pub fn to_bytes(&self) -> Bytes {
+        let payload_len = self.payload.as_ref().map(|p| p.len());
+        let mut vec = Vec::with_capacity(frame_len(&self.header, payload_len));
+        write_header(&mut vec, &self.header, payload_len);
+        if let Some(ref payload) = self.payload {
+            vec.extend_from_slice(payload);
+        }
+        Bytes::from(vec)
+    }
+
+    pub fn into_message(self) -> Message {
+        Message {
+            header: self.header,
+            payload: self.payload.map(|p| p.to_vec())
+        }
+    }
+}
+
+impl From<Message> for SharedMessage {
+    fn from(message: Message) -> SharedMessage {
+        SharedMessage {
+            header: message.header,
+            payload: message.payload.map(Bytes::from)
+        }
+    }
+}
+
+fn is_extended(header: &MessageHeader, payload_len: Option<usize>) -> bool {
+    header.event_name.len() > u8::MAX as usize
+        || payload_len.is_some_and(|len| len > u16::MAX as usize)
+}
+
+// Length of the encoded message
+fn frame_len(header: &MessageHeader, payload_len: Option<usize>) -> usize {
+    let (name_len_size, payload_len_size) = if is_extended(header, payload_len) {
+        (2, 4)
+    }
+    else {
+        (1, 2)
+    };
+    1 + name_len_size + header.event_name.len()
+        + header.offset.map_or(0, |_| 8)
+        + payload_len.map_or(0, |len| payload_len_size + len)
+}
+
+// Everything of the encoded message up to the payload itself
+fn write_header(vec: &mut Vec<u8>, header: &MessageHeader, payload_len: Option<usize>) {
+    // Should be safe to use unwrap, as writing to a Vec should not fail
+    let extended = is_extended(header, payload_len);
+    let mut flags = 0;
+    if header.retain {
+        flags |= RETAIN_FLAG;
+    }
+    if extended {
+        flags |= EXTENDED_FLAG;
+    }
+    if header.offset.is_some() {
+        flags |= OFFSET_FLAG;
+    }
+    vec.write_u8(header.message_type as u8 | flags).unwrap();
+    if extended {
+        vec.write_u16::<BigEndian>(header.event_name.len() as u16).unwrap();
+    }
+    else {
+        vec.write_u8(header.event_name.len() as u8).unwrap();
+    }
+    vec.extend_from_slice(header.event_name.as_bytes());
+    if let Some(offset) = header.offset {
+        vec.write_u64::<BigEndian>(offset).unwrap();
+    }
+    if let Some(payload_len) = payload_len {
+        if extended {
+            vec.write_u32::<BigEndian>(payload_len as u32).unwrap();
+        }
+        else {
+            vec.write_u16::<BigEndian>(payload_len as u16).unwrap();
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Default)]
 pub struct MessageBuilder {
     message_type: Option<MessageType>,
     event_name: Option<String>,
-    payload: Option<Vec<u8>>
+    payload: Option<Vec<u8>>,
+    retain: bool,
+    offset: Option<u64>
 }
 
 impl MessageBuilder {
@@ -59,7 +320,9 @@ impl MessageBuilder {
         MessageBuilder {
             message_type: None,
             event_name: None,
-            payload: None
+            payload: None,
+            retain: false,
+            offset: None
         }
     }
 
@@ -78,6 +341,16 @@ impl MessageBuilder {
         self
     }
 
+    pub fn retain(&mut self, retain: bool) -> &mut MessageBuilder {
+        self.retain = retain;
+        self
+    }
+
+    pub fn offset(&mut self, offset: u64) -> &mut MessageBuilder {
+        self.offset = Some(offset);
+        self
+    }
+
     pub fn validate(&self, only_header: bool) -> Result<(), MessageBuildError> {
         let mut missing_fields = Vec::new();
         if self.message_type.is_none() {
@@ -90,10 +363,24 @@ impl MessageBuilder {
             return Err(MessageBuildError::MissingField(missing_fields.join(", ")));
         }
 
-        if self.event_name.as_ref().unwrap().len() > u8::MAX as usize {
+        if self.event_name.as_ref().unwrap().len() > u16::MAX as usize {
             return Err(MessageBuildError::TooLargeField(String::from("event name")));
         }
 
+        if self.retain {
+            match self.message_type.unwrap() {
+                MessageType::Publish | MessageType::Event => {},
+                _ => return Err(MessageBuildError::InvalidField(String::from("retain")))
+            }
+        }
+
+        if self.offset.is_some() {
+            match self.message_type.unwrap() {
+                MessageType::Subscribe | MessageType::Event => {},
+                _ => return Err(MessageBuildError::InvalidField(String::from("offset")))
+            }
+        }
+
         if only_header {
             return Ok(());
         }
@@ -102,9 +389,14 @@ impl MessageBuilder {
             if self.payload.is_none() {
                 return Err(MessageBuildError::MissingField(String::from("payload")));
             }
-            if self.payload.as_ref().unwrap().len() > u16::MAX as usize {
+            if self.payload.as_ref().unwrap().len() > u32::MAX as usize {
                 return Err(MessageBuildError::TooLargeField(String::from("payload")));
             }
+            if let Some(len) = self.message_type.unwrap().fixed_payload_len() {
+                if self.payload.as_ref().unwrap().len() != len {
+                    return Err(MessageBuildError::InvalidField(String::from("payload")));
+                }
+            }
         }
         else if self.payload.is_some() {
             return Err(MessageBuildError::InvalidField(
@@ -118,7 +410,9 @@ impl MessageBuilder {
         try!(self.validate(true));
         Ok(MessageHeader {
             message_type: self.message_type.unwrap(),
-            event_name: self.event_name.unwrap()
+            event_name: self.event_name.unwrap(),
+            retain: self.retain,
+            offset: self.offset
         })
     }
 
@@ -126,7 +420,9 @@ impl MessageBuilder {
         try!(self.validate(false));
         let header = MessageHeader {
             message_type: self.message_type.unwrap(),
-            event_name: self.event_name.unwrap()
+            event_name: self.event_name.unwrap(),
+            retain: self.retain,
+            offset: self.offset
         };
         let message = Message {
             header: header,
@@ -145,7 +441,9 @@ pub enum MessageBuildError {
 
 #[cfg(test)]
 mod test {
-    use super::{Message, MessageHeader, MessageBuilder, MessageBuildError, MessageType};
+    use super::{Message, MessageHeader, MessageBuilder, MessageBuildError, MessageType, ErrorCode,
+                SharedMessage};
+    use bytes::Bytes;
 
     #[test]
     fn test_into_bytes() {
@@ -153,6 +451,8 @@ mod test {
             header: MessageHeader {
                 message_type: MessageType::Publish,
                 event_name: "event".to_string(),
+                retain: false,
+                offset: None
             },
             payload: Some("a payload here".to_string().into_bytes())
         };
@@ -195,7 +495,7 @@ mod test {
     #[test]
     fn test_validate_builder_event_name_length() {
         let mut builder = MessageBuilder::new();
-        let string_bytes = vec![97; 256];
+        let string_bytes = vec![97; 65536];
         let event_name = String::from_utf8(string_bytes).unwrap();
         builder.message_type(MessageType::Subscribe).
             event_name(event_name);
@@ -204,15 +504,32 @@ mod test {
     }
 
     #[test]
-    fn test_validate_builder_payload_length() {
+    fn test_extended_into_bytes() {
         let mut builder = MessageBuilder::new();
         let payload = vec![0xAB; 65536];
         builder.message_type(MessageType::Publish).
             event_name("event".to_string()).
             payload(payload);
-        assert_eq!(builder.build(),
-                   Err(MessageBuildError::TooLargeField("payload".to_string())));
+        let bytes = builder.build().unwrap().into_bytes();
+        let expected_header = vec![
+            0x43, // Type with extended flag
+            0x00, 0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name
+            0x00, 0x01, 0x00, 0x00 // Payload length
+                ];
+        assert_eq!(bytes.len(), expected_header.len() + 65536);
+        assert_eq!(&bytes[..expected_header.len()], &expected_header[..]);
+    }
 
+    #[test]
+    fn test_extended_event_name_into_bytes() {
+        let mut builder = MessageBuilder::new();
+        let event_name = String::from_utf8(vec![97; 256]).unwrap();
+        builder.message_type(MessageType::Subscribe).
+            event_name(event_name);
+        let bytes = builder.build().unwrap().into_bytes();
+        assert_eq!(bytes.len(), 1 + 2 + 256);
+        assert_eq!(&bytes[..3], &[0x41, 0x01, 0x00]);
     }
 
     #[test]
@@ -233,4 +550,133 @@ mod test {
             assert!(builder.build().is_ok());
         }
     }
+
+    #[test]
+    fn test_retained_into_bytes() {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Publish).
+            event_name("event".to_string()).
+            payload(vec![0x2A]).
+            retain(true);
+        let expected_bytes = vec![
+            0x83, // Type with retain flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name
+            0x00, 0x01, // Payload length
+            0x2A // Payload
+                ];
+        assert_eq!(builder.build().unwrap().into_bytes(), expected_bytes);
+    }
+
+    #[test]
+    fn test_validate_builder_retain() {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Subscribe).
+            event_name("event".to_string()).
+            retain(true);
+        assert_eq!(builder.build(),
+                   Err(MessageBuildError::InvalidField("retain".to_string())));
+    }
+
+    #[test]
+    fn test_ack_into_bytes() {
+        let message = Message::ack("event".to_string(), 258);
+        let expected_bytes = vec![
+            0x05, // Type
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name
+            0x00, 0x04, // Payload length
+            0x00, 0x00, 0x01, 0x02 // Correlation id
+                ];
+        assert_eq!(message.correlation_id(), Some(258));
+        assert_eq!(message.error_code(), None);
+        assert_eq!(message.into_bytes(), expected_bytes);
+    }
+
+    #[test]
+    fn test_error_into_bytes() {
+        let message = Message::error("event".to_string(), 7, ErrorCode::InvalidTopic);
+        let expected_bytes = vec![
+            0x06, // Type
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name
+            0x00, 0x05, // Payload length
+            0x00, 0x00, 0x00, 0x07, // Correlation id
+            0x02 // Error code
+                ];
+        assert_eq!(message.correlation_id(), Some(7));
+        assert_eq!(message.error_code(), Some(ErrorCode::InvalidTopic));
+        assert_eq!(message.into_bytes(), expected_bytes);
+    }
+
+    #[test]
+    fn test_validate_builder_reply_payload_length() {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Ack).
+            event_name("event".to_string()).
+            payload(vec![0x00, 0x01]);
+        assert_eq!(builder.build(),
+                   Err(MessageBuildError::InvalidField("payload".to_string())));
+
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Error).
+            event_name("event".to_string()).
+            payload(vec![0x00, 0x00, 0x00, 0x01, 0x01]);
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn test_shared_message_to_bytes() {
+        let payload = vec![0xAB; 65536];
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Event).
+            event_name("event".to_string()).
+            payload(payload.clone()).
+            retain(true);
+        let expected = builder.build().unwrap();
+
+        let message = SharedMessage::event("event".to_string(), Bytes::from(payload), true);
+        assert_eq!(&message.to_bytes()[..], &expected.into_bytes()[..]);
+        assert_eq!(SharedMessage::from(message.clone().into_message()), message);
+    }
+
+    #[test]
+    fn test_offset_into_bytes() {
+        let mut builder = MessageBuilder::new();
+        builder.message_type(MessageType::Subscribe).
+            event_name("event".to_string()).
+            offset(258);
+        let expected_bytes = vec![
+            0x21, // Type with offset flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02 // Offset
+                ];
+        assert_eq!(builder.build().unwrap().into_bytes(), expected_bytes);
+
+        let mut message = SharedMessage::event("event".to_string(), Bytes::from(vec![0x2A]), false);
+        message.header.offset = Some(1);
+        let expected_bytes = vec![
+            0x24, // Type with offset flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // Offset
+            0x00, 0x01, // Payload length
+            0x2A // Payload
+                ];
+        assert_eq!(&message.to_bytes()[..], &expected_bytes[..]);
+    }
+
+    #[test]
+    fn test_validate_builder_offset() {
+        for message_type in [MessageType::Unsubscribe, MessageType::Publish] {
+            let mut builder = MessageBuilder::new();
+            builder.message_type(message_type).
+                event_name("event".to_string()).
+                payload(vec![0x2A]).
+                offset(1);
+            assert_eq!(builder.build(),
+                       Err(MessageBuildError::InvalidField("offset".to_string())));
+        }
+    }
 }
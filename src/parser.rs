@@ -2,7 +2,8 @@ use byteorder::{BigEndian, ReadBytesExt};
 use std::io;
 use std::str;
 
-use message::{MessageBuilder, MessageType, MessageHeader};
+use message::{MessageBuilder, MessageType, MessageHeader, FLAGS_MASK, RETAIN_FLAG, EXTENDED_FLAG,
+              OFFSET_FLAG};
 
 macro_rules! try_parse {
     ($expr:expr) => (match $expr {
@@ -38,6 +39,22 @@ pub fn read_u16(b: &[u8]) -> Option<(u16, &[u8])> {
     }
 }
 
+pub fn read_u32(b: &[u8]) -> Option<(u32, &[u8])> {
+    let mut c = io::Cursor::new(b);
+    match c.read_u32::<BigEndian>() {
+        Ok(n) => Some((n, &b[4..])),
+        Err(_) => None
+    }
+}
+
+pub fn read_u64(b: &[u8]) -> Option<(u64, &[u8])> {
+    let mut c = io::Cursor::new(b);
+    match c.read_u64::<BigEndian>() {
+        Ok(n) => Some((n, &b[8..])),
+        Err(_) => None
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum ParseResult {
     Completed(MessageHeader, usize, usize),
@@ -59,6 +76,7 @@ enum Awaiting {
     MessageType,
     EventNameLen,
     EventName,
+    Offset,
     PayloadLen
 }
 
@@ -74,6 +92,8 @@ pub fn parse(data: &[u8]) -> ParseResult {
     let mut partial_message = MessageBuilder::new();
 
     let mut current_message_type = None;
+    let mut extended = false;
+    let mut has_offset = false;
     let mut expected_event_name_len = None;
 
     let mut consumed: usize = 0;
@@ -83,16 +103,25 @@ pub fn parse(data: &[u8]) -> ParseResult {
     while remainder.len() > 0 {
         match awaiting {
             Awaiting::MessageType => {
-                let (message_type, rest) = try_parse!(read_message_type(remainder));
-                partial_message.message_type(message_type);
+                let ((message_type, flags), rest) = try_parse!(read_message_type(remainder));
+                partial_message.message_type(message_type)
+                    .retain(flags & RETAIN_FLAG != 0);
                 current_message_type = Some(message_type);
+                extended = flags & EXTENDED_FLAG != 0;
+                has_offset = flags & OFFSET_FLAG != 0;
                 awaiting = Awaiting::EventNameLen;
                 consumed += remainder.len() - rest.len();
                 remainder = rest;
             },
             Awaiting::EventNameLen => {
-                let (len, rest) = try_parse!(read_u8(remainder)
-                                             .ok_or(ParseResult::Incomplete));
+                let (len, rest) = if extended {
+                    try_parse!(read_u16(remainder).ok_or(ParseResult::Incomplete))
+                }
+                else {
+                    let (len, rest) = try_parse!(read_u8(remainder)
+                                                 .ok_or(ParseResult::Incomplete));
+                    (len as u16, rest)
+                };
                 expected_event_name_len = Some(len);
                 awaiting = Awaiting::EventName;
                 consumed += remainder.len() - rest.len();
@@ -104,20 +133,49 @@ pub fn parse(data: &[u8]) -> ParseResult {
                 partial_message.event_name(event_name);
                 consumed += remainder.len() - rest.len();
                 remainder = rest;
+                if has_offset {
+                    awaiting = Awaiting::Offset;
+                }
+                else if current_message_type.unwrap().expects_payload() {
+                    awaiting = Awaiting::PayloadLen;
+                }
+                else {
+                    return match partial_message.build_header() {
+                        Ok(header) => ParseResult::Completed(header, consumed, 0),
+                        Err(_) => ParseResult::Error
+                    };
+                }
+            },
+            Awaiting::Offset => {
+                let (offset, rest) = try_parse!(read_u64(remainder)
+                                                .ok_or(ParseResult::Incomplete));
+                partial_message.offset(offset);
+                consumed += remainder.len() - rest.len();
+                remainder = rest;
                 if current_message_type.unwrap().expects_payload() {
                     awaiting = Awaiting::PayloadLen;
                 }
                 else {
-                    return ParseResult::Completed(partial_message.build_header().unwrap(),
-                                                  consumed, 0);
+                    return match partial_message.build_header() {
+                        Ok(header) => ParseResult::Completed(header, consumed, 0),
+                        Err(_) => ParseResult::Error
+                    };
                 }
             },
             Awaiting::PayloadLen => {
-                let (len, rest) = try_parse!(read_u16(remainder)
-                                             .ok_or(ParseResult::Incomplete));
+                let (len, rest) = if extended {
+                    try_parse!(read_u32(remainder).ok_or(ParseResult::Incomplete))
+                }
+                else {
+                    let (len, rest) = try_parse!(read_u16(remainder)
+                                                 .ok_or(ParseResult::Incomplete));
+                    (len as u32, rest)
+                };
                 consumed += remainder.len() - rest.len();
-                return ParseResult::Completed(partial_message.build_header().unwrap(),
-                                              consumed, len as usize);
+                return match partial_message.build_header() {
+                    Ok(header) => ParseResult::Completed(header, consumed, len as usize),
+                    Err(_) => ParseResult::Error
+                };
             }
         };
     }
@@ -125,11 +183,15 @@ pub fn parse(data: &[u8]) -> ParseResult {
 }
 
 fn read_message_type(b: &[u8])
-                     -> Result<(MessageType, &[u8]), ParserError> {
+                     -> Result<((MessageType, u8), &[u8]), ParserError> {
     match read_u8(b) {
         Some((val, remainder)) => {
-            let message_type = try!(match_message_type(val));
-            Ok((message_type, remainder))
+            let flags = val & FLAGS_MASK;
+            if flags & !(RETAIN_FLAG | EXTENDED_FLAG | OFFSET_FLAG) != 0 {
+                return Err(ParserError::InvalidValue);
+            }
+            let message_type = try!(match_message_type(val & !FLAGS_MASK));
+            Ok(((message_type, flags), remainder))
         },
         None => Err(ParserError::InsufficientData)
     }
@@ -150,12 +212,21 @@ fn match_message_type(val: u8) -> Result<MessageType, ParserError> {
     else if val == MessageType::Event as u8 {
         Ok(MessageType::Event)
     }
+    else if val == MessageType::Ack as u8 {
+        Ok(MessageType::Ack)
+    }
+    else if val == MessageType::Error as u8 {
+        Ok(MessageType::Error)
+    }
+    else if val == MessageType::Authenticate as u8 {
+        Ok(MessageType::Authenticate)
+    }
     else {
         Err(ParserError::InvalidValue)
     }
 }
 
-fn read_event_name(b: &[u8], event_name_len: u8)
+fn read_event_name(b: &[u8], event_name_len: u16)
                    -> Result<(String, &[u8]), ParserError> {
     let (event_name_bytes, rest) = try!(take_n(event_name_len as usize, b)
                                         .ok_or(ParserError::InsufficientData));
@@ -222,11 +293,14 @@ mod test {
         let expected_pairs = vec![(1, MessageType::Subscribe),
                                   (2, MessageType::Unsubscribe),
                                   (3, MessageType::Publish),
-                                  (4, MessageType::Event)];
+                                  (4, MessageType::Event),
+                                  (5, MessageType::Ack),
+                                  (6, MessageType::Error),
+                                  (7, MessageType::Authenticate)];
         for (val, message_type) in expected_pairs {
             assert_eq!(match_message_type(val), Ok(message_type));
         }
-        assert_eq!(match_message_type(5), Err(ParserError::InvalidValue));
+        assert_eq!(match_message_type(8), Err(ParserError::InvalidValue));
     }
 
     fn test_parse_message_without_payload(bytes: &[u8], expected_type: MessageType, expected_event_name: String,
@@ -334,4 +408,127 @@ mod test {
                                         &expected_payload, 9);
 
     }
+
+    #[test]
+    fn test_parse_ack() {
+        let message_bytes = vec![
+            0x05, // Type (Ack)
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name (event)
+            0x00, 0x04, // Payload length
+            0x00, 0x00, 0x00, 0x2A // Payload (correlation id)
+                ];
+        let expected_payload: Vec<u8> = vec![0x00, 0x00, 0x00, 0x2A];
+        test_parse_message_with_payload(&message_bytes, MessageType::Ack, "event".to_string(),
+                                        &expected_payload, 9);
+    }
+
+    #[test]
+    fn test_parse_retained_publish() {
+        let message_bytes = vec![
+            0x83, // Type (Publish) with retain flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name (event)
+            0x00, 0x01, // Payload length
+            0x2A // Payload
+                ];
+        if let ParseResult::Completed(message_header, _, _) = parse(&message_bytes) {
+            assert_eq!(message_header.message_type, MessageType::Publish);
+            assert!(message_header.retain);
+        }
+        else {
+            panic!("Result wasn't Completed");
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_flags() {
+        let retained_subscribe = vec![
+            0x81, // Type (Subscribe) with retain flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74 // Name (event)
+                ];
+        assert_eq!(parse(&retained_subscribe), ParseResult::Error);
+
+        let unknown_flag = vec![
+            0x11, // Type (Subscribe) with unknown flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74 // Name (event)
+                ];
+        assert_eq!(parse(&unknown_flag), ParseResult::Error);
+    }
+
+    #[test]
+    fn test_read_u32() {
+        let buf = [0xDE, 0xAD, 0xBE, 0xEF, 0x01];
+        let res = read_u32(&buf);
+        assert_eq!(res, Some((0xDEADBEEF, &buf[4..])));
+        assert!(read_u32(&buf[2..]).is_none());
+    }
+
+    #[test]
+    fn test_parse_extended_message() {
+        let message_bytes = vec![
+            0x43, // Type (Publish) with extended flag
+            0x00, 0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name (event)
+            0x00, 0x00, 0x00, 0x02, // Payload length
+            0x2A, 0x2B // Payload
+                ];
+        test_parse_message_with_payload(&message_bytes, MessageType::Publish, "event".to_string(),
+                                        &[0x2A, 0x2B], 12);
+    }
+
+    #[test]
+    fn test_parse_extended_incomplete_length() {
+        let message_bytes = vec![
+            0x43, // Type (Publish) with extended flag
+            0x00, 0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name (event)
+            0x00, 0x00, 0x00 // Payload length, missing one byte
+                ];
+        assert_eq!(parse(&message_bytes), ParseResult::Incomplete);
+    }
+
+    #[test]
+    fn test_parse_subscribe_with_offset() {
+        let message_bytes = vec![
+            0x21, // Type (Subscribe) with offset flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name (event)
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02 // Offset
+                ];
+        assert_eq!(parse(&message_bytes[..10]), ParseResult::Incomplete);
+        if let ParseResult::Completed(message_header, consumed, payload_len) = parse(&message_bytes) {
+            assert_eq!(message_header.message_type, MessageType::Subscribe);
+            assert_eq!(message_header.offset, Some(258));
+            assert_eq!(consumed, 15);
+            assert_eq!(payload_len, 0);
+        }
+        else {
+            assert!(false, "Result wasn't Completed");
+        }
+    }
+
+    #[test]
+    fn test_parse_event_with_offset() {
+        let message_bytes = vec![
+            0x24, // Type (Event) with offset flag
+            0x05, // Name length
+            0x65, 0x76, 0x65, 0x6e, 0x74, // Name (event)
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, // Offset
+            0x00, 0x01, // Payload length
+            0x2A // Payload
+                ];
+        test_parse_message_with_payload(&message_bytes, MessageType::Event, "event".to_string(),
+                                        &[0x2A], 17);
+        if let ParseResult::Completed(message_header, _, _) = parse(&message_bytes) {
+            assert_eq!(message_header.offset, Some(7));
+        }
+
+        // Only Subscribe and Event carry offsets
+        let mut publish = message_bytes.clone();
+        publish[0] = 0x23;
+        assert_eq!(parse(&publish), ParseResult::Error);
+    }
 }
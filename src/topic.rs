@@ -0,0 +1,187 @@
+// Event names are hierarchical: levels are separated by SEPARATOR,
+// e.g. "sensors.kitchen.temp". Subscriptions may use wildcards in place
+// of whole levels: "*" matches exactly one level and "#" matches any number
+// of levels (including none), and is only allowed as the last level.
+pub const SEPARATOR: char = '.';
+pub const SINGLE_LEVEL_WILDCARD: &str = "*";
+pub const MULTI_LEVEL_WILDCARD: &str = "#";
+
+pub fn levels<'a>(topic: &'a str) -> ::std::str::Split<'a, char> {
+    topic.split(SEPARATOR)
+}
+
+fn is_wildcard(level: &str) -> bool {
+    level == SINGLE_LEVEL_WILDCARD || level == MULTI_LEVEL_WILDCARD
+}
+
+fn contains_wildcard_char(level: &str) -> bool {
+    level.contains(SINGLE_LEVEL_WILDCARD) || level.contains(MULTI_LEVEL_WILDCARD)
+}
+
+// A concrete event name, as used when publishing
+pub fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !levels(topic).any(contains_wildcard_char)
+}
+
+// An event name that may contain wildcards, as used when subscribing
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    if pattern.is_empty() {
+        return false;
+    }
+    let mut levels = levels(pattern).peekable();
+    while let Some(level) = levels.next() {
+        if level == MULTI_LEVEL_WILDCARD {
+            if levels.peek().is_some() {
+                return false;
+            }
+        }
+        else if !is_wildcard(level) && contains_wildcard_char(level) {
+            return false;
+        }
+    }
+    true
+}
+
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut topic_levels = levels(topic);
+    for pattern_level in levels(pattern) {
+        if pattern_level == MULTI_LEVEL_WILDCARD {
+            return true;
+        }
+        match topic_levels.next() {
+            Some(topic_level) => {
+                if pattern_level != SINGLE_LEVEL_WILDCARD && pattern_level != topic_level {
+                    return false;
+                }
+            },
+            None => return false
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+// Whether every topic matching the inner pattern also matches the outer one
+pub fn covers(outer: &str, inner: &str) -> bool {
+    let mut inner_levels = levels(inner);
+    for outer_level in levels(outer) {
+        if outer_level == MULTI_LEVEL_WILDCARD {
+            return true;
+        }
+        match inner_levels.next() {
+            // Only matched by a multi-level wildcard
+            Some(inner_level) if inner_level == MULTI_LEVEL_WILDCARD => return false,
+            Some(inner_level) => {
+                if outer_level != SINGLE_LEVEL_WILDCARD && outer_level != inner_level {
+                    return false;
+                }
+            },
+            None => return false
+        }
+    }
+    inner_levels.next().is_none()
+}
+
+// Whether some topic matches both patterns
+pub fn overlaps(first: &str, second: &str) -> bool {
+    let mut first_levels = levels(first);
+    let mut second_levels = levels(second);
+    loop {
+        match (first_levels.next(), second_levels.next()) {
+            (Some(MULTI_LEVEL_WILDCARD), _) | (_, Some(MULTI_LEVEL_WILDCARD)) => return true,
+            (Some(a), Some(b)) => {
+                if a != b && a != SINGLE_LEVEL_WILDCARD && b != SINGLE_LEVEL_WILDCARD {
+                    return false;
+                }
+            },
+            (None, None) => return true,
+            // The longer pattern has levels the shorter one can't match,
+            // as a multi-level wildcard ending it would have matched above
+            (Some(_), None) | (None, Some(_)) => return false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_topic() {
+        assert!(is_valid_topic("event"));
+        assert!(is_valid_topic("sensors.kitchen.temp"));
+        assert!(!is_valid_topic(""));
+        assert!(!is_valid_topic("sensors.*.temp"));
+        assert!(!is_valid_topic("sensors.#"));
+        assert!(!is_valid_topic("sensors.kitchen*"));
+    }
+
+    #[test]
+    fn test_is_valid_pattern() {
+        assert!(is_valid_pattern("event"));
+        assert!(is_valid_pattern("sensors.*.temp"));
+        assert!(is_valid_pattern("sensors.#"));
+        assert!(is_valid_pattern("#"));
+        assert!(is_valid_pattern("*.*"));
+        assert!(!is_valid_pattern(""));
+        assert!(!is_valid_pattern("sensors.#.temp"));
+        assert!(!is_valid_pattern("sensors.kitchen*"));
+        assert!(!is_valid_pattern("sensors.#a"));
+    }
+
+    #[test]
+    fn test_matches_exact() {
+        assert!(matches("sensors.kitchen.temp", "sensors.kitchen.temp"));
+        assert!(!matches("sensors.kitchen.temp", "sensors.kitchen"));
+        assert!(!matches("sensors.kitchen", "sensors.kitchen.temp"));
+        assert!(!matches("sensors.kitchen.temp", "sensors.hall.temp"));
+    }
+
+    #[test]
+    fn test_matches_single_level_wildcard() {
+        assert!(matches("sensors.*.temp", "sensors.kitchen.temp"));
+        assert!(matches("sensors.*.temp", "sensors.hall.temp"));
+        assert!(!matches("sensors.*.temp", "sensors.kitchen.humidity"));
+        assert!(!matches("sensors.*.temp", "sensors.temp"));
+        assert!(!matches("sensors.*", "sensors.kitchen.temp"));
+    }
+
+    #[test]
+    fn test_matches_multi_level_wildcard() {
+        assert!(matches("sensors.#", "sensors.kitchen.temp"));
+        assert!(matches("sensors.#", "sensors.kitchen"));
+        assert!(matches("sensors.#", "sensors"));
+        assert!(matches("#", "anything.at.all"));
+        assert!(matches("*.kitchen.#", "sensors.kitchen.temp"));
+        assert!(!matches("sensors.#", "lights.kitchen"));
+    }
+
+    #[test]
+    fn test_covers() {
+        assert!(covers("sensors.#", "sensors.kitchen.temp"));
+        assert!(covers("sensors.#", "sensors.*.temp"));
+        assert!(covers("sensors.#", "sensors.#"));
+        assert!(covers("sensors.*.temp", "sensors.*.temp"));
+        assert!(covers("sensors.*.temp", "sensors.kitchen.temp"));
+        assert!(covers("#", "#"));
+        assert!(!covers("sensors.kitchen.temp", "sensors.*.temp"));
+        assert!(!covers("sensors.*", "sensors.#"));
+        assert!(!covers("sensors.*.temp", "sensors.kitchen"));
+        assert!(!covers("sensors.kitchen", "sensors.kitchen.temp"));
+        assert!(!covers("sensors.#", "lights.#"));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(overlaps("sensors.#", "sensors.kitchen.temp"));
+        assert!(overlaps("sensors.kitchen.temp", "sensors.#"));
+        assert!(overlaps("sensors.*.temp", "*.kitchen.*"));
+        assert!(overlaps("sensors.#", "sensors"));
+        assert!(overlaps("sensors", "sensors.#"));
+        assert!(overlaps("#", "anything"));
+        assert!(!overlaps("sensors.*.temp", "sensors.kitchen.humidity"));
+        assert!(!overlaps("sensors.*", "sensors"));
+        assert!(!overlaps("sensors.kitchen", "sensors.kitchen.temp"));
+        assert!(!overlaps("sensors.#", "lights.#"));
+    }
+}